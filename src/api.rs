@@ -0,0 +1,1709 @@
+//! The `/api/v1` surface: tasks as resources with sessions, the summary and
+//! attachments as sub-resources.
+
+use axum::{
+    body::Body,
+    extract::State,
+    handler::Handler,
+    headers::ETag,
+    http::{header, Method, Response, StatusCode},
+    response::{
+        sse::{self, KeepAlive, Sse},
+        IntoResponse,
+    },
+    routing::{on, MethodFilter, MethodRouter},
+    Router, TypedHeader,
+};
+use futures_util::{Stream, StreamExt};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{collections::HashMap, convert::Infallible};
+use tokio_stream::wrappers::BroadcastStream;
+use utoipa::{IntoParams, OpenApi, ToSchema};
+
+use crate::{
+    app::DeleteMode,
+    auth::CurrentUser,
+    data_dir::DataDir,
+    error::{Error, ErrorBody, Result},
+    event::Event,
+    extract::{IfMatch, Json, Path, Query},
+    share::{self, ShareInfo},
+    state::AppState,
+    task::{self, Change, DiffLine, Image, Session, SummaryRevision, Task, TaskStaus},
+    time::Time,
+    user::{self, Role, TokenInfo},
+};
+
+/// One routed operation: the path in axum syntax, its method and the handler.
+pub type Route = (&'static str, Method, MethodRouter<AppState>);
+
+/// Routes `method` on `path` to the handler.
+pub fn route<H, T>(path: &'static str, method: Method, handler: H) -> Route
+where
+    H: Handler<T, AppState>,
+    T: 'static,
+{
+    let filter = MethodFilter::try_from(method.clone()).expect("method can be routed");
+    (path, method, on(filter, handler))
+}
+
+/// Builds the router serving a route table.
+pub fn router(routes: Vec<Route>) -> Router<AppState> {
+    routes
+        .into_iter()
+        .fold(Router::new(), |router, (path, _, handler)| {
+            router.route(path, handler)
+        })
+}
+
+/// Every `/api/v1` operation. The tests check this table against the OpenAPI
+/// spec.
+pub fn routes() -> Vec<Route> {
+    vec![
+        route("/tasks", Method::GET, list_tasks),
+        route("/tasks", Method::POST, create_task),
+        route("/tasks/:id", Method::GET, get_task),
+        route("/tasks/:id", Method::PATCH, update_task),
+        route("/tasks/:id", Method::DELETE, delete_task),
+        route("/tasks/:id/sessions", Method::GET, list_sessions),
+        route("/tasks/:id/sessions", Method::POST, open_session),
+        route("/tasks/:id/sessions/current", Method::DELETE, close_session),
+        route("/tasks/:id/summary", Method::GET, get_summary),
+        route("/tasks/:id/summary", Method::PUT, update_summary),
+        route("/tasks/:id/summary/source", Method::GET, get_summary_source),
+        route(
+            "/tasks/:id/summary/revisions",
+            Method::GET,
+            list_summary_revisions,
+        ),
+        route(
+            "/tasks/:id/summary/revisions/:number",
+            Method::GET,
+            get_summary_revision,
+        ),
+        route(
+            "/tasks/:id/summary/revisions/:number/restore",
+            Method::POST,
+            restore_summary_revision,
+        ),
+        route(
+            "/tasks/:id/summary/diff",
+            Method::GET,
+            diff_summary_revisions,
+        ),
+        route("/tasks/:id/attachments", Method::GET, list_attachments),
+        route("/tasks/:id/attachments", Method::POST, upload_attachments),
+        route("/tasks/:id/attachments/:name", Method::GET, get_attachment),
+        route("/events", Method::GET, events),
+        route("/tokens", Method::GET, list_tokens),
+        route("/tokens", Method::POST, create_token),
+        route("/tokens/:name", Method::DELETE, revoke_token),
+        route("/shares", Method::GET, list_shares),
+        route("/shares", Method::POST, create_share),
+        route("/shares/:id", Method::DELETE, revoke_share),
+        route("/openapi.json", Method::GET, openapi),
+    ]
+}
+
+/// Description of every route, served at `/api/v1/openapi.json`.
+#[derive(OpenApi)]
+#[openapi(
+    info(title = "tasks-todo"),
+    paths(
+        list_tasks,
+        create_task,
+        get_task,
+        update_task,
+        delete_task,
+        list_sessions,
+        open_session,
+        close_session,
+        get_summary,
+        update_summary,
+        get_summary_source,
+        list_summary_revisions,
+        get_summary_revision,
+        restore_summary_revision,
+        diff_summary_revisions,
+        list_attachments,
+        upload_attachments,
+        get_attachment,
+        events,
+        list_tokens,
+        create_token,
+        revoke_token,
+        list_shares,
+        create_share,
+        revoke_share,
+        crate::get_tasks,
+        crate::add_task,
+        crate::modify_task,
+        crate::rename_task,
+        crate::delete_task,
+        crate::archive_task,
+        crate::upload_images,
+        crate::get_summaries,
+        crate::get_images,
+    ),
+    components(schemas(
+        Task,
+        TaskStaus,
+        Session,
+        Image,
+        crate::task::Reopening,
+        SummaryRevision,
+        DiffLine,
+        Change,
+        crate::time::Time,
+        DeleteMode,
+        NewTask,
+        TaskPatch,
+        PendingAttachments,
+        SummaryEdit,
+        Upload,
+        ErrorBody,
+        Event,
+        TokenInfo,
+        NewToken,
+        CreatedToken,
+        Role,
+        ShareInfo,
+        NewShare,
+        CreatedShare,
+        crate::PostTask,
+        crate::AddTask,
+        crate::RenameTask,
+        crate::DeleteTask,
+        crate::ArchiveTask,
+        crate::UploadImages,
+    )),
+    tags(
+        (name = "tasks", description = "The /api/v1 resources"),
+        (name = "tokens", description = "API tokens of the logged in user"),
+        (name = "shares", description = "Read-only links to a task and its subtasks"),
+        (name = "legacy", description = "The original routes, kept for existing clients")
+    )
+)]
+pub struct ApiDoc;
+
+async fn openapi() -> Json<utoipa::openapi::OpenApi> {
+    Json(ApiDoc::openapi())
+}
+
+/// A task is tagged with its revision.
+pub fn etag(task: &Task) -> TypedHeader<ETag> {
+    let etag = format!("\"{}\"", task.get_revision());
+    TypedHeader(etag.parse().expect("a quoted number is a valid etag"))
+}
+
+/// A list of tasks is tagged with a hash of their ids and revisions. The hash
+/// has to stay the same across builds, clients keep the tags around.
+pub fn list_etag<'a>(tasks: impl Iterator<Item = &'a Task>) -> TypedHeader<ETag> {
+    let mut hasher = Sha256::new();
+    for task in tasks {
+        hasher.update(task.get_id().to_le_bytes());
+        hasher.update(task.get_revision().to_le_bytes());
+    }
+    let hash = hasher.finalize();
+    let hex: String = hash[..8]
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect();
+    let etag = format!("\"{}\"", hex);
+    TypedHeader(etag.parse().expect("a quoted hex number is a valid etag"))
+}
+
+/// Fails unless the `If-Match` header, when there is one, matches the current
+/// revision of the task. Has to be checked under the same lock as the
+/// mutation.
+pub fn check_revision(task: &Task, precondition: &IfMatch) -> Result<()> {
+    match &precondition.0 {
+        Some(if_match) if !if_match.precondition_passes(&etag(task).0) => {
+            Err(Error::PreconditionFailed(format!(
+                "Task {} changed in the meantime, it is at revision {}",
+                task.get_id(),
+                task.get_revision()
+            )))
+        }
+        _ => Ok(()),
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, IntoParams)]
+struct ListQuery {
+    /// Include archived tasks
+    #[serde(default)]
+    archived: bool,
+    /// Only tasks assigned to you, or added by you and not assigned to
+    /// anyone else
+    #[serde(default)]
+    mine: bool,
+    /// Only tasks assigned to this user
+    assignee: Option<String>,
+    /// Only tasks added by this user
+    creator: Option<String>,
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/tasks",
+    tag = "tasks",
+    params(ListQuery),
+    responses(
+        (status = 200, description = "All tasks", body = [Task], headers(("ETag" = String, description = "Changes whenever any listed task does"))),
+        (status = 401, description = "mine without a login", body = ErrorBody)
+    )
+)]
+async fn list_tasks(
+    State(state): State<AppState>,
+    user: Option<CurrentUser>,
+    Query(query): Query<ListQuery>,
+) -> Result<(TypedHeader<ETag>, Json<Vec<Task>>)> {
+    let mine = match (query.mine, user) {
+        (false, _) => None,
+        (true, Some(CurrentUser(user))) => Some(user),
+        (true, None) => {
+            return Err(Error::Unauthorized(
+                "Log in to list your own tasks".to_string(),
+            ))
+        }
+    };
+    let state = state.read().await;
+    let tasks: Vec<Task> = state
+        .get_tasks()
+        .iter()
+        .filter(|t| query.archived || !t.is_archived())
+        .filter(|t| mine.as_ref().is_none_or(|user| t.is_for(user)))
+        .filter(|t| query.assignee.is_none() || t.get_assignee() == query.assignee.as_deref())
+        .filter(|t| query.creator.is_none() || t.get_creator() == query.creator.as_deref())
+        .cloned()
+        .collect();
+    Ok((list_etag(tasks.iter()), Json(tasks)))
+}
+
+#[derive(Deserialize, Serialize, Debug, ToSchema)]
+struct NewTask {
+    name: String,
+    parent_id: Option<u64>,
+    /// Who should work on it
+    assignee: Option<String>,
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/v1/tasks",
+    tag = "tasks",
+    request_body = NewTask,
+    responses(
+        (status = 201, description = "The new task", body = Task, headers(("ETag" = String))),
+        (status = 400, description = "Unknown assignee", body = ErrorBody),
+        (status = 403, description = "Viewers can not add tasks", body = ErrorBody),
+        (status = 404, description = "No such parent task", body = ErrorBody)
+    )
+)]
+async fn create_task(
+    State(state): State<AppState>,
+    user: Option<CurrentUser>,
+    Json(body): Json<NewTask>,
+) -> Result<impl IntoResponse> {
+    let by = user.as_ref().map(|CurrentUser(user)| user.as_str());
+    let mut state = state.write().await;
+    state.check_role(by, Role::Member)?;
+    // Checked first so that an unknown assignee doesn't leave a task behind
+    state.check_assignee(body.assignee.as_deref())?;
+    let id = match body.parent_id {
+        Some(parent) => state.add_subtask(parent, body.name, by)?,
+        None => state.add_task(body.name, by)?,
+    };
+    if body.assignee.is_some() {
+        state.assign_task(id, body.assignee)?;
+    }
+    let task = state.get_task(id)?.clone();
+    tracing::info!("Added task {}", task.get_name());
+    Ok((
+        StatusCode::CREATED,
+        [(header::LOCATION, format!("/api/v1/tasks/{}", id))],
+        etag(&task),
+        Json(task),
+    ))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/tasks/{id}",
+    tag = "tasks",
+    params(("id" = u64, Path, description = "Task id")),
+    responses((status = 200, body = Task, headers(("ETag" = String, description = "The revision of the task"))), (status = 404, description = "No such task", body = ErrorBody))
+)]
+async fn get_task(
+    State(state): State<AppState>,
+    Path(id): Path<u64>,
+) -> Result<(TypedHeader<ETag>, Json<Task>)> {
+    let task = state.read().await.get_task(id)?.clone();
+    Ok((etag(&task), Json(task)))
+}
+
+/// Every field is optional, only the given ones change.
+#[derive(Deserialize, Serialize, Debug, Default, ToSchema)]
+struct TaskPatch {
+    name: Option<String>,
+    /// Archives or restores the whole subtree
+    archived: Option<bool>,
+    status: Option<TaskStaus>,
+    /// Markdown summary, only taken when the status changes to `Complete`. One
+    /// linking local images has to come without other changes.
+    summary: Option<String>,
+    /// Why a complete task is reopened, only taken along with its new status
+    reason: Option<String>,
+    /// Who should work on it, `null` to unassign
+    #[serde(default, deserialize_with = "present")]
+    #[schema(value_type = Option<String>)]
+    assignee: Option<Option<String>>,
+}
+
+/// Tells a field that is `null` apart from one that is missing.
+fn present<'de, D, T>(deserializer: D) -> std::result::Result<Option<Option<T>>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    Option::<T>::deserialize(deserializer).map(Some)
+}
+
+/// Returned with 202 when completing a task needs local images uploaded
+/// through the attachments first.
+#[derive(Deserialize, Serialize, Debug, ToSchema)]
+struct PendingAttachments {
+    pending_attachments: Vec<String>,
+}
+
+#[utoipa::path(
+    patch,
+    path = "/api/v1/tasks/{id}",
+    tag = "tasks",
+    params(("id" = u64, Path, description = "Task id"), ("If-Match" = Option<String>, Header, description = "Only change the task if it is still at this revision")),
+    request_body = TaskPatch,
+    responses(
+        (status = 200, description = "The updated task", body = Task, headers(("ETag" = String))),
+        (status = 202, description = "Local images must be uploaded before the task completes", body = PendingAttachments),
+        (status = 400, description = "Unknown assignee, a summary or reason without the status change it belongs to, or a summary with images to upload next to other changes", body = ErrorBody),
+        (status = 403, description = "Viewers can not change tasks, only managers reassign them", body = ErrorBody),
+        (status = 404, description = "No such task", body = ErrorBody),
+        (status = 409, description = "The task is not in a state that allows this", body = ErrorBody),
+        (status = 412, description = "The task changed since the If-Match revision", body = ErrorBody)
+    )
+)]
+async fn update_task(
+    State(state): State<AppState>,
+    user: Option<CurrentUser>,
+    Path(id): Path<u64>,
+    precondition: IfMatch,
+    Json(body): Json<TaskPatch>,
+) -> Result<axum::response::Response> {
+    let by = user.as_ref().map(|CurrentUser(user)| user.as_str());
+    let data = state.data().clone();
+    let mut state = state.write().await;
+    state.check_role(by, Role::Member)?;
+    let task = state.get_task(id)?;
+    check_revision(task, &precondition)?;
+    let current = task.get_status().clone();
+    // Members take and hand out tasks, taking one away from someone else is
+    // up to a manager
+    let assigned = task.get_assignee();
+    if let Some(assignee) = &body.assignee {
+        if assigned
+            .is_some_and(|assigned| Some(assigned) != by && Some(assigned) != assignee.as_deref())
+        {
+            state.check_role(by, Role::Manager)?;
+        }
+        state.check_assignee(assignee.as_deref())?;
+    }
+    // Nothing changes unless all of it can
+    let change = body.status.as_ref().filter(|status| **status != current);
+    if body.summary.is_some() && change != Some(&TaskStaus::Complete) {
+        return Err(Error::BadRequest(
+            "A summary is only taken when the task is completed".to_string(),
+        ));
+    }
+    if body.reason.is_some() && (current != TaskStaus::Complete || change.is_none()) {
+        return Err(Error::BadRequest(
+            "A reason is only taken when a complete task is reopened".to_string(),
+        ));
+    }
+    // Completing with local images waits for their upload, everything else
+    // would change without it
+    let waits = change == Some(&TaskStaus::Complete)
+        && body
+            .summary
+            .as_deref()
+            .is_some_and(|summary| !Task::local_images(summary).is_empty());
+    if waits && (body.name.is_some() || body.archived.is_some() || body.assignee.is_some()) {
+        return Err(Error::BadRequest(
+            "A summary with images to upload can not be combined with other changes".to_string(),
+        ));
+    }
+    match (&current, change) {
+        (TaskStaus::Complete, Some(TaskStaus::Paused)) => {
+            return Err(Error::InvalidState(
+                "Complete tasks can not be paused".to_string(),
+            ))
+        }
+        (_, Some(TaskStaus::Incomplete)) if current != TaskStaus::Complete => {
+            return Err(Error::InvalidState(
+                "Only complete tasks can be moved back to incomplete".to_string(),
+            ))
+        }
+        _ => {}
+    }
+
+    // The transition goes first, it is the only part that can still fail
+    let mut pending = None;
+    match (current, body.status) {
+        (_, None) => {}
+        // Joins the others working on it
+        (TaskStaus::InProgress, Some(TaskStaus::InProgress)) => state.start_task(id, by)?,
+        (current, Some(status)) if current == status => {}
+        (TaskStaus::Complete, Some(status)) => {
+            let start = status == TaskStaus::InProgress;
+            state.reopen_task(id, body.reason, start, by)?;
+        }
+        (TaskStaus::Paused, Some(TaskStaus::InProgress)) => state.resume_task(id, by)?,
+        (_, Some(TaskStaus::InProgress)) => state.start_task(id, by)?,
+        (_, Some(TaskStaus::Paused)) => state.pause_task(id, by)?,
+        (_, Some(TaskStaus::Complete)) => {
+            pending = state.stop_task(id, body.summary, by, &data).await?;
+        }
+        (_, Some(TaskStaus::Incomplete)) => unreachable!("rejected above"),
+    }
+    if let Some(name) = body.name {
+        state.rename_task(id, name)?;
+    }
+    if let Some(archived) = body.archived {
+        state.archive_task(id, archived)?;
+    }
+    if let Some(assignee) = body.assignee {
+        state.assign_task(id, assignee)?;
+    }
+    if let Some(images) = pending {
+        let mut pending_attachments: Vec<String> = images.into_keys().collect();
+        pending_attachments.sort();
+        let body = PendingAttachments {
+            pending_attachments,
+        };
+        return Ok((StatusCode::ACCEPTED, Json(body)).into_response());
+    }
+    tracing::info!("Updated task {}", id);
+    let task = state.get_task(id)?.clone();
+    Ok((etag(&task), Json(task)).into_response())
+}
+
+#[derive(Deserialize, Serialize, Debug, IntoParams)]
+struct DeleteQuery {
+    /// What happens to the children, reparented unless asked otherwise
+    children: Option<DeleteMode>,
+}
+
+#[utoipa::path(
+    delete,
+    path = "/api/v1/tasks/{id}",
+    tag = "tasks",
+    params(("id" = u64, Path, description = "Task id"), DeleteQuery, ("If-Match" = Option<String>, Header, description = "Only change the task if it is still at this revision")),
+    responses((status = 204, description = "Deleted"), (status = 403, description = "Only managers delete tasks", body = ErrorBody), (status = 404, description = "No such task", body = ErrorBody), (status = 412, description = "The task changed since the If-Match revision", body = ErrorBody))
+)]
+async fn delete_task(
+    State(state): State<AppState>,
+    user: Option<CurrentUser>,
+    Path(id): Path<u64>,
+    Query(query): Query<DeleteQuery>,
+    precondition: IfMatch,
+) -> Result<StatusCode> {
+    let by = user.as_ref().map(|CurrentUser(user)| user.as_str());
+    let mode = query.children.unwrap_or(DeleteMode::Reparent);
+    let removed = {
+        let mut state = state.write().await;
+        state.check_role(by, Role::Manager)?;
+        check_revision(state.get_task(id)?, &precondition)?;
+        state.delete_task(id, mode)?
+    };
+    for task in removed.iter() {
+        task.remove_files(state.data()).await;
+    }
+    tracing::info!("Deleted {} task(s) starting at {}", removed.len(), id);
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/tasks/{id}/sessions",
+    tag = "tasks",
+    params(("id" = u64, Path, description = "Task id")),
+    responses((status = 200, body = [Session]), (status = 404, description = "No such task", body = ErrorBody))
+)]
+async fn list_sessions(
+    State(state): State<AppState>,
+    Path(id): Path<u64>,
+) -> Result<Json<Vec<Session>>> {
+    Ok(Json(
+        state.read().await.get_task(id)?.get_sessions().to_vec(),
+    ))
+}
+
+/// Starts a new work session of the logged in user, which starts or resumes
+/// the task.
+#[utoipa::path(
+    post,
+    path = "/api/v1/tasks/{id}/sessions",
+    tag = "tasks",
+    params(("id" = u64, Path, description = "Task id"), ("If-Match" = Option<String>, Header, description = "Only change the task if it is still at this revision")),
+    responses(
+        (status = 201, description = "The new session", body = Session),
+        (status = 403, description = "Viewers can not track time", body = ErrorBody),
+        (status = 404, description = "No such task", body = ErrorBody),
+        (status = 409, description = "The task is complete or you already have a running session", body = ErrorBody),
+        (status = 412, description = "The task changed since the If-Match revision", body = ErrorBody)
+    )
+)]
+async fn open_session(
+    State(state): State<AppState>,
+    user: Option<CurrentUser>,
+    Path(id): Path<u64>,
+    precondition: IfMatch,
+) -> Result<impl IntoResponse> {
+    let by = user.as_ref().map(|CurrentUser(user)| user.as_str());
+    let mut state = state.write().await;
+    state.check_role(by, Role::Member)?;
+    let task = state.get_task(id)?;
+    check_revision(task, &precondition)?;
+    if task.running_session(by).is_some() {
+        return Err(Error::InvalidState(
+            "There already is a running session".to_string(),
+        ));
+    }
+    match task.get_status() {
+        TaskStaus::Paused => state.resume_task(id, by)?,
+        _ => state.start_task(id, by)?,
+    }
+    let session = state
+        .get_task(id)?
+        .running_session(by)
+        .cloned()
+        .ok_or_else(|| Error::Storage("Started task has no session".to_string()))?;
+    tracing::info!("Started a session on task {}", id);
+    Ok((StatusCode::CREATED, Json(session)))
+}
+
+/// Ends the running session of the logged in user. The task is paused once
+/// nobody works on it anymore.
+#[utoipa::path(
+    delete,
+    path = "/api/v1/tasks/{id}/sessions/current",
+    tag = "tasks",
+    params(("id" = u64, Path, description = "Task id"), ("If-Match" = Option<String>, Header, description = "Only change the task if it is still at this revision")),
+    responses((status = 204, description = "Paused"), (status = 403, description = "Viewers can not track time", body = ErrorBody), (status = 404, description = "No such task", body = ErrorBody), (status = 409, description = "The task is not in a state that allows this", body = ErrorBody), (status = 412, description = "The task changed since the If-Match revision", body = ErrorBody))
+)]
+async fn close_session(
+    State(state): State<AppState>,
+    user: Option<CurrentUser>,
+    Path(id): Path<u64>,
+    precondition: IfMatch,
+) -> Result<StatusCode> {
+    let by = user.as_ref().map(|CurrentUser(user)| user.as_str());
+    let mut state = state.write().await;
+    state.check_role(by, Role::Member)?;
+    check_revision(state.get_task(id)?, &precondition)?;
+    state.pause_task(id, by)?;
+    tracing::info!("Paused task {}", id);
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/tasks/{id}/summary",
+    tag = "tasks",
+    params(("id" = u64, Path, description = "Task id")),
+    responses(
+        (status = 200, description = "The summary rendered to html", body = String, content_type = "text/html"),
+        (status = 404, description = "No such task or summary", body = ErrorBody)
+    )
+)]
+async fn get_summary(
+    State(state): State<AppState>,
+    Path(id): Path<u64>,
+) -> Result<Response<String>> {
+    let path = state
+        .read()
+        .await
+        .get_task(id)?
+        .get_summary()
+        .map(str::to_string)
+        .ok_or_else(|| Error::NotFound(format!("Task {} has no summary", id)))?;
+    let file = async_fs::read_to_string(state.data().resolve(&path)?)
+        .await
+        .map_err(|_| Error::NotFound(format!("Summary of task {} not found", id)))?;
+    Ok(Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, "text/html")
+        .body(file)
+        .unwrap())
+}
+
+/// The summary returned by [`get_summary_source`] with changes.
+#[derive(Deserialize, Serialize, Debug, ToSchema)]
+struct SummaryEdit {
+    markdown: String,
+}
+
+/// Replaces the summary and renders it again, whatever the status of the
+/// task.
+#[utoipa::path(
+    put,
+    path = "/api/v1/tasks/{id}/summary",
+    tag = "tasks",
+    params(("id" = u64, Path, description = "Task id"), ("If-Match" = Option<String>, Header, description = "Only change the task if it is still at this revision")),
+    request_body = SummaryEdit,
+    responses(
+        (status = 200, description = "The updated task", body = Task, headers(("ETag" = String))),
+        (status = 400, description = "The summary links images that are not uploaded", body = ErrorBody),
+        (status = 403, description = "Viewers can not change tasks", body = ErrorBody),
+        (status = 404, description = "No such task", body = ErrorBody),
+        (status = 412, description = "The task changed since the If-Match revision", body = ErrorBody)
+    )
+)]
+async fn update_summary(
+    State(state): State<AppState>,
+    user: Option<CurrentUser>,
+    Path(id): Path<u64>,
+    precondition: IfMatch,
+    Json(body): Json<SummaryEdit>,
+) -> Result<(TypedHeader<ETag>, Json<Task>)> {
+    let by = user.as_ref().map(|CurrentUser(user)| user.as_str());
+    let data = state.data().clone();
+    let mut state = state.write().await;
+    state.check_role(by, Role::Member)?;
+    check_revision(state.get_task(id)?, &precondition)?;
+    state.set_summary(id, body.markdown, by, &data).await?;
+    tracing::info!("Updated the summary of task {}", id);
+    let task = state.get_task(id)?.clone();
+    Ok((etag(&task), Json(task)))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/tasks/{id}/summary/source",
+    tag = "tasks",
+    params(("id" = u64, Path, description = "Task id")),
+    responses(
+        (status = 200, description = "The markdown the summary was rendered from", body = String, content_type = "text/markdown"),
+        (status = 404, description = "No such task or summary, or one written before the markdown was kept", body = ErrorBody)
+    )
+)]
+async fn get_summary_source(
+    State(state): State<AppState>,
+    Path(id): Path<u64>,
+) -> Result<Response<String>> {
+    if state.read().await.get_task(id)?.get_summary().is_none() {
+        return Err(Error::NotFound(format!("Task {} has no summary", id)));
+    }
+    let file = async_fs::read_to_string(state.data().resolve(&DataDir::summary_source(id))?)
+        .await
+        .map_err(|_| Error::NotFound(format!("The markdown of task {} was not kept", id)))?;
+    Ok(Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, "text/markdown; charset=utf-8")
+        .body(file)
+        .unwrap())
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/tasks/{id}/summary/revisions",
+    tag = "tasks",
+    params(("id" = u64, Path, description = "Task id")),
+    responses((status = 200, description = "Oldest first, the last one is the current summary", body = [SummaryRevision]), (status = 404, description = "No such task", body = ErrorBody))
+)]
+async fn list_summary_revisions(
+    State(state): State<AppState>,
+    Path(id): Path<u64>,
+) -> Result<Json<Vec<SummaryRevision>>> {
+    Ok(Json(
+        state
+            .read()
+            .await
+            .get_task(id)?
+            .get_summary_revisions()
+            .to_vec(),
+    ))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/tasks/{id}/summary/revisions/{number}",
+    tag = "tasks",
+    params(("id" = u64, Path, description = "Task id"), ("number" = u64, Path, description = "Revision number")),
+    responses(
+        (status = 200, description = "The markdown of the revision", body = String, content_type = "text/markdown"),
+        (status = 404, description = "No such task or revision", body = ErrorBody)
+    )
+)]
+async fn get_summary_revision(
+    State(state): State<AppState>,
+    Path((id, number)): Path<(u64, u64)>,
+) -> Result<Response<String>> {
+    let task = state.read().await.get_task(id)?.clone();
+    let summary = task.read_summary_revision(number, state.data()).await?;
+    Ok(Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, "text/markdown; charset=utf-8")
+        .body(summary)
+        .unwrap())
+}
+
+/// Makes the revision the current summary again, as a new revision.
+#[utoipa::path(
+    post,
+    path = "/api/v1/tasks/{id}/summary/revisions/{number}/restore",
+    tag = "tasks",
+    params(("id" = u64, Path, description = "Task id"), ("number" = u64, Path, description = "Revision number"), ("If-Match" = Option<String>, Header, description = "Only change the task if it is still at this revision")),
+    responses(
+        (status = 200, description = "The updated task", body = Task, headers(("ETag" = String))),
+        (status = 403, description = "Viewers can not change tasks", body = ErrorBody),
+        (status = 404, description = "No such task or revision", body = ErrorBody),
+        (status = 412, description = "The task changed since the If-Match revision", body = ErrorBody)
+    )
+)]
+async fn restore_summary_revision(
+    State(state): State<AppState>,
+    user: Option<CurrentUser>,
+    Path((id, number)): Path<(u64, u64)>,
+    precondition: IfMatch,
+) -> Result<(TypedHeader<ETag>, Json<Task>)> {
+    let by = user.as_ref().map(|CurrentUser(user)| user.as_str());
+    let data = state.data().clone();
+    let mut state = state.write().await;
+    state.check_role(by, Role::Member)?;
+    check_revision(state.get_task(id)?, &precondition)?;
+    state.restore_summary(id, number, by, &data).await?;
+    tracing::info!("Restored revision {} of the summary of task {}", number, id);
+    let task = state.get_task(id)?.clone();
+    Ok((etag(&task), Json(task)))
+}
+
+#[derive(Deserialize, Serialize, Debug, IntoParams)]
+struct DiffQuery {
+    /// The older revision
+    from: u64,
+    /// The newer revision, the current one if left out
+    to: Option<u64>,
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/tasks/{id}/summary/diff",
+    tag = "tasks",
+    params(("id" = u64, Path, description = "Task id"), DiffQuery),
+    responses((status = 200, description = "Every line of both revisions in order, marked by how it changed", body = [DiffLine]), (status = 404, description = "No such task or revision", body = ErrorBody))
+)]
+async fn diff_summary_revisions(
+    State(state): State<AppState>,
+    Path(id): Path<u64>,
+    Query(query): Query<DiffQuery>,
+) -> Result<Json<Vec<DiffLine>>> {
+    Ok(Json(diff_summary(&state, id, query.from, query.to).await?))
+}
+
+/// Line diff between two revisions of the summary, `to` defaults to the
+/// current one.
+pub async fn diff_summary(
+    state: &AppState,
+    id: u64,
+    from: u64,
+    to: Option<u64>,
+) -> Result<Vec<DiffLine>> {
+    let task = state.read().await.get_task(id)?.clone();
+    let to = match to {
+        Some(to) => to,
+        None => task
+            .get_summary_revisions()
+            .last()
+            .map(|revision| revision.number)
+            .ok_or_else(|| Error::NotFound(format!("Task {} has no summary revisions", id)))?,
+    };
+    let old = task.read_summary_revision(from, state.data()).await?;
+    let new = task.read_summary_revision(to, state.data()).await?;
+    Ok(task::diff_lines(&old, &new))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/tasks/{id}/attachments",
+    tag = "tasks",
+    params(("id" = u64, Path, description = "Task id")),
+    responses((status = 200, body = [Image]), (status = 404, description = "No such task", body = ErrorBody))
+)]
+async fn list_attachments(
+    State(state): State<AppState>,
+    Path(id): Path<u64>,
+) -> Result<Json<Vec<Image>>> {
+    Ok(Json(state.read().await.get_task(id)?.get_images().to_vec()))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/tasks/{id}/attachments/{name}",
+    tag = "tasks",
+    params(("id" = u64, Path, description = "Task id"), ("name" = String, Path, description = "Image name")),
+    responses(
+        (status = 200, description = "The image", content_type = "image/*"),
+        (status = 404, description = "No such task or attachment", body = ErrorBody)
+    )
+)]
+async fn get_attachment(
+    State(state): State<AppState>,
+    Path((id, name)): Path<(u64, String)>,
+) -> Result<Response<Body>> {
+    let path = state
+        .read()
+        .await
+        .get_task(id)?
+        .get_images()
+        .iter()
+        .find(|image| image.name == name)
+        .map(|image| image.path.clone())
+        .ok_or_else(|| Error::NotFound(format!("Task {} has no attachment {}", id, name)))?;
+    let bytes = async_fs::read(state.data().resolve(&path)?)
+        .await
+        .map_err(|_| Error::NotFound(format!("Attachment {} not found", name)))?;
+    let format = image::guess_format(&bytes)?;
+    Ok(Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, format.to_mime_type())
+        .body(Body::from(bytes))
+        .unwrap())
+}
+
+/// Streams every change as it happens. A client that falls too far behind
+/// gets a `resync` event and should fetch the tasks again. The stream ends
+/// when the server shuts down.
+#[utoipa::path(
+    get,
+    path = "/api/v1/events",
+    tag = "tasks",
+    responses((status = 200, description = "Server-sent events named after their type", body = Event, content_type = "text/event-stream"))
+)]
+async fn events(
+    State(state): State<AppState>,
+) -> Sse<impl Stream<Item = std::result::Result<sse::Event, Infallible>>> {
+    let closed = state.clone();
+    let stream = BroadcastStream::new(state.subscribe())
+        .take_until(async move { closed.closed().await })
+        .map(|event| {
+            Ok(match event {
+                Ok(event) => sse::Event::default()
+                    .event(event.name())
+                    .json_data(&event)
+                    .unwrap_or_default(),
+                Err(_) => sse::Event::default().event("resync").data("{}"),
+            })
+        });
+    Sse::new(stream).keep_alive(KeepAlive::default())
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/tokens",
+    tag = "tokens",
+    responses((status = 200, body = [TokenInfo]), (status = 401, description = "Not logged in", body = ErrorBody))
+)]
+async fn list_tokens(
+    State(state): State<AppState>,
+    CurrentUser(name): CurrentUser,
+) -> Result<Json<Vec<TokenInfo>>> {
+    let state = state.read().await;
+    let tokens = state.get_user(&name)?.get_tokens();
+    Ok(Json(tokens.iter().map(|token| token.info()).collect()))
+}
+
+#[derive(Deserialize, Serialize, Debug, ToSchema)]
+struct NewToken {
+    /// Letters, digits, - _ . and @
+    name: String,
+}
+
+/// A new token, the only time its secret is shown.
+#[derive(Deserialize, Serialize, Debug, ToSchema)]
+pub struct CreatedToken {
+    pub name: String,
+    pub created: Time,
+    /// Send it as `Authorization: Bearer <token>`
+    pub token: String,
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/v1/tokens",
+    tag = "tokens",
+    request_body = NewToken,
+    responses(
+        (status = 201, description = "The token", body = CreatedToken),
+        (status = 400, description = "Invalid token name", body = ErrorBody),
+        (status = 401, description = "Not logged in", body = ErrorBody),
+        (status = 409, description = "A token with this name exists", body = ErrorBody)
+    )
+)]
+async fn create_token(
+    State(state): State<AppState>,
+    CurrentUser(name): CurrentUser,
+    Json(body): Json<NewToken>,
+) -> Result<(StatusCode, Json<CreatedToken>)> {
+    let secret = user::new_secret();
+    let mut state = state.write().await;
+    let token = state.add_token(&name, body.name, user::hash_secret(&secret))?;
+    tracing::info!("{} created the token {}", name, token.name);
+    Ok((
+        StatusCode::CREATED,
+        Json(CreatedToken {
+            name: token.name,
+            created: token.created,
+            token: secret,
+        }),
+    ))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/api/v1/tokens/{name}",
+    tag = "tokens",
+    params(("name" = String, Path, description = "Token name")),
+    responses((status = 204, description = "Revoked"), (status = 401, description = "Not logged in", body = ErrorBody), (status = 404, description = "No such token", body = ErrorBody))
+)]
+async fn revoke_token(
+    State(state): State<AppState>,
+    CurrentUser(name): CurrentUser,
+    Path(token): Path<String>,
+) -> Result<StatusCode> {
+    state.write().await.revoke_token(&name, &token)?;
+    tracing::info!("{} revoked the token {}", name, token);
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/shares",
+    tag = "shares",
+    responses((status = 200, body = [ShareInfo]))
+)]
+async fn list_shares(State(state): State<AppState>) -> Json<Vec<ShareInfo>> {
+    let state = state.read().await;
+    Json(
+        state
+            .get_shares()
+            .iter()
+            .map(|share| share.info())
+            .collect(),
+    )
+}
+
+#[derive(Deserialize, Serialize, Debug, ToSchema)]
+struct NewShare {
+    /// The task to share along with its subtasks
+    task_id: u64,
+}
+
+/// A new share link, the only time it is shown.
+#[derive(Deserialize, Serialize, ToSchema)]
+struct CreatedShare {
+    #[serde(flatten)]
+    info: ShareInfo,
+    /// Opens the tasks without a login, relative to the server
+    url: String,
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/v1/shares",
+    tag = "shares",
+    request_body = NewShare,
+    responses(
+        (status = 201, description = "The link", body = CreatedShare),
+        (status = 403, description = "Viewers can not share tasks", body = ErrorBody),
+        (status = 404, description = "No such task", body = ErrorBody)
+    )
+)]
+async fn create_share(
+    State(state): State<AppState>,
+    user: Option<CurrentUser>,
+    Json(body): Json<NewShare>,
+) -> Result<(StatusCode, Json<CreatedShare>)> {
+    let by = user.as_ref().map(|CurrentUser(user)| user.as_str());
+    let secret = user::new_secret();
+    let mut state = state.write().await;
+    state.check_role(by, Role::Member)?;
+    let info = state
+        .add_share(body.task_id, user::hash_secret(&secret), by)?
+        .info();
+    tracing::info!("Shared task {} as {}", body.task_id, info.id);
+    Ok((
+        StatusCode::CREATED,
+        Json(CreatedShare {
+            info,
+            url: share::url(&secret),
+        }),
+    ))
+}
+
+/// Revokes a link. Members can revoke the links they made, managers any.
+#[utoipa::path(
+    delete,
+    path = "/api/v1/shares/{id}",
+    tag = "shares",
+    params(("id" = String, Path, description = "Share link id")),
+    responses((status = 204, description = "Revoked"), (status = 403, description = "Made by someone else", body = ErrorBody), (status = 404, description = "No such link", body = ErrorBody))
+)]
+async fn revoke_share(
+    State(state): State<AppState>,
+    user: Option<CurrentUser>,
+    Path(id): Path<String>,
+) -> Result<StatusCode> {
+    let by = user.as_ref().map(|CurrentUser(user)| user.as_str());
+    let mut state = state.write().await;
+    state.check_role(by, Role::Member)?;
+    if state.get_share(&id)?.created_by.as_deref() != by {
+        state.check_role(by, Role::Manager)?;
+    }
+    state.revoke_share(&id)?;
+    tracing::info!("Revoked the share link {}", id);
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// An image uploaded as a data url.
+#[derive(Deserialize, Serialize, Debug, ToSchema)]
+pub struct Upload {
+    pub name: String,
+    pub data: String,
+    /// png, jpg, jpeg, gif, webp or bmp
+    pub extension: String,
+}
+
+/// Image types uploads may have, the extension becomes part of their path.
+const IMAGE_EXTENSIONS: [&str; 6] = ["png", "jpg", "jpeg", "gif", "webp", "bmp"];
+
+#[utoipa::path(
+    post,
+    path = "/api/v1/tasks/{id}/attachments",
+    tag = "tasks",
+    params(("id" = u64, Path, description = "Task id"), ("If-Match" = Option<String>, Header, description = "Only change the task if it is still at this revision")),
+    request_body = [Upload],
+    responses(
+        (status = 201, description = "The task with the images attached", body = Task, headers(("ETag" = String))),
+        (status = 400, description = "An image is not a base64 data url or has an unknown extension", body = ErrorBody),
+        (status = 403, description = "Viewers can not upload", body = ErrorBody),
+        (status = 404, description = "No such task", body = ErrorBody),
+        (status = 412, description = "The task changed since the If-Match revision", body = ErrorBody)
+    )
+)]
+async fn upload_attachments(
+    State(state): State<AppState>,
+    user: Option<CurrentUser>,
+    Path(id): Path<u64>,
+    precondition: IfMatch,
+    Json(body): Json<Vec<Upload>>,
+) -> Result<impl IntoResponse> {
+    let by = user.as_ref().map(|CurrentUser(user)| user.as_str());
+    state.read().await.check_role(by, Role::Member)?;
+    attach_uploads(&state, id, by, &precondition, &body).await?;
+    let task = state.read().await.get_task(id)?.clone();
+    Ok((StatusCode::CREATED, etag(&task), Json(task)))
+}
+
+/// Stores the uploaded images and attaches them to the task. If a summary is
+/// waiting for them, its links are pointed at the uploads and the task is
+/// completed.
+pub async fn attach_uploads(
+    state: &AppState,
+    id: u64,
+    by: Option<&str>,
+    precondition: &IfMatch,
+    uploads: &[Upload],
+) -> Result<()> {
+    use base64::{engine::general_purpose, Engine as _};
+
+    if uploads.is_empty() {
+        return Err(Error::BadRequest("No images uploaded".to_string()));
+    }
+    // Decode everything up front so a bad upload leaves nothing half written
+    let mut decoded = Vec::new();
+    for image in uploads.iter() {
+        // Strip the data url prefix and decode the Base64 data
+        let data = image
+            .data
+            .split_once(',')
+            .map(|(_, data)| data)
+            .ok_or_else(|| Error::BadRequest(format!("Image {} is not a data url", image.name)))?;
+        let bytes = general_purpose::STANDARD.decode(data).map_err(|e| {
+            Error::BadRequest(format!("Image {} is not valid base64: {}", image.name, e))
+        })?;
+        let extension = image.extension.to_ascii_lowercase();
+        if !IMAGE_EXTENSIONS.contains(&extension.as_str()) {
+            return Err(Error::BadRequest(format!(
+                "Image {} has an unknown extension {}",
+                image.name, image.extension
+            )));
+        }
+        decoded.push((DataDir::image(id, &extension), bytes));
+    }
+
+    let data = state.data();
+    // One lock for the whole change, so nobody gets in after the check
+    let mut app = state.write().await;
+    check_revision(app.get_task(id)?, precondition)?;
+    // Every file is on disk before the task links any of them
+    for (written, (path, bytes)) in decoded.iter().enumerate() {
+        if let Err(e) = data.write(path, bytes).await {
+            let written: Vec<String> = decoded[..written]
+                .iter()
+                .map(|(path, _)| path.clone())
+                .collect();
+            remove_stored(data, &written).await;
+            return Err(e);
+        }
+    }
+    let mut replaced = Vec::new();
+    for (image, (path, _)) in uploads.iter().zip(&decoded) {
+        replaced.extend(app.attach_image(id, image.name.clone(), path.clone())?);
+    }
+
+    // Finish a stop that was waiting for the images
+    let temp_md = data.resolve(&DataDir::pending_summary(id))?;
+    if let Ok(contents) = async_fs::read_to_string(&temp_md).await {
+        let paths: HashMap<&str, &str> = uploads
+            .iter()
+            .zip(&decoded)
+            .map(|(image, (path, _))| (image.name.as_str(), path.as_str()))
+            .collect();
+        let new_contents = Task::relink_images(&contents, &paths);
+        // With images still missing the relinked summary keeps waiting for
+        // them in the temp file
+        if app
+            .stop_task(id, Some(new_contents), by, data)
+            .await?
+            .is_none()
+        {
+            async_fs::remove_file(&temp_md).await?;
+        }
+    }
+    drop(app);
+
+    // The stored path is new on every upload, drop the old files
+    remove_stored(data, &replaced).await;
+    Ok(())
+}
+
+/// Removes files by their stored path, failures are only logged.
+async fn remove_stored(data: &DataDir, paths: &[String]) {
+    for path in paths {
+        let Ok(path) = data.resolve(path) else {
+            continue;
+        };
+        if let Err(e) = async_fs::remove_file(&path).await {
+            tracing::warn!("Failed to remove {}: {}", path.display(), e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{list_etag, ApiDoc};
+    use crate::task::Task;
+    use crate::tests::{request, request_text, request_with_headers, test_router};
+    use axum::http::StatusCode;
+    use serde_json::{json, Value};
+    use utoipa::OpenApi;
+
+    #[tokio::test]
+    async fn test_task_lifecycle() {
+        let router = test_router();
+        let (status, task) = request(
+            &router,
+            "POST",
+            "/api/v1/tasks",
+            json!({ "name": "Child", "parent_id": 0 }),
+        )
+        .await;
+        assert_eq!(status, StatusCode::CREATED);
+        assert_eq!(task["parent_id"], 0);
+        let uri = format!("/api/v1/tasks/{}", task["id"]);
+
+        let (status, session) =
+            request(&router, "POST", &format!("{}/sessions", uri), Value::Null).await;
+        assert_eq!(status, StatusCode::CREATED);
+        assert!(session["end"].is_null());
+        let (status, _) = request(&router, "POST", &format!("{}/sessions", uri), Value::Null).await;
+        assert_eq!(status, StatusCode::CONFLICT);
+        let (status, _) = request(
+            &router,
+            "DELETE",
+            &format!("{}/sessions/current", uri),
+            Value::Null,
+        )
+        .await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+
+        let (status, task) = request(
+            &router,
+            "PATCH",
+            &uri,
+            json!({ "name": "Renamed", "status": "Complete" }),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(task["name"], "Renamed");
+        assert_eq!(task["status"], "Complete");
+
+        let (status, task) = request(
+            &router,
+            "PATCH",
+            &uri,
+            json!({ "status": "InProgress", "reason": "Missed a case" }),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(task["reopened"][0]["reason"], "Missed a case");
+        assert_eq!(task["sessions"].as_array().unwrap().len(), 2);
+
+        let (status, _) = request(&router, "DELETE", &uri, Value::Null).await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        let (status, _) = request(&router, "GET", &uri, Value::Null).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_invalid_transitions() {
+        let router = test_router();
+        let (status, body) = request(
+            &router,
+            "PATCH",
+            "/api/v1/tasks/0",
+            json!({ "status": "Paused" }),
+        )
+        .await;
+        assert_eq!(status, StatusCode::CONFLICT);
+        assert_eq!(body["error"], "invalid_state");
+        let (status, _) = request(
+            &router,
+            "DELETE",
+            "/api/v1/tasks/0/sessions/current",
+            Value::Null,
+        )
+        .await;
+        assert_eq!(status, StatusCode::CONFLICT);
+        let (status, _) = request(&router, "GET", "/api/v1/tasks/0/summary", Value::Null).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        // A refused transition leaves the other fields alone too
+        let uri = "/api/v1/tasks/0";
+        request(&router, "PATCH", uri, json!({ "status": "InProgress" })).await;
+        let patch = json!({ "name": "Renamed", "status": "Incomplete" });
+        let (status, _) = request(&router, "PATCH", uri, patch).await;
+        assert_eq!(status, StatusCode::CONFLICT);
+        let (_, task) = request(&router, "GET", uri, Value::Null).await;
+        assert_eq!(task["name"], "Write report");
+        for patch in [
+            json!({ "name": "Renamed", "summary": "# Done" }),
+            json!({ "summary": "# Done", "status": "Paused" }),
+            json!({ "reason": "Missed a case", "status": "Paused" }),
+        ] {
+            let (status, _) = request(&router, "PATCH", uri, patch.clone()).await;
+            assert_eq!(status, StatusCode::BAD_REQUEST, "{}", patch);
+        }
+        let (_, task) = request(&router, "GET", uri, Value::Null).await;
+        assert_eq!(task["name"], "Write report");
+        assert_eq!(task["status"], "InProgress");
+        let (status, _) = request(
+            &router,
+            "GET",
+            "/api/v1/tasks/0/attachments/plot",
+            Value::Null,
+        )
+        .await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_pending_images() {
+        let router = test_router();
+        let summary = "See [docs](http://x) and ![plot](plot.png)\n![a](a.png) ![b](b.png)\n";
+        // Nothing else may change while the images are missing
+        let stop = json!({ "status": "Complete", "summary": summary, "name": "x" });
+        let (status, _) = request(&router, "PATCH", "/api/v1/tasks/0", stop).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        let (_, task) = request(&router, "GET", "/api/v1/tasks/0", Value::Null).await;
+        assert_eq!(task["name"], "Write report");
+        assert_eq!(task["revision"], 0);
+
+        let stop = json!({ "status": "Complete", "summary": summary });
+        let (status, body) = request(&router, "PATCH", "/api/v1/tasks/0", stop).await;
+        assert_eq!(status, StatusCode::ACCEPTED);
+        assert_eq!(body["pending_attachments"], json!(["a", "b", "plot"]));
+
+        let upload = |name: &str| json!({ "name": name, "data": "data:image/png;base64,aGk=", "extension": "png" });
+        let uploads = json!([upload("plot"), upload("a")]);
+        let stale = [("If-Match", "\"5\"")];
+        let attachments = "/api/v1/tasks/0/attachments";
+        let (status, _, _) =
+            request_with_headers(&router, "POST", attachments, &stale, uploads.clone()).await;
+        assert_eq!(status, StatusCode::PRECONDITION_FAILED);
+        let (status, task) = request(&router, "POST", attachments, uploads).await;
+        assert_eq!(status, StatusCode::CREATED);
+        assert_eq!(task["status"], "Incomplete");
+        // The rest can follow later
+        let (_, task) = request(&router, "POST", attachments, json!([upload("b")])).await;
+        assert_eq!(task["status"], "Complete");
+        let (_, source) = request_text(&router, "/api/v1/tasks/0/summary/source").await;
+        assert!(
+            source.starts_with("See [docs](http://x) and ![plot](images/0_"),
+            "{}",
+            source
+        );
+        assert_eq!(source.matches("](images/0_").count(), 3, "{}", source);
+        for local in ["](plot.png)", "](a.png)", "](b.png)"] {
+            assert!(!source.contains(local), "{}", source);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_bad_upload_writes_nothing() {
+        use crate::{app::App, config::Config, data_dir::DataDir, state::AppState, storage};
+        use std::sync::Arc;
+
+        let storage = Arc::new(storage::SqliteStorage::open(":memory:").unwrap());
+        let mut app = App::new();
+        app.add_task("Write report".to_string(), None).unwrap();
+        let data = DataDir::temp();
+        let state = AppState::new(app, storage, data.clone(), crate::zone::Zone::utc());
+        let router = crate::router(state, &Config::default());
+
+        let good =
+            json!({ "name": "plot", "data": "data:image/png;base64,aGk=", "extension": "png" });
+        let bad =
+            json!({ "name": "graph", "data": "data:image/png;base64,!!", "extension": "png" });
+        let attachments = "/api/v1/tasks/0/attachments";
+        let (status, _) = request(&router, "POST", attachments, json!([good, bad])).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        let (_, images) = request(&router, "GET", attachments, Value::Null).await;
+        assert_eq!(images, json!([]));
+        let written = std::fs::read_dir(data.root().join("images"))
+            .map(|entries| entries.count())
+            .unwrap_or(0);
+        assert_eq!(written, 0);
+        let _ = std::fs::remove_dir_all(data.root());
+    }
+
+    #[tokio::test]
+    async fn test_edit_summary() {
+        let router = test_router();
+        let uri = "/api/v1/tasks/0/summary";
+        let (status, _) = request(&router, "GET", &format!("{}/source", uri), Value::Null).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        // Summaries can be written before the task is done
+        let (status, headers, task) =
+            request_with_headers(&router, "PUT", uri, &[], json!({ "markdown": "# Plan" })).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(task["status"], "Incomplete");
+        assert_eq!(task["summary"], "summaries/0.html");
+        let (status, _) = request(&router, "GET", &format!("{}/source", uri), Value::Null).await;
+        assert_eq!(status, StatusCode::OK);
+
+        let stale = [("If-Match", "\"0\"")];
+        let body = json!({ "markdown": "# Done" });
+        let (status, _, _) = request_with_headers(&router, "PUT", uri, &stale, body.clone()).await;
+        assert_eq!(status, StatusCode::PRECONDITION_FAILED);
+        let current = [("If-Match", headers["etag"].to_str().unwrap())];
+        let (status, _, _) = request_with_headers(&router, "PUT", uri, &current, body).await;
+        assert_eq!(status, StatusCode::OK);
+
+        let body = json!({ "markdown": "![plot](plot.png)" });
+        let (status, body) = request(&router, "PUT", uri, body).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert!(body["message"].as_str().unwrap().contains("plot"));
+    }
+
+    #[tokio::test]
+    async fn test_summary_revisions() {
+        let router = test_router();
+        let uri = "/api/v1/tasks/0/summary";
+        for markdown in ["# Plan\nDraft", "# Plan\nFinal"] {
+            request(&router, "PUT", uri, json!({ "markdown": markdown })).await;
+        }
+        let (status, revisions) =
+            request(&router, "GET", &format!("{}/revisions", uri), Value::Null).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(revisions[1]["number"], 2);
+        assert!(revisions[1]["author"].is_null());
+        let (status, _) =
+            request(&router, "GET", &format!("{}/revisions/1", uri), Value::Null).await;
+        assert_eq!(status, StatusCode::OK);
+
+        let (status, diff) =
+            request(&router, "GET", &format!("{}/diff?from=1", uri), Value::Null).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            diff,
+            json!([
+                { "change": "same", "line": "# Plan" },
+                { "change": "removed", "line": "Draft" },
+                { "change": "added", "line": "Final" }
+            ])
+        );
+        let (_, diff) = request(
+            &router,
+            "GET",
+            &format!("{}/diff?from=2&to=1", uri),
+            Value::Null,
+        )
+        .await;
+        assert_eq!(diff[1]["change"], "removed");
+        assert_eq!(diff[1]["line"], "Final");
+
+        let restore = format!("{}/revisions/1/restore", uri);
+        let (status, task) = request(&router, "POST", &restore, Value::Null).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(task["summary_revisions"][2]["restored"], 1);
+        let (_, diff) = request(&router, "GET", &format!("{}/diff?from=1", uri), Value::Null).await;
+        assert!(diff
+            .as_array()
+            .unwrap()
+            .iter()
+            .all(|line| line["change"] == "same"));
+
+        for missing in ["/revisions/7", "/diff?from=7"] {
+            let (status, _) =
+                request(&router, "GET", &format!("{}{}", uri, missing), Value::Null).await;
+            assert_eq!(status, StatusCode::NOT_FOUND, "{}", missing);
+        }
+        let (status, _) = request(
+            &router,
+            "POST",
+            &format!("{}/revisions/7/restore", uri),
+            Value::Null,
+        )
+        .await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_revisions() {
+        let router = test_router();
+        let (_, headers, _) =
+            request_with_headers(&router, "GET", "/api/v1/tasks", &[], Value::Null).await;
+        let list_etag = headers["etag"].clone();
+        let (_, headers, task) =
+            request_with_headers(&router, "GET", "/api/v1/tasks/0", &[], Value::Null).await;
+        assert_eq!(headers["etag"], "\"0\"");
+        assert_eq!(task["revision"], 0);
+
+        let rename = |name: &str| json!({ "name": name });
+        let (status, headers, task) = request_with_headers(
+            &router,
+            "PATCH",
+            "/api/v1/tasks/0",
+            &[("if-match", "\"0\"")],
+            rename("First"),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(headers["etag"], "\"1\"");
+        assert_eq!(task["revision"], 1);
+
+        // A second edit based on the same revision conflicts
+        let (status, _, body) = request_with_headers(
+            &router,
+            "PATCH",
+            "/api/v1/tasks/0",
+            &[("if-match", "\"0\"")],
+            rename("Second"),
+        )
+        .await;
+        assert_eq!(status, StatusCode::PRECONDITION_FAILED);
+        assert_eq!(body["error"], "precondition_failed");
+        let (status, _, _) = request_with_headers(
+            &router,
+            "POST",
+            "/renametask",
+            &[("if-match", "\"0\"")],
+            json!({ "id": 0, "name": "Second" }),
+        )
+        .await;
+        assert_eq!(status, StatusCode::PRECONDITION_FAILED);
+        let (status, _, _) = request_with_headers(
+            &router,
+            "DELETE",
+            "/api/v1/tasks/0",
+            &[("if-match", "\"0\"")],
+            Value::Null,
+        )
+        .await;
+        assert_eq!(status, StatusCode::PRECONDITION_FAILED);
+
+        // No If-Match, or a wildcard, always applies
+        let (status, _) = request(&router, "PATCH", "/api/v1/tasks/0", rename("Third")).await;
+        assert_eq!(status, StatusCode::OK);
+        let (status, _, task) = request_with_headers(
+            &router,
+            "PATCH",
+            "/api/v1/tasks/0",
+            &[("if-match", "*")],
+            rename("Fourth"),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(task["revision"], 3);
+
+        let (_, headers, _) =
+            request_with_headers(&router, "GET", "/api/v1/tasks", &[], Value::Null).await;
+        assert_ne!(headers["etag"], list_etag);
+    }
+
+    #[tokio::test]
+    async fn test_repeated_start_keeps_revision() {
+        let router = test_router();
+        let start = json!({ "status": "InProgress" });
+        let (status, first, _) =
+            request_with_headers(&router, "PATCH", "/api/v1/tasks/0", &[], start.clone()).await;
+        assert_eq!(status, StatusCode::OK);
+        let (status, second, task) =
+            request_with_headers(&router, "PATCH", "/api/v1/tasks/0", &[], start).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(second["etag"], first["etag"]);
+        assert_eq!(task["revision"], 1);
+        assert_eq!(task["sessions"].as_array().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_list_etag_is_stable() {
+        let tasks = [
+            Task::new(0, None, "a".to_string()),
+            Task::new(1, Some(0), "b".to_string()),
+        ];
+        let etag = list_etag(tasks.iter()).0;
+        // Clients keep the tags across server upgrades
+        assert_eq!(etag, "\"6cc4f0e930b34481\"".parse().unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_event_stream() {
+        use axum::{body::Body, http::Request};
+        use hyper::body::HttpBody;
+        use tower::ServiceExt;
+
+        let router = test_router();
+        let events = Request::get("/api/v1/events").body(Body::empty()).unwrap();
+        let response = router.clone().oneshot(events).await.unwrap();
+        assert_eq!(response.headers()["content-type"], "text/event-stream");
+        let mut body = response.into_body();
+        request(
+            &router,
+            "PATCH",
+            "/api/v1/tasks/0",
+            json!({ "name": "Renamed" }),
+        )
+        .await;
+        let chunk = body.data().await.unwrap().unwrap();
+        let chunk = std::str::from_utf8(&chunk).unwrap();
+        assert!(chunk.starts_with("event:task_renamed\n"), "{}", chunk);
+        let data: Value =
+            serde_json::from_str(chunk.lines().nth(1).unwrap().strip_prefix("data:").unwrap())
+                .unwrap();
+        assert_eq!(data["type"], "task_renamed");
+        assert_eq!(data["task"]["name"], "Renamed");
+    }
+
+    /// Every documented operation has to be routed.
+    #[tokio::test]
+    async fn test_openapi_operations_are_routed() {
+        use axum::{body::Body, http::Request};
+        use tower::ServiceExt;
+
+        let spec = serde_json::to_value(ApiDoc::openapi()).unwrap();
+        for (path, operations) in spec["paths"].as_object().unwrap() {
+            let uri = path
+                .replace("{id}", "0")
+                .replace("{name}", "missing")
+                .replace("{key}", "missing");
+            for method in operations.as_object().unwrap().keys() {
+                let method = method.to_uppercase();
+                let request = Request::builder()
+                    .method(method.as_str())
+                    .uri(&uri)
+                    .body(Body::empty())
+                    .unwrap();
+                let response = test_router().oneshot(request).await.unwrap();
+                let status = response.status();
+                assert_ne!(
+                    status,
+                    StatusCode::METHOD_NOT_ALLOWED,
+                    "{} {}",
+                    method,
+                    path
+                );
+                if status == StatusCode::NOT_FOUND {
+                    // Unrouted requests fall through to an empty 404, every
+                    // handler answers with a json body
+                    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+                    assert!(
+                        !body.is_empty(),
+                        "{} {} is documented but not routed",
+                        method,
+                        path
+                    );
+                }
+            }
+        }
+    }
+
+    /// The route tables and the OpenAPI spec list the same operations.
+    #[test]
+    fn test_routes_are_documented() {
+        let spec = serde_json::to_value(ApiDoc::openapi()).unwrap();
+        let undocumented = [
+            ("/", "get"),
+            ("/logout", "post"),
+            ("/api/v1/openapi.json", "get"),
+        ];
+        let mut routed = Vec::new();
+        for (prefix, routes) in [("", crate::routes()), ("/api/v1", super::routes())] {
+            for (path, method, _) in routes {
+                let path = format!("{}{}", prefix, path)
+                    .split('/')
+                    .map(|part| match part.strip_prefix(':') {
+                        Some(param) => format!("{{{}}}", param),
+                        None => part.to_string(),
+                    })
+                    .collect::<Vec<_>>()
+                    .join("/");
+                routed.push((path, method.as_str().to_lowercase()));
+            }
+        }
+        for (path, method) in routed.iter() {
+            if !undocumented.contains(&(path.as_str(), method.as_str())) {
+                assert!(
+                    spec["paths"][path].get(method).is_some(),
+                    "{} {} is routed but missing from the OpenAPI spec",
+                    method,
+                    path
+                );
+            }
+        }
+        for (path, operations) in spec["paths"].as_object().unwrap() {
+            for method in operations.as_object().unwrap().keys() {
+                assert!(
+                    routed.contains(&(path.clone(), method.clone())),
+                    "{} {} is documented but not in a route table",
+                    method,
+                    path
+                );
+            }
+        }
+    }
+}
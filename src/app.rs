@@ -1,15 +1,41 @@
 use std::collections::HashMap;
 
 use crate::{
+    data_dir::DataDir,
+    error::{Error, Result},
+    event::Event,
+    share::Share,
+    storage::Storage,
     task::{Task, TaskStaus},
-    Result,
+    time::Time,
+    user::{self, ApiToken, Role, TokenInfo, User},
 };
 use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+/// What happens to the children of a deleted task.
+#[derive(Serialize, Deserialize, ToSchema, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum DeleteMode {
+    /// Delete the whole subtree
+    Cascade,
+    /// Move the children up to the parent of the deleted task
+    Reparent,
+}
 
 #[derive(Serialize, Deserialize, Clone)]
 pub struct App {
     tasks: Vec<Task>,
     running_id: u64,
+    users: Vec<User>,
+    shares: Vec<Share>,
+    /// Changes since the last `take_events`
+    #[serde(skip)]
+    events: Vec<Event>,
+    /// Whether a mutation without an event happened since the last
+    /// `take_changed`, like adding a user
+    #[serde(skip)]
+    changed: bool,
 }
 
 impl App {
@@ -17,81 +43,732 @@ impl App {
         App {
             tasks: Vec::new(),
             running_id: 0,
+            users: Vec::new(),
+            shares: Vec::new(),
+            events: Vec::new(),
+            changed: false,
         }
     }
 
-    pub async fn save(&self) -> Result<()> {
-        let serialized = serde_json::to_string_pretty(self)?;
-        async_fs::write("data.json", serialized).await?;
-        Ok(())
+    pub fn from_parts(
+        tasks: Vec<Task>,
+        running_id: u64,
+        users: Vec<User>,
+        shares: Vec<Share>,
+    ) -> App {
+        App {
+            tasks,
+            running_id,
+            users,
+            shares,
+            events: Vec::new(),
+            changed: false,
+        }
     }
 
-    pub async fn load() -> Result<App> {
-        let serialized = async_fs::read_to_string("data.json").await?;
-        let app = serde_json::from_str(&serialized)?;
-        Ok(app)
+    pub fn save(&self, storage: &dyn Storage) -> Result<()> {
+        storage.save(self)
     }
 
-    pub fn add_task(&mut self, name: String) -> Result<u64> {
-        let task = Task::new(self.running_id, None, name);
+    /// Loads the app from the storage, starting with an empty one if nothing
+    /// has been stored yet.
+    pub fn load(storage: &dyn Storage) -> Result<App> {
+        Ok(storage.load()?.unwrap_or_else(App::new))
+    }
+
+    /// Adds a task, `by` is who adds it.
+    pub fn add_task(&mut self, name: String, by: Option<&str>) -> Result<u64> {
+        let mut task = Task::new(self.running_id, None, name);
+        task.creator = by.map(str::to_string);
+        self.events.push(Event::Created { task: task.clone() });
         self.tasks.push(task);
         self.running_id += 1;
         Ok(self.running_id - 1)
     }
 
-    pub fn add_subtask(&mut self, parent_id: u64, name: String) -> Result<u64> {
-        let task = Task::new(self.running_id, Some(parent_id), name);
+    pub fn add_subtask(&mut self, parent_id: u64, name: String, by: Option<&str>) -> Result<u64> {
+        if !self.tasks.iter().any(|task| task.get_id() == parent_id) {
+            return Err(Error::TaskNotFound(parent_id));
+        }
+        let mut task = Task::new(self.running_id, Some(parent_id), name);
+        task.creator = by.map(str::to_string);
+        self.events.push(Event::Created { task: task.clone() });
         self.tasks.push(task);
         self.running_id += 1;
         Ok(self.running_id - 1)
     }
 
-    pub fn start_task(&mut self, id: u64) -> Result<()> {
+    /// Starts a session of `by` on the task, and on its parents that were not
+    /// started yet.
+    pub fn start_task(&mut self, id: u64, by: Option<&str>) -> Result<()> {
         let task = self
             .tasks
             .iter_mut()
             .find(|task| task.get_id() == id)
-            .ok_or("Task not found")?;
-        task.start();
+            .ok_or(Error::TaskNotFound(id))?;
+        if !task.start(by)? {
+            return Ok(());
+        }
+        self.events.push(Event::Started { task: task.clone() });
         if task.get_parent_id().is_some() {
             let parent_id = task.get_parent_id().unwrap();
             let parent = self
                 .tasks
                 .iter_mut()
                 .find(|task| task.get_id() == parent_id)
-                .ok_or("Task not found")?;
+                .ok_or(Error::TaskNotFound(parent_id))?;
             if parent.get_status() == &TaskStaus::Incomplete {
-                self.start_task(parent_id)?;
+                self.start_task(parent_id, by)?;
             }
         }
         Ok(())
     }
 
+    /// Ends the session of `by`, or every session without a user.
+    pub fn pause_task(&mut self, id: u64, by: Option<&str>) -> Result<()> {
+        let task = self
+            .tasks
+            .iter_mut()
+            .find(|task| task.get_id() == id)
+            .ok_or(Error::TaskNotFound(id))?;
+        task.pause(by)?;
+        self.events.push(Event::Paused { task: task.clone() });
+        Ok(())
+    }
+
+    pub fn resume_task(&mut self, id: u64, by: Option<&str>) -> Result<()> {
+        let task = self
+            .tasks
+            .iter_mut()
+            .find(|task| task.get_id() == id)
+            .ok_or(Error::TaskNotFound(id))?;
+        task.resume(by)?;
+        self.events.push(Event::Started { task: task.clone() });
+        Ok(())
+    }
+
+    /// Reopens a complete task, optionally starting a new session on it right
+    /// away.
+    pub fn reopen_task(
+        &mut self,
+        id: u64,
+        reason: Option<String>,
+        start: bool,
+        by: Option<&str>,
+    ) -> Result<()> {
+        let task = self
+            .tasks
+            .iter_mut()
+            .find(|task| task.get_id() == id)
+            .ok_or(Error::TaskNotFound(id))?;
+        task.reopen(reason)?;
+        self.events.push(Event::Updated { task: task.clone() });
+        if start {
+            self.start_task(id, by)?;
+        }
+        Ok(())
+    }
+
     pub async fn stop_task(
         &mut self,
         id: u64,
         summary: Option<String>,
+        by: Option<&str>,
+        data: &DataDir,
     ) -> Result<Option<HashMap<String, String>>> {
         let task = self
             .tasks
             .iter_mut()
             .find(|task| task.get_id() == id)
-            .ok_or("Task not found")?;
-        let images = task.stop(summary).await;
+            .ok_or(Error::TaskNotFound(id))?;
+        let images = task.stop(summary, by, data).await?;
+        if images.is_none() {
+            self.events.push(Event::Stopped { task: task.clone() });
+        }
         Ok(images)
     }
 
+    pub async fn set_summary(
+        &mut self,
+        id: u64,
+        summary: String,
+        by: Option<&str>,
+        data: &DataDir,
+    ) -> Result<()> {
+        let task = self
+            .tasks
+            .iter_mut()
+            .find(|task| task.get_id() == id)
+            .ok_or(Error::TaskNotFound(id))?;
+        task.set_summary(summary, by, data).await?;
+        self.events.push(Event::Updated { task: task.clone() });
+        Ok(())
+    }
+
+    pub async fn restore_summary(
+        &mut self,
+        id: u64,
+        number: u64,
+        by: Option<&str>,
+        data: &DataDir,
+    ) -> Result<()> {
+        let task = self
+            .tasks
+            .iter_mut()
+            .find(|task| task.get_id() == id)
+            .ok_or(Error::TaskNotFound(id))?;
+        task.restore_summary(number, by, data).await?;
+        self.events.push(Event::Updated { task: task.clone() });
+        Ok(())
+    }
+
     pub fn rename_task(&mut self, id: u64, name: String) -> Result<()> {
         let task = self
             .tasks
             .iter_mut()
             .find(|task| task.get_id() == id)
-            .ok_or("Task not found")?;
+            .ok_or(Error::TaskNotFound(id))?;
         task.rename(name);
+        self.events.push(Event::Renamed { task: task.clone() });
+        Ok(())
+    }
+
+    /// Assigns the task to someone, who has to have an account once there are
+    /// any.
+    pub fn assign_task(&mut self, id: u64, assignee: Option<String>) -> Result<()> {
+        self.check_assignee(assignee.as_deref())?;
+        let task = self
+            .tasks
+            .iter_mut()
+            .find(|task| task.get_id() == id)
+            .ok_or(Error::TaskNotFound(id))?;
+        task.assignee = assignee;
+        task.touch();
+        self.events.push(Event::Updated { task: task.clone() });
+        Ok(())
+    }
+
+    pub fn check_assignee(&self, assignee: Option<&str>) -> Result<()> {
+        match assignee {
+            Some(assignee) if !self.users.is_empty() && self.get_user(assignee).is_err() => {
+                Err(Error::BadRequest(format!("Unknown user {}", assignee)))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Attaches the image, returning the stored path of the image it replaces.
+    pub fn attach_image(&mut self, id: u64, name: String, path: String) -> Result<Option<String>> {
+        let task = self
+            .tasks
+            .iter_mut()
+            .find(|task| task.get_id() == id)
+            .ok_or(Error::TaskNotFound(id))?;
+        let replaced = task.attach_image(name, path);
+        self.events.push(Event::Updated { task: task.clone() });
+        Ok(replaced)
+    }
+
+    /// Removes the task, returning every removed task so the caller can clean
+    /// up their summaries and images.
+    pub fn delete_task(&mut self, id: u64, mode: DeleteMode) -> Result<Vec<Task>> {
+        let parent_id = self
+            .tasks
+            .iter()
+            .find(|task| task.get_id() == id)
+            .ok_or(Error::TaskNotFound(id))?
+            .get_parent_id();
+        let removed_ids = match mode {
+            DeleteMode::Cascade => self.subtree_ids(id),
+            DeleteMode::Reparent => {
+                for task in self.tasks.iter_mut() {
+                    if task.get_parent_id() == Some(id) {
+                        task.parent_id = parent_id;
+                        task.touch();
+                        self.events.push(Event::Updated { task: task.clone() });
+                    }
+                }
+                vec![id]
+            }
+        };
+        let (removed, kept) = std::mem::take(&mut self.tasks)
+            .into_iter()
+            .partition(|task| removed_ids.contains(&task.get_id()));
+        self.tasks = kept;
+        self.shares
+            .retain(|share| !removed_ids.contains(&share.task_id));
+        for task in removed.iter() {
+            self.events.push(Event::Deleted { id: task.get_id() });
+        }
+        Ok(removed)
+    }
+
+    /// Archives or restores the task together with all of its descendants.
+    pub fn archive_task(&mut self, id: u64, archived: bool) -> Result<()> {
+        if !self.tasks.iter().any(|task| task.get_id() == id) {
+            return Err(Error::TaskNotFound(id));
+        }
+        let ids = self.subtree_ids(id);
+        for task in self.tasks.iter_mut() {
+            if ids.contains(&task.get_id()) {
+                task.archived = archived;
+                task.touch();
+                self.events.push(Event::Updated { task: task.clone() });
+            }
+        }
         Ok(())
     }
 
+    /// The task and all of its descendants.
+    pub fn subtree_ids(&self, id: u64) -> Vec<u64> {
+        let mut ids = vec![id];
+        let mut i = 0;
+        while i < ids.len() {
+            for task in self.tasks.iter() {
+                if task.get_parent_id() == Some(ids[i]) {
+                    ids.push(task.get_id());
+                }
+            }
+            i += 1;
+        }
+        ids
+    }
+
+    pub fn add_user(&mut self, user: User) -> Result<()> {
+        if self.users.iter().any(|u| u.name == user.name) {
+            return Err(Error::InvalidState(format!(
+                "User {} already exists",
+                user.name
+            )));
+        }
+        self.users.push(user);
+        self.changed = true;
+        Ok(())
+    }
+
+    pub fn remove_user(&mut self, name: &str) -> Result<()> {
+        self.get_user(name)?;
+        self.users.retain(|user| user.name != name);
+        self.changed = true;
+        Ok(())
+    }
+
+    pub fn set_role(&mut self, name: &str, role: Role) -> Result<()> {
+        self.get_user_mut(name)?.role = role;
+        self.changed = true;
+        Ok(())
+    }
+
+    /// Fails unless `user` has at least the role. Without accounts everyone
+    /// may do everything.
+    pub fn check_role(&self, user: Option<&str>, role: Role) -> Result<()> {
+        if self.users.is_empty() {
+            return Ok(());
+        }
+        let user = user.ok_or_else(|| Error::Unauthorized("Log in to do this".to_string()))?;
+        let has = self
+            .get_user(user)
+            .map_err(|_| Error::Unauthorized(format!("No user {}", user)))?
+            .role;
+        if has < role {
+            return Err(Error::Forbidden(format!(
+                "{} is a {}, this needs a {}",
+                user, has, role
+            )));
+        }
+        Ok(())
+    }
+
+    /// Replaces the password hash of the user.
+    pub fn set_password(&mut self, name: &str, password: String) -> Result<()> {
+        self.get_user_mut(name)?.password = password;
+        self.changed = true;
+        Ok(())
+    }
+
+    /// Adds an API token given the hash of its secret.
+    pub fn add_token(&mut self, name: &str, token: String, hash: String) -> Result<TokenInfo> {
+        user::check_name(&token)?;
+        let user = self.get_user_mut(name)?;
+        if user.tokens.iter().any(|t| t.name == token) {
+            return Err(Error::InvalidState(format!(
+                "{} already has a token named {}",
+                name, token
+            )));
+        }
+        let token = ApiToken {
+            name: token,
+            hash,
+            created: Time::now(),
+        };
+        let info = token.info();
+        user.tokens.push(token);
+        self.changed = true;
+        Ok(info)
+    }
+
+    pub fn revoke_token(&mut self, name: &str, token: &str) -> Result<()> {
+        let user = self.get_user_mut(name)?;
+        let count = user.tokens.len();
+        user.tokens.retain(|t| t.name != token);
+        if user.tokens.len() == count {
+            return Err(Error::NotFound(format!("{} has no token {}", name, token)));
+        }
+        self.changed = true;
+        Ok(())
+    }
+
+    pub fn get_user(&self, name: &str) -> Result<&User> {
+        self.users
+            .iter()
+            .find(|user| user.name == name)
+            .ok_or_else(|| Error::NotFound(format!("No user {}", name)))
+    }
+
+    fn get_user_mut(&mut self, name: &str) -> Result<&mut User> {
+        self.users
+            .iter_mut()
+            .find(|user| user.name == name)
+            .ok_or_else(|| Error::NotFound(format!("No user {}", name)))
+    }
+
+    /// The user owning the token with this hash.
+    pub fn find_token_user(&self, hash: &str) -> Option<&User> {
+        self.users
+            .iter()
+            .find(|user| user.tokens.iter().any(|token| token.hash == hash))
+    }
+
+    pub fn get_users(&self) -> &[User] {
+        &self.users
+    }
+
+    /// Adds a share link to the subtree of the task given the hash of its
+    /// secret.
+    pub fn add_share(&mut self, task_id: u64, hash: String, by: Option<&str>) -> Result<&Share> {
+        self.get_task(task_id)?;
+        self.shares.push(Share {
+            hash,
+            task_id,
+            created_by: by.map(str::to_string),
+            created: Time::now(),
+        });
+        self.changed = true;
+        Ok(self.shares.last().unwrap())
+    }
+
+    pub fn revoke_share(&mut self, id: &str) -> Result<Share> {
+        let index = self
+            .shares
+            .iter()
+            .position(|share| share.id() == id)
+            .ok_or_else(|| Error::NotFound(format!("No share link {}", id)))?;
+        self.changed = true;
+        Ok(self.shares.remove(index))
+    }
+
+    pub fn get_share(&self, id: &str) -> Result<&Share> {
+        self.shares
+            .iter()
+            .find(|share| share.id() == id)
+            .ok_or_else(|| Error::NotFound(format!("No share link {}", id)))
+    }
+
+    /// The share link with this secret hash.
+    pub fn find_share(&self, hash: &str) -> Option<&Share> {
+        self.shares.iter().find(|share| share.hash == hash)
+    }
+
+    pub fn get_shares(&self) -> &[Share] {
+        &self.shares
+    }
+
+    /// Drains the events recorded by the mutations so far.
+    pub fn take_events(&mut self) -> Vec<Event> {
+        std::mem::take(&mut self.events)
+    }
+
+    /// Whether anything was mutated since the last call. Recorded events count
+    /// as changes, so call this before `take_events`.
+    pub fn take_changed(&mut self) -> bool {
+        std::mem::take(&mut self.changed) || !self.events.is_empty()
+    }
+
+    pub fn get_task(&self, id: u64) -> Result<&Task> {
+        self.tasks
+            .iter()
+            .find(|task| task.get_id() == id)
+            .ok_or(Error::TaskNotFound(id))
+    }
+
     pub fn get_tasks(&self) -> &Vec<Task> {
         &self.tasks
     }
+
+    pub fn get_running_id(&self) -> u64 {
+        self.running_id
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tree() -> App {
+        let mut app = App::new();
+        let root = app.add_task("root".to_string(), None).unwrap();
+        let child = app.add_subtask(root, "child".to_string(), None).unwrap();
+        app.add_subtask(child, "grandchild".to_string(), None)
+            .unwrap();
+        app.add_task("other".to_string(), None).unwrap();
+        app
+    }
+
+    fn ids(app: &App) -> Vec<u64> {
+        app.get_tasks().iter().map(|t| t.get_id()).collect()
+    }
+
+    #[test]
+    fn test_delete_cascade() {
+        let mut app = tree();
+        let removed = app.delete_task(1, DeleteMode::Cascade).unwrap();
+        assert_eq!(removed.len(), 2);
+        assert_eq!(ids(&app), vec![0, 3]);
+    }
+
+    #[test]
+    fn test_delete_reparent() {
+        let mut app = tree();
+        app.delete_task(1, DeleteMode::Reparent).unwrap();
+        assert_eq!(ids(&app), vec![0, 2, 3]);
+        assert_eq!(app.get_tasks()[1].get_parent_id(), Some(0));
+    }
+
+    #[test]
+    fn test_sessions() {
+        let mut app = tree();
+        app.start_task(2, None).unwrap();
+        assert_eq!(app.get_tasks()[0].get_status(), &TaskStaus::InProgress);
+        assert!(app.resume_task(2, None).is_err());
+        app.pause_task(2, None).unwrap();
+        app.resume_task(2, None).unwrap();
+        let task = &app.get_tasks()[2];
+        assert_eq!(task.get_sessions().len(), 2);
+        assert!(task.get_sessions()[0].end.is_some());
+        assert!(task.get_sessions()[1].end.is_none());
+    }
+
+    #[test]
+    fn test_shared_sessions() {
+        let mut app = tree();
+        app.start_task(3, Some("ada")).unwrap();
+        app.start_task(3, Some("bob")).unwrap();
+        app.take_events();
+        let revision = app.get_tasks()[3].get_revision();
+        app.start_task(3, Some("bob")).unwrap();
+        assert!(app.take_events().is_empty());
+        assert_eq!(app.get_tasks()[3].get_revision(), revision);
+        assert_eq!(app.get_tasks()[3].get_sessions().len(), 2);
+        assert!(app.pause_task(3, Some("eve")).is_err());
+        app.pause_task(3, Some("ada")).unwrap();
+        let task = &app.get_tasks()[3];
+        assert_eq!(task.get_status(), &TaskStaus::InProgress);
+        assert!(task.running_session(Some("ada")).is_none());
+        assert!(task.running_session(Some("bob")).is_some());
+        app.pause_task(3, Some("bob")).unwrap();
+        assert_eq!(app.get_tasks()[3].get_status(), &TaskStaus::Paused);
+        app.resume_task(3, Some("bob")).unwrap();
+        let task = &app.get_tasks()[3];
+        assert_eq!(
+            task.running_session(Some("bob")).unwrap().user.as_deref(),
+            Some("bob")
+        );
+        assert!(task.tracked_time_of("ada") <= task.tracked_time());
+
+        // A session started without an account is not anyone's to pause
+        app.start_task(2, None).unwrap();
+        assert!(app.pause_task(2, Some("ada")).is_err());
+        assert!(app.get_tasks()[2].running_session(None).is_some());
+        app.pause_task(2, None).unwrap();
+        assert_eq!(app.get_tasks()[2].get_status(), &TaskStaus::Paused);
+    }
+
+    #[test]
+    fn test_assign() {
+        let mut app = tree();
+        app.assign_task(0, Some("anyone".to_string())).unwrap();
+        app.add_user(User::new("ada".to_string(), "secret", Role::Member).unwrap())
+            .unwrap();
+        assert!(app.assign_task(0, Some("bob".to_string())).is_err());
+        app.assign_task(0, Some("ada".to_string())).unwrap();
+        assert!(app.get_tasks()[0].is_for("ada"));
+        let id = app.add_task("mine".to_string(), Some("bob")).unwrap();
+        assert!(app.get_task(id).unwrap().is_for("bob"));
+        app.assign_task(id, Some("ada".to_string())).unwrap();
+        assert!(!app.get_task(id).unwrap().is_for("bob"));
+    }
+
+    #[test]
+    fn test_reopen() {
+        let mut app = tree();
+        assert!(app.reopen_task(3, None, false, None).is_err());
+        app.start_task(3, None).unwrap();
+        app.pause_task(3, None).unwrap();
+        app.tasks[3].status = TaskStaus::Complete;
+        app.reopen_task(3, Some("Found a bug".to_string()), true, None)
+            .unwrap();
+        let task = &app.get_tasks()[3];
+        assert_eq!(task.get_status(), &TaskStaus::InProgress);
+        assert_eq!(task.get_sessions().len(), 2);
+        assert_eq!(task.reopened[0].reason.as_deref(), Some("Found a bug"));
+    }
+
+    #[test]
+    fn test_events() {
+        let mut app = tree();
+        app.take_events();
+        app.start_task(2, None).unwrap();
+        let started: Vec<u64> = app
+            .take_events()
+            .iter()
+            .map(|event| match event {
+                Event::Started { task } => task.get_id(),
+                _ => panic!("unexpected {}", event.name()),
+            })
+            .collect();
+        assert_eq!(started, vec![2, 1, 0]);
+        app.delete_task(1, DeleteMode::Cascade).unwrap();
+        let names: Vec<&str> = app.take_events().iter().map(Event::name).collect();
+        assert_eq!(names, vec!["task_deleted", "task_deleted"]);
+        assert!(app.take_events().is_empty());
+    }
+
+    #[test]
+    fn test_archive_subtree() {
+        let mut app = tree();
+        app.archive_task(1, true).unwrap();
+        let archived: Vec<bool> = app.get_tasks().iter().map(|t| t.is_archived()).collect();
+        assert_eq!(archived, vec![false, true, true, false]);
+        assert!(app.archive_task(42, true).is_err());
+    }
+
+    #[test]
+    fn test_users() {
+        let mut app = tree();
+        app.add_user(User::new("ada".to_string(), "secret", Role::Member).unwrap())
+            .unwrap();
+        assert!(app
+            .add_user(User::new("ada".to_string(), "other", Role::Member).unwrap())
+            .is_err());
+        app.add_token("ada", "ci".to_string(), user::hash_secret("token"))
+            .unwrap();
+        assert!(app
+            .add_token("ada", "ci".to_string(), user::hash_secret("again"))
+            .is_err());
+        assert!(app
+            .add_token("bob", "ci".to_string(), String::new())
+            .is_err());
+        let owner = app.find_token_user(&user::hash_secret("token")).unwrap();
+        assert_eq!(owner.get_name(), "ada");
+        app.revoke_token("ada", "ci").unwrap();
+        assert!(app.find_token_user(&user::hash_secret("token")).is_none());
+        assert!(app.revoke_token("ada", "ci").is_err());
+        app.remove_user("ada").unwrap();
+        assert!(app.get_users().is_empty());
+    }
+
+    #[test]
+    fn test_roles() {
+        let mut app = tree();
+        assert!(app.check_role(None, Role::Manager).is_ok());
+        app.add_user(User::new("ada".to_string(), "secret", Role::Viewer).unwrap())
+            .unwrap();
+        assert!(app.check_role(None, Role::Viewer).is_err());
+        assert!(app.check_role(Some("bob"), Role::Viewer).is_err());
+        app.check_role(Some("ada"), Role::Viewer).unwrap();
+        assert!(matches!(
+            app.check_role(Some("ada"), Role::Member),
+            Err(Error::Forbidden(_))
+        ));
+        app.set_role("ada", Role::Manager).unwrap();
+        app.check_role(Some("ada"), Role::Manager).unwrap();
+    }
+
+    #[test]
+    fn test_shares() {
+        let mut app = tree();
+        assert!(app.add_share(9, "x".repeat(64), None).is_err());
+        let id = app
+            .add_share(1, user::hash_secret("link"), Some("ada"))
+            .unwrap()
+            .id()
+            .to_string();
+        let share = app.find_share(&user::hash_secret("link")).unwrap();
+        assert_eq!(share.task_id, 1);
+        app.revoke_share(&id).unwrap();
+        assert!(app.find_share(&user::hash_secret("link")).is_none());
+        assert!(app.revoke_share(&id).is_err());
+
+        // Deleting the task takes its links along
+        app.add_share(2, user::hash_secret("link"), None).unwrap();
+        app.delete_task(1, DeleteMode::Cascade).unwrap();
+        assert!(app.get_shares().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_files_in_data_dir() {
+        let data = DataDir::temp();
+        let mut app = tree();
+        app.stop_task(3, Some("# Done".to_string()), None, &data)
+            .await
+            .unwrap();
+        let summary = app.get_task(3).unwrap().get_summary().unwrap().to_string();
+        assert_eq!(summary, "summaries/3.html");
+        assert!(data.resolve(&summary).unwrap().exists());
+        app.set_summary(3, "# Done, really".to_string(), Some("ada"), &data)
+            .await
+            .unwrap();
+        let source = std::fs::read_to_string(data.root().join("summaries/3.md")).unwrap();
+        assert_eq!(source, "# Done, really");
+        let images = app
+            .stop_task(2, Some("![plot](plot.png)".to_string()), None, &data)
+            .await
+            .unwrap();
+        assert_eq!(images.unwrap()["plot"], "plot.png");
+        assert!(data.root().join("temp/2.md").exists());
+
+        let mut removed = app.delete_task(1, DeleteMode::Cascade).unwrap();
+        removed.extend(app.delete_task(3, DeleteMode::Cascade).unwrap());
+        for task in removed {
+            task.remove_files(&data).await;
+        }
+        assert!(!data.root().join("temp/2.md").exists());
+        assert!(!data.root().join("summaries/3.md").exists());
+        std::fs::remove_dir_all(data.root()).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_stop_waits_for_images() {
+        let data = DataDir::temp();
+        let mut app = tree();
+        app.start_task(2, Some("ada")).unwrap();
+        app.take_events();
+        let revision = app.get_task(2).unwrap().get_revision();
+        let images = app
+            .stop_task(2, Some("![plot](plot.png)".to_string()), None, &data)
+            .await
+            .unwrap();
+        assert!(images.is_some());
+        let task = app.get_task(2).unwrap();
+        assert_eq!(task.get_status(), &TaskStaus::InProgress);
+        assert!(task.running_session(Some("ada")).is_some());
+        assert_eq!(task.get_revision(), revision);
+        assert!(!app.take_changed());
+
+        app.stop_task(2, None, None, &data).await.unwrap();
+        let task = app.get_task(2).unwrap();
+        assert_eq!(task.get_status(), &TaskStaus::Complete);
+        assert!(task.running_session(Some("ada")).is_none());
+        std::fs::remove_dir_all(data.root()).unwrap();
+    }
 }
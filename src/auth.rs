@@ -0,0 +1,199 @@
+//! Who is calling: login sessions for the web UI, bearer tokens for scripts
+//! and the middleware turning away everyone else.
+//!
+//! As long as no account exists the server stays open, like it always was.
+//! Once the first one is added with `tasks-todo user add`, every route but
+//! the static assets, the login page and share links needs a session cookie
+//! or an `Authorization: Bearer` token. What the user may do then depends on
+//! their role, checked by the handlers with `App::check_role`.
+
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use axum::{
+    async_trait,
+    extract::{FromRequestParts, State},
+    headers::{Cookie, HeaderMapExt},
+    http::{header, request::Parts, HeaderMap, Method, Request},
+    middleware::Next,
+    response::{Html, IntoResponse, Redirect, Response},
+};
+use serde::Deserialize;
+
+use crate::{
+    app::App,
+    error::{Error, Result},
+    extract::Form,
+    state::AppState,
+    user,
+};
+
+const SESSION_COOKIE: &str = "tasks_todo_session";
+/// Logins last a week, the server forgets them when it restarts
+const SESSION_LENGTH: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+/// The logged in web UI sessions, keyed by the hash of their cookie.
+#[derive(Default)]
+pub struct Sessions {
+    sessions: Mutex<HashMap<String, Session>>,
+}
+
+struct Session {
+    user: String,
+    expires: Instant,
+}
+
+impl Sessions {
+    /// Starts a session for the user, returning the secret for the cookie.
+    pub fn create(&self, user: &str) -> String {
+        let secret = user::new_secret();
+        let now = Instant::now();
+        let mut sessions = self.sessions.lock().unwrap();
+        sessions.retain(|_, session| session.expires > now);
+        sessions.insert(
+            user::hash_secret(&secret),
+            Session {
+                user: user.to_string(),
+                expires: now + SESSION_LENGTH,
+            },
+        );
+        secret
+    }
+
+    /// The user logged in with the secret, if the session is still valid.
+    pub fn user(&self, secret: &str) -> Option<String> {
+        let sessions = self.sessions.lock().unwrap();
+        sessions
+            .get(&user::hash_secret(secret))
+            .filter(|session| session.expires > Instant::now())
+            .map(|session| session.user.clone())
+    }
+
+    pub fn remove(&self, secret: &str) {
+        self.sessions
+            .lock()
+            .unwrap()
+            .remove(&user::hash_secret(secret));
+    }
+}
+
+/// The name of the authenticated user, put into the request by
+/// [`require_user`]. Extracting it fails with `401` when the server is open.
+#[derive(Clone, Debug)]
+pub struct CurrentUser(pub String);
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for CurrentUser {
+    type Rejection = Error;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self> {
+        parts
+            .extensions
+            .get::<CurrentUser>()
+            .cloned()
+            .ok_or_else(|| Error::Unauthorized("Log in to use this".to_string()))
+    }
+}
+
+/// Middleware letting through requests with a valid session or token. Pages
+/// redirect to the login page, everything else gets `401`.
+pub async fn require_user<B>(
+    State(state): State<AppState>,
+    mut request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let user = {
+        let app = state.read().await;
+        if app.get_users().is_empty() {
+            None
+        } else {
+            match authenticate(&state, &app, request.headers()) {
+                Some(user) => Some(user),
+                None if wants_page(&request) => return Redirect::to("/login").into_response(),
+                None => {
+                    return Error::Unauthorized(
+                        "Log in or send an API token as Authorization: Bearer".to_string(),
+                    )
+                    .into_response()
+                }
+            }
+        }
+    };
+    if let Some(user) = user {
+        request.extensions_mut().insert(CurrentUser(user));
+    }
+    next.run(request).await
+}
+
+fn authenticate(state: &AppState, app: &App, headers: &HeaderMap) -> Option<String> {
+    if let Some(authorization) = headers.get(header::AUTHORIZATION) {
+        let token = authorization.to_str().ok()?.strip_prefix("Bearer ")?;
+        let user = app.find_token_user(&user::hash_secret(token.trim()))?;
+        return Some(user.get_name().to_string());
+    }
+    let cookie = headers.typed_get::<Cookie>()?;
+    let user = state.sessions().user(cookie.get(SESSION_COOKIE)?)?;
+    // The account may have been removed since
+    app.get_user(&user)
+        .ok()
+        .map(|user| user.get_name().to_string())
+}
+
+fn wants_page<B>(request: &Request<B>) -> bool {
+    request.method() == Method::GET
+        && request
+            .headers()
+            .get(header::ACCEPT)
+            .and_then(|accept| accept.to_str().ok())
+            .is_some_and(|accept| accept.contains("text/html"))
+}
+
+pub async fn login_page() -> Html<&'static str> {
+    Html(include_str!("../static/login.html"))
+}
+
+#[derive(Deserialize)]
+pub struct Login {
+    name: String,
+    password: String,
+}
+
+/// Checks the password from the login form and sets the session cookie.
+pub async fn login(State(state): State<AppState>, Form(login): Form<Login>) -> Result<Response> {
+    let user = state.read().await.get_user(&login.name).ok().cloned();
+    let password = login.password;
+    let valid = tokio::task::spawn_blocking(move || match user {
+        Some(user) => user.check_password(&password),
+        None => user::check_unknown_password(&password),
+    })
+    .await?;
+    if !valid {
+        tracing::warn!("Failed login as {}", login.name);
+        return Ok(Redirect::to("/login?failed").into_response());
+    }
+    let secret = state.sessions().create(&login.name);
+    let cookie = format!(
+        "{}={}; Path=/; HttpOnly; SameSite=Strict; Max-Age={}",
+        SESSION_COOKIE,
+        secret,
+        SESSION_LENGTH.as_secs()
+    );
+    Ok(([(header::SET_COOKIE, cookie)], Redirect::to("/")).into_response())
+}
+
+pub async fn logout(State(state): State<AppState>, headers: HeaderMap) -> impl IntoResponse {
+    if let Some(secret) = headers
+        .typed_get::<Cookie>()
+        .and_then(|cookie| cookie.get(SESSION_COOKIE).map(str::to_string))
+    {
+        state.sessions().remove(&secret);
+    }
+    let cookie = format!(
+        "{}=; Path=/; HttpOnly; SameSite=Strict; Max-Age=0",
+        SESSION_COOKIE
+    );
+    ([(header::SET_COOKIE, cookie)], Redirect::to("/login"))
+}
@@ -0,0 +1,1072 @@
+//! The command line client: `tasks-todo add`, `start`, `stop` and friends.
+//!
+//! Commands work on the local data store directly, or talk to a running server
+//! through `/api/v1` when `--server` (or `TASKS_TODO_SERVER`) names one. Both
+//! go through [`Client`] so they behave the same.
+
+use std::{
+    collections::HashSet,
+    io::BufRead,
+    path::{Path, PathBuf},
+};
+
+use axum::{
+    body::Body,
+    http::{header, Method, Request, StatusCode},
+};
+use clap::Subcommand;
+use hyper::client::HttpConnector;
+use serde::de::DeserializeOwned;
+use serde_json::{json, Value};
+
+use crate::{
+    api::{self, Upload},
+    app::App,
+    config::Config,
+    data_dir::DataDir,
+    error::{Error, Result},
+    extract::IfMatch,
+    state::AppState,
+    storage,
+    task::{Change, DiffLine, Task, TaskStaus},
+    tui,
+    user::{self, Role, User},
+    zone::Zone,
+};
+
+/// Commands that log work from a terminal. Without `--server` they change the
+/// local data store, which should not be used by a running server at the
+/// same time.
+#[derive(Subcommand, Debug, PartialEq)]
+pub enum Command {
+    /// Add a task
+    Add {
+        #[arg(required = true)]
+        name: Vec<String>,
+        /// Add it as a subtask of this task
+        #[arg(long)]
+        parent: Option<u64>,
+    },
+    /// Start or resume a task
+    Start { id: u64 },
+    /// Pause a task
+    Pause { id: u64 },
+    /// Complete a task
+    Stop {
+        id: u64,
+        /// Markdown summary, images it links are uploaded relative to it
+        #[arg(long, value_name = "FILE", conflicts_with = "edit")]
+        summary: Option<PathBuf>,
+        /// Write the summary in $EDITOR
+        #[arg(long)]
+        edit: bool,
+    },
+    /// Rename a task
+    Rename {
+        id: u64,
+        #[arg(required = true)]
+        name: Vec<String>,
+    },
+    /// List tasks
+    List {
+        /// Include archived tasks
+        #[arg(long)]
+        archived: bool,
+    },
+    /// Show tasks with their subtasks
+    Tree {
+        /// Include archived tasks
+        #[arg(long)]
+        archived: bool,
+    },
+    /// Show the sessions and summary of a task
+    Show { id: u64 },
+    /// Print the markdown summary of a task, or replace it
+    Summary {
+        id: u64,
+        /// Replace it with this markdown file
+        #[arg(long, value_name = "FILE", conflicts_with_all = ["edit", "history", "diff", "restore"])]
+        file: Option<PathBuf>,
+        /// Change it in $EDITOR
+        #[arg(long, conflicts_with_all = ["history", "diff", "restore"])]
+        edit: bool,
+        /// List the revisions of the summary
+        #[arg(long, conflicts_with_all = ["diff", "restore"])]
+        history: bool,
+        /// Show what changed since this revision
+        #[arg(long, value_name = "REVISION", conflicts_with = "restore")]
+        diff: Option<u64>,
+        /// Compare with this revision instead of the current one
+        #[arg(long, value_name = "REVISION", requires = "diff")]
+        to: Option<u64>,
+        /// Make an older revision the current summary again
+        #[arg(long, value_name = "REVISION")]
+        restore: Option<u64>,
+    },
+    /// Browse and drive the task tree in the terminal
+    Tui,
+    /// Manage the accounts that may use the server
+    User {
+        #[command(subcommand)]
+        command: UserCommand,
+    },
+}
+
+/// Accounts live in the local data store, so these always change it and never
+/// a server. Changes are picked up when the server starts.
+#[derive(Subcommand, Debug, PartialEq)]
+pub enum UserCommand {
+    /// Add an account, asking for its password
+    Add {
+        name: String,
+        /// Read the password from the first line of stdin instead
+        #[arg(long)]
+        password_stdin: bool,
+        /// viewer, member or manager
+        #[arg(long, default_value = "member")]
+        role: Role,
+    },
+    /// Change the password of an account
+    Passwd {
+        name: String,
+        /// Read the password from the first line of stdin instead
+        #[arg(long)]
+        password_stdin: bool,
+    },
+    /// Change what an account may do: viewer, member or manager
+    Role { name: String, role: Role },
+    /// Remove an account
+    Remove { name: String },
+    /// List the accounts and their API tokens
+    List,
+    /// Create an API token for scripts, it is only shown once
+    Token { name: String, token: String },
+    /// Revoke an API token
+    Revoke { name: String, token: String },
+}
+
+/// Runs the command and prints its output.
+pub async fn run(
+    command: Command,
+    server: Option<String>,
+    token: Option<String>,
+    config: &Config,
+) -> Result<()> {
+    let zone = config.zone;
+    if let Command::User { command } = command {
+        if server.is_some() {
+            return Err(Error::Config(
+                "Accounts are kept in the local data store, run this without --server".to_string(),
+            ));
+        }
+        let state = open_local(config)?;
+        let result = accounts(&state, command).await;
+        state.flush().await?;
+        print!("{}", result?);
+        return Ok(());
+    }
+    let client = match server {
+        Some(server) => Client::Remote(Remote::new(&server, token)?),
+        None => Client::Local(open_local(config)?),
+    };
+    let result = match command {
+        Command::Tui => tui::run(&client, zone).await.map(|_| String::new()),
+        command => execute(&client, command, zone).await,
+    };
+    client.save().await?;
+    print!("{}", result?);
+    Ok(())
+}
+
+async fn execute(client: &Client, command: Command, zone: Zone) -> Result<String> {
+    Ok(match command {
+        Command::Add { name, parent } => {
+            let task = client.add(name.join(" "), parent).await?;
+            format!("Added task {} {}\n", task.get_id(), task.get_name())
+        }
+        Command::Start { id } => {
+            client.start(id).await?;
+            format!("Started task {}\n", id)
+        }
+        Command::Pause { id } => {
+            client.pause(id).await?;
+            format!("Paused task {}\n", id)
+        }
+        Command::Stop { id, summary, edit } => {
+            let (summary, base) = match summary {
+                None if edit => (edit_summary(id, "")?, PathBuf::from(".")),
+                None => (None, PathBuf::from(".")),
+                Some(path) => {
+                    let summary = std::fs::read_to_string(&path).map_err(|e| {
+                        Error::Config(format!("Can not read {}: {}", path.display(), e))
+                    })?;
+                    let base = path.parent().unwrap_or(Path::new(".")).to_path_buf();
+                    (Some(summary), base)
+                }
+            };
+            complete(client, id, summary, &base).await?;
+            format!("Completed task {}\n", id)
+        }
+        Command::Rename { id, name } => {
+            let name = name.join(" ");
+            client.rename(id, name.clone()).await?;
+            format!("Renamed task {} to {}\n", id, name)
+        }
+        Command::List { archived } => client
+            .tasks(archived)
+            .await?
+            .iter()
+            .map(|task| line(task, 0))
+            .collect(),
+        Command::Tree { archived } => render_tree(&client.tasks(archived).await?),
+        Command::Show { id } => render_task(&client.task(id).await?, zone),
+        Command::Summary {
+            id, history: true, ..
+        } => render_revisions(&client.task(id).await?, zone),
+        Command::Summary {
+            id,
+            diff: Some(from),
+            to,
+            ..
+        } => client
+            .summary_diff(id, from, to)
+            .await?
+            .iter()
+            .map(|line| match line.change {
+                Change::Same => format!("  {}\n", line.line),
+                Change::Added => format!("+ {}\n", line.line),
+                Change::Removed => format!("- {}\n", line.line),
+            })
+            .collect(),
+        Command::Summary {
+            id,
+            restore: Some(number),
+            ..
+        } => {
+            client.restore_summary(id, number).await?;
+            format!(
+                "Restored revision {} of the summary of task {}\n",
+                number, id
+            )
+        }
+        Command::Summary { id, file, edit, .. } => {
+            let summary = match file {
+                Some(path) => std::fs::read_to_string(&path).map_err(|e| {
+                    Error::Config(format!("Can not read {}: {}", path.display(), e))
+                })?,
+                None if edit => {
+                    // Tasks without a summary get their first one
+                    let current = match client.summary_source(id).await {
+                        Err(Error::NotFound(_)) => String::new(),
+                        current => current?,
+                    };
+                    match edit_summary(id, &current)? {
+                        Some(summary) if summary != current => summary,
+                        _ => return Ok(format!("Left the summary of task {} as it was\n", id)),
+                    }
+                }
+                None => {
+                    let mut summary = client.summary_source(id).await?;
+                    if !summary.ends_with('\n') {
+                        summary.push('\n');
+                    }
+                    return Ok(summary);
+                }
+            };
+            client.set_summary(id, summary).await?;
+            format!("Updated the summary of task {}\n", id)
+        }
+        Command::Tui | Command::User { .. } => unreachable!("run on their own"),
+    })
+}
+
+async fn accounts(state: &AppState, command: UserCommand) -> Result<String> {
+    Ok(match command {
+        UserCommand::Add {
+            name,
+            password_stdin,
+            role,
+        } => {
+            user::check_name(&name)?;
+            state
+                .read()
+                .await
+                .get_user(&name)
+                .err()
+                .ok_or_else(|| Error::InvalidState(format!("User {} already exists", name)))?;
+            let password = read_password(password_stdin)?;
+            let user =
+                tokio::task::spawn_blocking(move || User::new(name, &password, role)).await??;
+            let output = format!("Added user {} as a {}\n", user.get_name(), role);
+            state.write().await.add_user(user)?;
+            output
+        }
+        UserCommand::Passwd {
+            name,
+            password_stdin,
+        } => {
+            state.read().await.get_user(&name)?;
+            let password = read_password(password_stdin)?;
+            let hash =
+                tokio::task::spawn_blocking(move || user::hash_password(&password)).await??;
+            state.write().await.set_password(&name, hash)?;
+            format!("Changed the password of {}\n", name)
+        }
+        UserCommand::Role { name, role } => {
+            state.write().await.set_role(&name, role)?;
+            format!("{} is a {} now\n", name, role)
+        }
+        UserCommand::Remove { name } => {
+            state.write().await.remove_user(&name)?;
+            format!("Removed user {}\n", name)
+        }
+        UserCommand::List => state
+            .read()
+            .await
+            .get_users()
+            .iter()
+            .map(|user| {
+                let tokens: Vec<&str> = user.get_tokens().iter().map(|t| t.name.as_str()).collect();
+                match tokens.is_empty() {
+                    true => format!("{}  {}\n", user.get_name(), user.get_role()),
+                    false => format!(
+                        "{}  {}  tokens: {}\n",
+                        user.get_name(),
+                        user.get_role(),
+                        tokens.join(", ")
+                    ),
+                }
+            })
+            .collect(),
+        UserCommand::Token { name, token } => {
+            let secret = user::new_secret();
+            state
+                .write()
+                .await
+                .add_token(&name, token.clone(), user::hash_secret(&secret))?;
+            format!(
+                "Created the token {} for {}, it is not shown again:\n{}\n",
+                token, name, secret
+            )
+        }
+        UserCommand::Revoke { name, token } => {
+            state.write().await.revoke_token(&name, &token)?;
+            format!("Revoked the token {} of {}\n", token, name)
+        }
+    })
+}
+
+/// Asks for a new password twice, or reads it from stdin for scripts.
+fn read_password(from_stdin: bool) -> Result<String> {
+    if from_stdin {
+        let mut password = String::new();
+        std::io::stdin().lock().read_line(&mut password)?;
+        return Ok(password.trim_end_matches(['\r', '\n']).to_string());
+    }
+    let password = rpassword::prompt_password("Password: ")?;
+    if rpassword::prompt_password("Repeat the password: ")? != password {
+        return Err(Error::BadRequest("The passwords do not match".to_string()));
+    }
+    Ok(password)
+}
+
+/// Completes the task, uploading the local images the summary links to
+/// relative to `base`.
+pub async fn complete(
+    client: &Client,
+    id: u64,
+    summary: Option<String>,
+    base: &Path,
+) -> Result<()> {
+    let pending = client.stop(id, summary.clone()).await?;
+    if !pending.is_empty() {
+        let uploads = read_images(summary.as_deref().unwrap_or_default(), base, &pending)?;
+        client.attach(id, uploads).await?;
+    }
+    Ok(())
+}
+
+/// Opens `$VISUAL` or `$EDITOR` on a file with `initial` in it and returns
+/// what was written, `None` if the file was left empty.
+pub fn edit_summary(id: u64, initial: &str) -> Result<Option<String>> {
+    let editor = std::env::var("VISUAL")
+        .or_else(|_| std::env::var("EDITOR"))
+        .unwrap_or_else(|_| "vi".to_string());
+    // A random name other users can not guess and plant a file at
+    let file = tempfile::Builder::new()
+        .prefix(&format!("tasks-todo-{}-", id))
+        .suffix(".md")
+        .tempfile()?;
+    std::fs::write(file.path(), initial)?;
+    // The editor may come with arguments, like "code --wait"
+    let mut words = editor.split_whitespace();
+    let program = words
+        .next()
+        .ok_or_else(|| Error::Config("EDITOR is empty".to_string()))?;
+    let status = std::process::Command::new(program)
+        .args(words)
+        .arg(file.path())
+        .status()?;
+    // Editors may replace the file instead of writing to it, read it by path
+    let summary = std::fs::read_to_string(file.path())?;
+    file.close()?;
+    if !status.success() {
+        return Err(Error::Config(format!("{} exited with {}", editor, status)));
+    }
+    Ok(Some(summary).filter(|summary| !summary.trim().is_empty()))
+}
+
+/// Reads the pending images linked from the summary, relative to `base`.
+fn read_images(summary: &str, base: &Path, pending: &[String]) -> Result<Vec<Upload>> {
+    use base64::{engine::general_purpose, Engine as _};
+
+    let links = Task::local_images(summary);
+    pending
+        .iter()
+        .map(|name| {
+            let link = links.get(name).ok_or_else(|| {
+                Error::NotFound(format!("Image {} is not linked from the summary", name))
+            })?;
+            let path = base.join(link);
+            let bytes = std::fs::read(&path).map_err(|e| {
+                Error::NotFound(format!("Can not read image {}: {}", path.display(), e))
+            })?;
+            let extension = path
+                .extension()
+                .and_then(|extension| extension.to_str())
+                .unwrap_or("png")
+                .to_string();
+            Ok(Upload {
+                name: name.clone(),
+                data: format!(
+                    "data:image/{};base64,{}",
+                    extension,
+                    general_purpose::STANDARD.encode(bytes)
+                ),
+                extension,
+            })
+        })
+        .collect()
+}
+
+fn line(task: &Task, depth: usize) -> String {
+    format!(
+        "{:>4}  {:<11}  {:>8}  {}{}\n",
+        task.get_id(),
+        task.get_status().to_string(),
+        task.tracked_time().to_string(),
+        "  ".repeat(depth),
+        task.get_name()
+    )
+}
+
+/// Every task under its parent with its depth, in display order. Tasks whose
+/// parent is not in the list are shown at the top level, the children of
+/// `collapsed` tasks are left out.
+pub fn walk_tree<'a>(tasks: &'a [Task], collapsed: &HashSet<u64>) -> Vec<(usize, &'a Task)> {
+    fn subtree<'a>(
+        tasks: &'a [Task],
+        collapsed: &HashSet<u64>,
+        task: &'a Task,
+        depth: usize,
+        out: &mut Vec<(usize, &'a Task)>,
+    ) {
+        out.push((depth, task));
+        if collapsed.contains(&task.get_id()) {
+            return;
+        }
+        for child in tasks
+            .iter()
+            .filter(|child| child.get_parent_id() == Some(task.get_id()))
+        {
+            subtree(tasks, collapsed, child, depth + 1, out);
+        }
+    }
+
+    let ids: HashSet<u64> = tasks.iter().map(Task::get_id).collect();
+    let mut out = Vec::new();
+    for root in tasks.iter().filter(|task| {
+        task.get_parent_id()
+            .is_none_or(|parent| !ids.contains(&parent))
+    }) {
+        subtree(tasks, collapsed, root, 0, &mut out);
+    }
+    out
+}
+
+fn render_tree(tasks: &[Task]) -> String {
+    walk_tree(tasks, &HashSet::new())
+        .into_iter()
+        .map(|(depth, task)| line(task, depth))
+        .collect()
+}
+
+fn render_task(task: &Task, zone: Zone) -> String {
+    let mut out = format!("Task {}: {}\n", task.get_id(), task.get_name());
+    out.push_str(&format!("Status:   {}\n", task.get_status()));
+    if let Some(parent) = task.get_parent_id() {
+        out.push_str(&format!("Parent:   {}\n", parent));
+    }
+    if task.is_archived() {
+        out.push_str("Archived: yes\n");
+    }
+    if let Some(creator) = task.get_creator() {
+        out.push_str(&format!("Creator:  {}\n", creator));
+    }
+    if let Some(assignee) = task.get_assignee() {
+        out.push_str(&format!("Assignee: {}\n", assignee));
+    }
+    out.push_str(&format!("Tracked:  {}\n", task.tracked_time()));
+    let mut users: Vec<&str> = task
+        .get_sessions()
+        .iter()
+        .filter_map(|session| session.user.as_deref())
+        .collect();
+    users.sort_unstable();
+    users.dedup();
+    for user in users {
+        out.push_str(&format!("  {}: {}\n", user, task.tracked_time_of(user)));
+    }
+    if !task.get_sessions().is_empty() {
+        out.push_str("Sessions:\n");
+        for session in task.get_sessions() {
+            let start = zone.to_local(session.start);
+            let user = session
+                .user
+                .as_ref()
+                .map(|user| format!(" by {}", user))
+                .unwrap_or_default();
+            match session.end {
+                Some(end) => out.push_str(&format!(
+                    "  {} - {} ({}){}\n",
+                    start,
+                    zone.to_local(end),
+                    end - session.start,
+                    user
+                )),
+                None => out.push_str(&format!("  {} - running{}\n", start, user)),
+            }
+        }
+    }
+    for reopening in task.reopened.iter() {
+        out.push_str(&format!(
+            "Reopened: {} {}\n",
+            zone.to_local(reopening.time),
+            reopening.reason.as_deref().unwrap_or_default()
+        ));
+    }
+    if let Some(summary) = task.get_summary() {
+        out.push_str(&format!("Summary:  {}\n", summary));
+    }
+    for image in task.get_images() {
+        out.push_str(&format!("Image:    {} {}\n", image.name, image.path));
+    }
+    out
+}
+
+/// The revisions of the summary, oldest first.
+fn render_revisions(task: &Task, zone: Zone) -> String {
+    let mut out = String::new();
+    for revision in task.get_summary_revisions() {
+        out.push_str(&format!(
+            "{:>4}  {}",
+            revision.number,
+            zone.to_local(revision.time)
+        ));
+        if let Some(author) = &revision.author {
+            out.push_str(&format!(" by {}", author));
+        }
+        if let Some(restored) = revision.restored {
+            out.push_str(&format!(", restores {}", restored));
+        }
+        out.push('\n');
+    }
+    out
+}
+
+/// The tasks, either loaded from the local store or behind a server.
+pub enum Client {
+    Local(AppState),
+    Remote(Remote),
+}
+
+pub struct Remote {
+    client: hyper::Client<HttpConnector>,
+    /// Like `http://127.0.0.1:8000/api/v1`
+    base: String,
+    /// Sent as a bearer token when the server needs a login
+    token: Option<String>,
+}
+
+/// The local data store, which a running server should not be using.
+fn open_local(config: &Config) -> Result<AppState> {
+    let storage = storage::open(config.storage, &config.data, config.backups)?;
+    let app = App::load(storage.as_ref())?;
+    Ok(AppState::new(
+        app,
+        storage,
+        config.data.clone(),
+        config.zone,
+    ))
+}
+
+impl Client {
+    /// Saves the local store right away, nothing to do for a server.
+    pub async fn save(&self) -> Result<()> {
+        match self {
+            Client::Local(state) => state.flush().await,
+            Client::Remote(_) => Ok(()),
+        }
+    }
+
+    pub async fn tasks(&self, archived: bool) -> Result<Vec<Task>> {
+        match self {
+            Client::Local(state) => Ok(state
+                .read()
+                .await
+                .get_tasks()
+                .iter()
+                .filter(|task| archived || !task.is_archived())
+                .cloned()
+                .collect()),
+            Client::Remote(remote) => {
+                let path = format!("/tasks?archived={}", archived);
+                remote.fetch(Method::GET, &path, None).await
+            }
+        }
+    }
+
+    pub async fn task(&self, id: u64) -> Result<Task> {
+        match self {
+            Client::Local(state) => Ok(state.read().await.get_task(id)?.clone()),
+            Client::Remote(remote) => {
+                let path = format!("/tasks/{}", id);
+                remote.fetch(Method::GET, &path, None).await
+            }
+        }
+    }
+
+    pub async fn add(&self, name: String, parent: Option<u64>) -> Result<Task> {
+        match self {
+            Client::Local(state) => {
+                let mut app = state.write().await;
+                let id = match parent {
+                    Some(parent) => app.add_subtask(parent, name, None)?,
+                    None => app.add_task(name, None)?,
+                };
+                Ok(app.get_task(id)?.clone())
+            }
+            Client::Remote(remote) => {
+                let body = json!({ "name": name, "parent_id": parent });
+                remote.fetch(Method::POST, "/tasks", Some(body)).await
+            }
+        }
+    }
+
+    /// Starts the task, or resumes it when paused.
+    pub async fn start(&self, id: u64) -> Result<()> {
+        match self {
+            Client::Local(state) => {
+                let mut app = state.write().await;
+                match app.get_task(id)?.get_status() {
+                    TaskStaus::InProgress => Err(Error::InvalidState(
+                        "Task already has a running session".to_string(),
+                    )),
+                    TaskStaus::Paused => app.resume_task(id, None),
+                    _ => app.start_task(id, None),
+                }
+            }
+            Client::Remote(remote) => {
+                let path = format!("/tasks/{}/sessions", id);
+                remote.send(Method::POST, &path, None).await.map(|_| ())
+            }
+        }
+    }
+
+    pub async fn pause(&self, id: u64) -> Result<()> {
+        match self {
+            Client::Local(state) => state.write().await.pause_task(id, None),
+            Client::Remote(remote) => {
+                let path = format!("/tasks/{}/sessions/current", id);
+                remote.send(Method::DELETE, &path, None).await.map(|_| ())
+            }
+        }
+    }
+
+    /// Completes the task, returning the names of the images that have to be
+    /// attached before it is.
+    pub async fn stop(&self, id: u64, summary: Option<String>) -> Result<Vec<String>> {
+        match self {
+            Client::Local(state) => {
+                let data = state.data();
+                let images = state
+                    .write()
+                    .await
+                    .stop_task(id, summary, None, data)
+                    .await?;
+                let mut pending: Vec<String> = images.unwrap_or_default().into_keys().collect();
+                pending.sort();
+                Ok(pending)
+            }
+            Client::Remote(remote) => {
+                let path = format!("/tasks/{}", id);
+                let body = json!({ "status": TaskStaus::Complete, "summary": summary });
+                let (status, body) = remote.send(Method::PATCH, &path, Some(body)).await?;
+                if status != StatusCode::ACCEPTED {
+                    return Ok(Vec::new());
+                }
+                let body: Value = serde_json::from_slice(&body)?;
+                Ok(serde_json::from_value(body["pending_attachments"].clone())?)
+            }
+        }
+    }
+
+    /// The rendered summary of the task.
+    pub async fn summary(&self, id: u64) -> Result<String> {
+        match self {
+            Client::Local(state) => {
+                let path = state
+                    .read()
+                    .await
+                    .get_task(id)?
+                    .get_summary()
+                    .map(str::to_string)
+                    .ok_or_else(|| Error::NotFound(format!("Task {} has no summary", id)))?;
+                Ok(async_fs::read_to_string(state.data().resolve(&path)?).await?)
+            }
+            Client::Remote(remote) => {
+                let path = format!("/tasks/{}/summary", id);
+                let (_, bytes) = remote.send(Method::GET, &path, None).await?;
+                Ok(String::from_utf8_lossy(&bytes).into_owned())
+            }
+        }
+    }
+
+    /// The markdown the summary of the task was rendered from.
+    pub async fn summary_source(&self, id: u64) -> Result<String> {
+        match self {
+            Client::Local(state) => {
+                if state.read().await.get_task(id)?.get_summary().is_none() {
+                    return Err(Error::NotFound(format!("Task {} has no summary", id)));
+                }
+                let path = state.data().resolve(&DataDir::summary_source(id))?;
+                async_fs::read_to_string(path).await.map_err(|_| {
+                    Error::NotFound(format!("The markdown of task {} was not kept", id))
+                })
+            }
+            Client::Remote(remote) => {
+                let path = format!("/tasks/{}/summary/source", id);
+                let (_, bytes) = remote.send(Method::GET, &path, None).await?;
+                Ok(String::from_utf8_lossy(&bytes).into_owned())
+            }
+        }
+    }
+
+    /// Replaces the summary of the task.
+    pub async fn set_summary(&self, id: u64, summary: String) -> Result<()> {
+        match self {
+            Client::Local(state) => {
+                let data = state.data();
+                state
+                    .write()
+                    .await
+                    .set_summary(id, summary, None, data)
+                    .await
+            }
+            Client::Remote(remote) => {
+                let path = format!("/tasks/{}/summary", id);
+                let body = json!({ "markdown": summary });
+                remote
+                    .send(Method::PUT, &path, Some(body))
+                    .await
+                    .map(|_| ())
+            }
+        }
+    }
+
+    /// Line diff between two revisions of the summary, `to` defaults to the
+    /// current one.
+    pub async fn summary_diff(&self, id: u64, from: u64, to: Option<u64>) -> Result<Vec<DiffLine>> {
+        match self {
+            Client::Local(state) => api::diff_summary(state, id, from, to).await,
+            Client::Remote(remote) => {
+                let mut path = format!("/tasks/{}/summary/diff?from={}", id, from);
+                if let Some(to) = to {
+                    path.push_str(&format!("&to={}", to));
+                }
+                remote.fetch(Method::GET, &path, None).await
+            }
+        }
+    }
+
+    pub async fn restore_summary(&self, id: u64, number: u64) -> Result<()> {
+        match self {
+            Client::Local(state) => {
+                let data = state.data();
+                state
+                    .write()
+                    .await
+                    .restore_summary(id, number, None, data)
+                    .await
+            }
+            Client::Remote(remote) => {
+                let path = format!("/tasks/{}/summary/revisions/{}/restore", id, number);
+                remote.send(Method::POST, &path, None).await.map(|_| ())
+            }
+        }
+    }
+
+    pub async fn attach(&self, id: u64, uploads: Vec<Upload>) -> Result<()> {
+        match self {
+            Client::Local(state) => {
+                api::attach_uploads(state, id, None, &IfMatch(None), &uploads).await
+            }
+            Client::Remote(remote) => {
+                let path = format!("/tasks/{}/attachments", id);
+                let body = serde_json::to_value(uploads)?;
+                remote
+                    .send(Method::POST, &path, Some(body))
+                    .await
+                    .map(|_| ())
+            }
+        }
+    }
+
+    pub async fn rename(&self, id: u64, name: String) -> Result<()> {
+        match self {
+            Client::Local(state) => state.write().await.rename_task(id, name),
+            Client::Remote(remote) => {
+                let path = format!("/tasks/{}", id);
+                let body = json!({ "name": name });
+                remote
+                    .send(Method::PATCH, &path, Some(body))
+                    .await
+                    .map(|_| ())
+            }
+        }
+    }
+}
+
+impl Remote {
+    /// Only plain http is spoken, a `https://` server is refused rather than
+    /// failing on the first request.
+    fn new(server: &str, token: Option<String>) -> Result<Remote> {
+        let server = server.trim_end_matches('/');
+        let base = match server.split_once("://") {
+            Some(("http", _)) => format!("{}/api/v1", server),
+            Some((scheme, _)) => {
+                return Err(Error::Config(format!(
+                    "Unsupported scheme {} in --server {}, only http is supported",
+                    scheme, server
+                )))
+            }
+            None => format!("http://{}/api/v1", server),
+        };
+        Ok(Remote {
+            client: hyper::Client::new(),
+            base,
+            token,
+        })
+    }
+
+    /// Sends the request, turning error responses back into an [`Error`].
+    async fn send(
+        &self,
+        method: Method,
+        path: &str,
+        body: Option<Value>,
+    ) -> Result<(StatusCode, hyper::body::Bytes)> {
+        let uri = format!("{}{}", self.base, path);
+        let mut request = Request::builder()
+            .method(method)
+            .uri(&uri)
+            .header(header::CONTENT_TYPE, "application/json");
+        if let Some(token) = &self.token {
+            request = request.header(header::AUTHORIZATION, format!("Bearer {}", token));
+        }
+        let request = request
+            .body(body.map_or_else(Body::empty, |body| Body::from(body.to_string())))
+            .map_err(|_| Error::Config(format!("Invalid server address {}", uri)))?;
+        let response = self.client.request(request).await?;
+        let status = response.status();
+        let bytes = hyper::body::to_bytes(response.into_body()).await?;
+        if status.is_success() {
+            return Ok((status, bytes));
+        }
+        let body: Value = serde_json::from_slice(&bytes).unwrap_or_default();
+        let message = body["message"]
+            .as_str()
+            .map_or_else(|| format!("Server answered {}", status), str::to_string);
+        Err(match body["error"].as_str() {
+            Some("task_not_found") | Some("not_found") => Error::NotFound(message),
+            Some("bad_request") => Error::BadRequest(message),
+            Some("unauthorized") => Error::Unauthorized(message),
+            Some("forbidden") => Error::Forbidden(message),
+            Some("invalid_state") => Error::InvalidState(message),
+            Some("precondition_failed") => Error::PreconditionFailed(message),
+            Some("too_large") => Error::TooLarge(message),
+            _ => Error::Storage(message),
+        })
+    }
+
+    async fn fetch<T: DeserializeOwned>(
+        &self,
+        method: Method,
+        path: &str,
+        body: Option<Value>,
+    ) -> Result<T> {
+        let (_, bytes) = self.send(method, path, body).await?;
+        Ok(serde_json::from_slice(&bytes)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_render_tree() {
+        let mut app = App::new();
+        let root = app.add_task("root".to_string(), None).unwrap();
+        let child = app.add_subtask(root, "child".to_string(), None).unwrap();
+        app.add_task("other".to_string(), None).unwrap();
+        app.add_subtask(child, "grandchild".to_string(), None)
+            .unwrap();
+        let names: Vec<String> = render_tree(app.get_tasks())
+            .lines()
+            .map(|line| line[29..].to_string())
+            .collect();
+        assert_eq!(names, vec!["root", "  child", "    grandchild", "other"]);
+        // Without their parent subtasks move to the top
+        let names: Vec<String> = render_tree(&app.get_tasks()[1..])
+            .lines()
+            .map(|line| line[29..].to_string())
+            .collect();
+        assert_eq!(names, vec!["child", "  grandchild", "other"]);
+    }
+
+    async fn exercise(client: Client) {
+        let zone = Zone::utc();
+        let command = Command::Add {
+            name: vec!["Review".to_string()],
+            parent: Some(0),
+        };
+        assert_eq!(
+            execute(&client, command, zone).await.unwrap(),
+            "Added task 1 Review\n"
+        );
+        execute(&client, Command::Start { id: 1 }, zone)
+            .await
+            .unwrap();
+        let error = execute(&client, Command::Start { id: 1 }, zone)
+            .await
+            .unwrap_err();
+        assert!(matches!(error, Error::InvalidState(_)));
+        execute(&client, Command::Pause { id: 1 }, zone)
+            .await
+            .unwrap();
+        execute(&client, Command::Start { id: 1 }, zone)
+            .await
+            .unwrap();
+        let command = Command::Rename {
+            id: 1,
+            name: vec!["Review".to_string(), "draft".to_string()],
+        };
+        execute(&client, command, zone).await.unwrap();
+        let error = execute(&client, Command::Show { id: 7 }, zone)
+            .await
+            .unwrap_err();
+        assert!(matches!(error, Error::TaskNotFound(7) | Error::NotFound(_)));
+
+        let tree = execute(&client, Command::Tree { archived: false }, zone)
+            .await
+            .unwrap();
+        assert!(tree.contains("In Progress"), "{}", tree);
+        assert!(tree.ends_with("  Review draft\n"), "{}", tree);
+        let show = execute(&client, Command::Show { id: 1 }, zone)
+            .await
+            .unwrap();
+        assert!(show.starts_with("Task 1: Review draft\n"), "{}", show);
+        assert!(show.contains("Parent:   0\n"), "{}", show);
+        assert_eq!(show.matches(" - ").count(), 2, "{}", show);
+
+        let summary = |file, history, diff, restore| Command::Summary {
+            id: 1,
+            file,
+            edit: false,
+            history,
+            diff,
+            to: None,
+            restore,
+        };
+        let show = || summary(None, false, None, None);
+        let error = execute(&client, show(), zone).await.unwrap_err();
+        assert!(matches!(error, Error::NotFound(_)));
+        let dir = DataDir::temp();
+        std::fs::create_dir_all(dir.root()).unwrap();
+        let file = dir.root().join("notes.md");
+        std::fs::write(&file, "# Notes").unwrap();
+        let replace = summary(Some(file.clone()), false, None, None);
+        assert_eq!(
+            execute(&client, replace, zone).await.unwrap(),
+            "Updated the summary of task 1\n"
+        );
+        assert_eq!(execute(&client, show(), zone).await.unwrap(), "# Notes\n");
+        assert!(client.summary(1).await.unwrap().contains("<h1>Notes</h1>"));
+
+        std::fs::write(&file, "# Notes\nMore").unwrap();
+        let replace = summary(Some(file), false, None, None);
+        execute(&client, replace, zone).await.unwrap();
+        let diff = summary(None, false, Some(1), None);
+        assert_eq!(
+            execute(&client, diff, zone).await.unwrap(),
+            "  # Notes\n+ More\n"
+        );
+        let restore = summary(None, false, None, Some(1));
+        execute(&client, restore, zone).await.unwrap();
+        assert_eq!(execute(&client, show(), zone).await.unwrap(), "# Notes\n");
+        let history = execute(&client, summary(None, true, None, None), zone)
+            .await
+            .unwrap();
+        assert_eq!(history.lines().count(), 3, "{}", history);
+        assert!(history.ends_with(", restores 1\n"), "{}", history);
+        let missing = summary(None, false, Some(7), None);
+        let error = execute(&client, missing, zone).await.unwrap_err();
+        assert!(matches!(error, Error::NotFound(_)));
+        std::fs::remove_dir_all(dir.root()).unwrap();
+    }
+
+    fn state() -> AppState {
+        let storage = Arc::new(storage::SqliteStorage::open(":memory:").unwrap());
+        let mut app = App::new();
+        app.add_task("Write report".to_string(), None).unwrap();
+        AppState::new(app, storage, DataDir::temp(), Zone::utc())
+    }
+
+    #[tokio::test]
+    async fn test_local_client() {
+        exercise(Client::Local(state())).await;
+    }
+
+    #[tokio::test]
+    async fn test_remote_client() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = axum::Server::from_tcp(listener)
+            .unwrap()
+            .serve(crate::router(state(), &Config::default()).into_make_service());
+        tokio::spawn(server);
+        exercise(Client::Remote(
+            Remote::new(&addr.to_string(), None).unwrap(),
+        ))
+        .await;
+
+        let client = Client::Remote(Remote::new("http://127.0.0.1:1/", None).unwrap());
+        assert!(matches!(client.tasks(false).await, Err(Error::Http(_))));
+        assert!(matches!(
+            Remote::new("https://tasks.example.com", None),
+            Err(Error::Config(_))
+        ));
+    }
+}
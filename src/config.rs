@@ -0,0 +1,359 @@
+//! Settings of the server and the client commands. Each one comes from a flag,
+//! its environment variable or the TOML config file, in that order, before
+//! falling back to the default.
+
+use std::{
+    net::SocketAddr,
+    path::{Path, PathBuf},
+};
+
+use axum::http::{header, HeaderValue};
+use clap::Parser;
+use serde::Deserialize;
+use tower_http::cors::{AllowOrigin, Any, CorsLayer};
+use tracing_subscriber::filter::LevelFilter;
+
+use crate::{
+    cli,
+    data_dir::DataDir,
+    error::{Error, Result},
+    storage::{self, Backend},
+    zone::Zone,
+};
+
+const DEFAULT_BIND: &str = "127.0.0.1:8000";
+/// Uploads carry their images base64 encoded in the body
+const DEFAULT_BODY_LIMIT: usize = 1024 * 1024 * 1024;
+
+/// Simple utility to manage stuff you are doing.
+///
+/// Without a command the server is started, serving the web UI and the API.
+#[derive(Parser, Debug)]
+#[command(version)]
+pub struct Args {
+    /// Address to serve on [default: 127.0.0.1:8000]
+    #[arg(value_name = "BIND", env = "TASKS_TODO_BIND")]
+    bind: Option<String>,
+    /// Same as --storage, kept for older scripts
+    #[arg(value_name = "STORAGE", hide = true)]
+    legacy_storage: Option<String>,
+    /// TOML config file [default: tasks-todo/config.toml in the user config
+    /// directory]
+    #[arg(long, short, env = "TASKS_TODO_CONFIG", global = true)]
+    config: Option<PathBuf>,
+    /// Directory the tasks, summaries and images are kept in [default:
+    /// tasks-todo in the user data directory]
+    #[arg(long, env = "TASKS_TODO_DATA_DIR", global = true)]
+    data_dir: Option<PathBuf>,
+    /// json or sqlite [default: json]
+    #[arg(long, env = "TASKS_TODO_STORAGE", global = true)]
+    storage: Option<String>,
+    /// Hourly data.json backups to keep [default: 5]
+    #[arg(long, env = "TASKS_TODO_BACKUPS", global = true)]
+    backups: Option<usize>,
+    /// IANA time zone timestamps are shown in, like Asia/Kolkata [default: TZ
+    /// or UTC]
+    #[arg(long, env = "TASKS_TODO_TZ", global = true)]
+    timezone: Option<String>,
+    /// Largest accepted request body, like 10MiB [default: 1GiB]
+    #[arg(long, env = "TASKS_TODO_BODY_LIMIT")]
+    body_limit: Option<String>,
+    /// Origin allowed to call the API from a browser, may be repeated, * for
+    /// any [default: *]
+    #[arg(
+        long = "cors-origin",
+        value_name = "ORIGIN",
+        env = "TASKS_TODO_CORS_ORIGINS",
+        value_delimiter = ','
+    )]
+    cors_origins: Option<Vec<String>>,
+    /// off, error, warn, info, debug or trace [default: info]
+    #[arg(long, env = "TASKS_TODO_LOG")]
+    log_level: Option<String>,
+    /// Run commands against this server instead of the local data store
+    #[arg(long, value_name = "ADDRESS", env = "TASKS_TODO_SERVER", global = true)]
+    pub server: Option<String>,
+    /// API token sent to --server, see `tasks-todo user token`
+    #[arg(long, env = "TASKS_TODO_TOKEN", global = true, hide_env_values = true)]
+    pub token: Option<String>,
+    #[command(subcommand)]
+    pub command: Option<cli::Command>,
+}
+
+/// The config file, every key is optional.
+#[derive(Deserialize, Default, Debug)]
+#[serde(deny_unknown_fields)]
+struct File {
+    bind: Option<String>,
+    data_dir: Option<PathBuf>,
+    storage: Option<String>,
+    backups: Option<usize>,
+    timezone: Option<String>,
+    body_limit: Option<Size>,
+    cors_origins: Option<Vec<String>>,
+    log_level: Option<String>,
+}
+
+/// A size in bytes, or a string with a unit.
+#[derive(Deserialize, Debug)]
+#[serde(untagged)]
+enum Size {
+    Bytes(usize),
+    Text(String),
+}
+
+#[derive(Clone, Debug)]
+pub struct Config {
+    pub bind: SocketAddr,
+    pub data: DataDir,
+    pub storage: Backend,
+    /// Only used by the json backend
+    pub backups: usize,
+    pub zone: Zone,
+    pub body_limit: usize,
+    /// `None` allows any origin
+    pub cors_origins: Option<Vec<HeaderValue>>,
+    pub log_level: LevelFilter,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            bind: DEFAULT_BIND.parse().unwrap(),
+            data: DataDir::new(DataDir::default_root().unwrap_or_else(|| PathBuf::from("."))),
+            storage: Backend::Json,
+            backups: storage::DEFAULT_BACKUPS,
+            zone: Zone::utc(),
+            body_limit: DEFAULT_BODY_LIMIT,
+            cors_origins: None,
+            log_level: LevelFilter::INFO,
+        }
+    }
+}
+
+impl Config {
+    /// Reads the config file and combines it with the arguments.
+    pub fn load(args: &Args) -> Result<Config> {
+        let file = match &args.config {
+            Some(path) => File::read(path)?,
+            None => match dirs::config_dir() {
+                Some(dir) if dir.join("tasks-todo/config.toml").exists() => {
+                    File::read(&dir.join("tasks-todo/config.toml"))?
+                }
+                _ => File::default(),
+            },
+        };
+        Config::resolve(args, file)
+    }
+
+    fn resolve(args: &Args, file: File) -> Result<Config> {
+        let mut config = Config::default();
+        if let Some(bind) = args.bind.as_ref().or(file.bind.as_ref()) {
+            config.bind = bind.parse().map_err(|_| {
+                Error::Config(format!("Invalid bind address {}, expected ip:port", bind))
+            })?;
+        }
+        if let Some(dir) = args.data_dir.clone().or(file.data_dir) {
+            config.data = DataDir::new(std::path::absolute(&dir).map_err(|e| {
+                Error::Config(format!("Invalid data directory {}: {}", dir.display(), e))
+            })?);
+        }
+        if let Some(storage) = args
+            .storage
+            .as_ref()
+            .or(args.legacy_storage.as_ref())
+            .or(file.storage.as_ref())
+        {
+            config.storage = storage.parse()?;
+        }
+        if let Some(backups) = args.backups.or(file.backups) {
+            config.backups = backups;
+        }
+        config.zone = match args.timezone.as_ref().or(file.timezone.as_ref()) {
+            Some(zone) => zone.parse()?,
+            None => Zone::from_tz_env(),
+        };
+        config.body_limit = match (&args.body_limit, &file.body_limit) {
+            (Some(limit), _) | (None, Some(Size::Text(limit))) => parse_size(limit)?,
+            (None, Some(Size::Bytes(limit))) => *limit,
+            (None, None) => DEFAULT_BODY_LIMIT,
+        };
+        let origins = args.cors_origins.as_ref().or(file.cors_origins.as_ref());
+        if let Some(origins) = origins.filter(|origins| !origins.iter().any(|o| o == "*")) {
+            config.cors_origins = Some(
+                origins
+                    .iter()
+                    .map(|o| parse_origin(o))
+                    .collect::<Result<_>>()?,
+            );
+        }
+        if let Some(level) = args.log_level.as_ref().or(file.log_level.as_ref()) {
+            config.log_level = level.parse().map_err(|_| {
+                Error::Config(format!(
+                    "Invalid log level {}, expected off, error, warn, info, debug or trace",
+                    level
+                ))
+            })?;
+        }
+        Ok(config)
+    }
+
+    pub fn cors(&self) -> CorsLayer {
+        match &self.cors_origins {
+            None => CorsLayer::permissive(),
+            Some(origins) => CorsLayer::new()
+                .allow_origin(AllowOrigin::list(origins.iter().cloned()))
+                .allow_methods(Any)
+                .allow_headers(Any)
+                .expose_headers([header::ETAG, header::LOCATION]),
+        }
+    }
+}
+
+impl File {
+    fn read(path: &Path) -> Result<File> {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|e| Error::Config(format!("Can not read {}: {}", path.display(), e)))?;
+        toml::from_str(&contents)
+            .map_err(|e| Error::Config(format!("Invalid config file {}: {}", path.display(), e)))
+    }
+}
+
+/// Parses a number of bytes with an optional unit, like "1048576", "512KB"
+/// or "10MiB".
+fn parse_size(size: &str) -> Result<usize> {
+    let invalid = || {
+        Error::Config(format!(
+            "Invalid size {}, expected something like 10MiB",
+            size
+        ))
+    };
+    let size = size.trim();
+    let digits = size
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(size.len());
+    let number: usize = size[..digits].parse().map_err(|_| invalid())?;
+    let unit = match size[digits..].trim().to_lowercase().as_str() {
+        "" | "b" => 1,
+        "kb" => 1000,
+        "k" | "kib" => 1024,
+        "mb" => 1000 * 1000,
+        "m" | "mib" => 1024 * 1024,
+        "gb" => 1000 * 1000 * 1000,
+        "g" | "gib" => 1024 * 1024 * 1024,
+        _ => return Err(invalid()),
+    };
+    number.checked_mul(unit).ok_or_else(invalid)
+}
+
+fn parse_origin(origin: &str) -> Result<HeaderValue> {
+    let invalid = || {
+        Error::Config(format!(
+            "Invalid CORS origin {}, expected something like https://example.com",
+            origin
+        ))
+    };
+    let Some((scheme, host)) = origin.split_once("://") else {
+        return Err(invalid());
+    };
+    if !matches!(scheme, "http" | "https") || host.is_empty() || host.contains('/') {
+        return Err(invalid());
+    }
+    HeaderValue::from_str(origin).map_err(|_| invalid())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(line: &str) -> Args {
+        Args::try_parse_from(std::iter::once("tasks-todo").chain(line.split_whitespace())).unwrap()
+    }
+
+    fn resolve(line: &str, file: &str) -> Result<Config> {
+        Config::resolve(&args(line), toml::from_str(file).unwrap())
+    }
+
+    #[test]
+    fn test_parse_args() {
+        // The server keeps taking the address and backend as before
+        let config = resolve("0.0.0.0:9000 sqlite", "").unwrap();
+        assert_eq!(config.bind, "0.0.0.0:9000".parse().unwrap());
+        assert_eq!(config.storage, Backend::Sqlite);
+
+        assert_eq!(
+            args("--storage sqlite add Write the report --parent 3").command,
+            Some(cli::Command::Add {
+                name: vec!["Write", "the", "report"]
+                    .into_iter()
+                    .map(String::from)
+                    .collect(),
+                parent: Some(3)
+            })
+        );
+        let args = args("show 4 --server localhost:8000");
+        assert_eq!(args.server.as_deref(), Some("localhost:8000"));
+        assert_eq!(args.command, Some(cli::Command::Show { id: 4 }));
+
+        for line in [
+            "add",
+            "start x",
+            "stop 1 --summary",
+            "stop 1 --summary notes.md --edit",
+            "rename 1",
+            "list all",
+            "--server",
+            "--body-limit",
+        ] {
+            let args = std::iter::once("tasks-todo").chain(line.split_whitespace());
+            assert!(Args::try_parse_from(args).is_err(), "{}", line);
+        }
+    }
+
+    #[test]
+    fn test_precedence() {
+        let file = r#"
+            bind = "127.0.0.1:7000"
+            timezone = "Asia/Kolkata"
+            backups = 10
+            body_limit = 2048
+            cors_origins = ["https://tasks.example.com"]
+            log_level = "debug"
+        "#;
+        let config = resolve("--timezone UTC --body-limit 10MiB --backups 0", file).unwrap();
+        assert_eq!(config.bind, "127.0.0.1:7000".parse().unwrap());
+        assert_eq!(config.backups, 0);
+        assert_eq!(config.zone, Zone::utc());
+        assert_eq!(config.body_limit, 10 * 1024 * 1024);
+        assert_eq!(
+            config.cors_origins.unwrap(),
+            vec!["https://tasks.example.com"]
+        );
+        assert_eq!(config.log_level, LevelFilter::DEBUG);
+
+        let config = resolve("--cors-origin *", file).unwrap();
+        assert_eq!(config.backups, 10);
+        assert_eq!(config.body_limit, 2048);
+        assert!(config.cors_origins.is_none());
+    }
+
+    #[test]
+    fn test_validation() {
+        for (line, file) in [
+            ("localhost", ""),
+            ("--storage csv", ""),
+            ("--timezone Mars/Olympus", ""),
+            ("--body-limit 10XB", ""),
+            ("", "body_limit = \"lots\""),
+            ("--cors-origin example.com", ""),
+            ("--cors-origin https://example.com/path", ""),
+            ("--log-level loud", ""),
+        ] {
+            assert!(resolve(line, file).is_err(), "{} {}", line, file);
+        }
+        assert!(toml::from_str::<File>("port = 80").is_err());
+        assert!(toml::from_str::<File>("backups = -1").is_err());
+        assert_eq!(parse_size("512 KB").unwrap(), 512_000);
+        assert_eq!(parse_size("1G").unwrap(), 1024 * 1024 * 1024);
+    }
+}
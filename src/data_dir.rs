@@ -0,0 +1,212 @@
+use std::{
+    path::{Component, Path, PathBuf},
+    sync::atomic::{AtomicU64, Ordering},
+    time::SystemTime,
+};
+
+use crate::error::{Error, Result};
+
+/// The directory everything is stored under: the task database, summaries
+/// with their markdown and revisions, uploaded images and summaries waiting
+/// for their images.
+///
+/// Tasks keep the paths of their files relative to it, like
+/// `summaries/3.html`, so the whole directory can be moved around.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DataDir {
+    root: PathBuf,
+    #[cfg(test)]
+    temp: Option<std::sync::Arc<TempRoot>>,
+}
+
+/// Removes the directory of `DataDir::temp` once the last clone is dropped.
+#[cfg(test)]
+#[derive(Debug, PartialEq)]
+struct TempRoot(PathBuf);
+
+#[cfg(test)]
+impl Drop for TempRoot {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+impl DataDir {
+    pub fn new(root: impl Into<PathBuf>) -> DataDir {
+        DataDir {
+            root: root.into(),
+            #[cfg(test)]
+            temp: None,
+        }
+    }
+
+    /// `tasks-todo` in the user data directory, like
+    /// `~/.local/share/tasks-todo`.
+    pub fn default_root() -> Option<PathBuf> {
+        dirs::data_dir().map(|dir| dir.join("tasks-todo"))
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Creates the directory if it does not exist yet.
+    pub fn create(&self) -> Result<()> {
+        std::fs::create_dir_all(&self.root)?;
+        Ok(())
+    }
+
+    /// Where a path kept in a task is on disk. Only relative paths that stay
+    /// inside the directory are accepted, symlinks included.
+    pub fn resolve(&self, stored: &str) -> Result<PathBuf> {
+        let invalid = || Error::BadRequest(format!("Invalid path {}", stored));
+        let relative = Path::new(stored);
+        if stored.is_empty()
+            || !relative
+                .components()
+                .all(|component| matches!(component, Component::Normal(_)))
+        {
+            return Err(invalid());
+        }
+        let path = self.root.join(relative);
+        // A symlink on the way could still point elsewhere, check the part
+        // that exists
+        let existing = path
+            .ancestors()
+            .take_while(|ancestor| ancestor.starts_with(&self.root))
+            .find(|ancestor| ancestor.exists());
+        if let Some(existing) = existing {
+            let root = self.root.canonicalize()?;
+            if !existing.canonicalize()?.starts_with(root) {
+                return Err(invalid());
+            }
+        }
+        Ok(path)
+    }
+
+    /// Where the file with a client supplied name in one of the directories
+    /// is, like `summaries` and `3.html`. The name may not contain a path.
+    pub fn file(&self, dir: &str, name: &str) -> Result<PathBuf> {
+        let mut components = Path::new(name).components();
+        match (components.next(), components.next()) {
+            (Some(Component::Normal(_)), None) if !name.contains(['/', '\\']) => {
+                self.resolve(&format!("{}/{}", dir, name))
+            }
+            _ => Err(Error::BadRequest(format!("Invalid file name {}", name))),
+        }
+    }
+
+    /// Writes the file at the stored path, creating its directory first.
+    pub async fn write(&self, stored: &str, contents: impl AsRef<[u8]>) -> Result<()> {
+        let path = self.resolve(stored)?;
+        if let Some(parent) = path.parent() {
+            async_fs::create_dir_all(parent).await?;
+        }
+        // Resolve again now that the directories exist
+        async_fs::write(self.resolve(stored)?, contents).await?;
+        Ok(())
+    }
+
+    /// Stored path of the rendered summary of a task.
+    pub fn summary(id: u64) -> String {
+        format!("summaries/{}.html", id)
+    }
+
+    /// Stored path of the markdown a summary was rendered from.
+    pub fn summary_source(id: u64) -> String {
+        format!("summaries/{}.md", id)
+    }
+
+    /// Stored path of the markdown of one revision of a summary.
+    pub fn summary_revision(id: u64, number: u64) -> String {
+        format!("summaries/{}-{}.md", id, number)
+    }
+
+    /// Stored path of a summary that waits for its images to be uploaded.
+    pub fn pending_summary(id: u64) -> String {
+        format!("temp/{}.md", id)
+    }
+
+    /// A new stored path for an image uploaded for a task. Names are made up
+    /// here so nothing the client sends ends up in a path.
+    pub fn image(id: u64, extension: &str) -> String {
+        static COUNT: AtomicU64 = AtomicU64::new(0);
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+        let count = COUNT.fetch_add(1, Ordering::Relaxed);
+        format!("images/{}_{:x}{:x}.{}", id, now, count, extension)
+    }
+
+    /// An empty directory in the system temp dir, a new one on every call.
+    /// It is removed again when the last clone is dropped.
+    #[cfg(test)]
+    pub fn temp() -> DataDir {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        static COUNT: AtomicUsize = AtomicUsize::new(0);
+        let root = std::env::temp_dir().join(format!(
+            "tasks-todo-data-{}-{}",
+            std::process::id(),
+            COUNT.fetch_add(1, Ordering::Relaxed)
+        ));
+        let _ = std::fs::remove_dir_all(&root);
+        DataDir {
+            temp: Some(std::sync::Arc::new(TempRoot(root.clone()))),
+            root,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_paths_stay_inside() {
+        let data = DataDir::temp();
+        data.create().unwrap();
+        assert_eq!(
+            data.resolve("summaries/3.html").unwrap(),
+            data.root().join("summaries/3.html")
+        );
+        for stored in [
+            "",
+            "../data.json",
+            "summaries/../../etc/passwd",
+            "/etc/passwd",
+            "./data.json",
+        ] {
+            assert!(data.resolve(stored).is_err(), "{}", stored);
+        }
+        assert!(data.file("summaries", "3.html").is_ok());
+        for name in ["..", "../3.html", "/etc/passwd", "a/b", "a\\b", ""] {
+            assert!(data.file("summaries", name).is_err(), "{}", name);
+        }
+        std::fs::remove_dir_all(data.root()).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_symlinks_stay_inside() {
+        let data = DataDir::temp();
+        data.create().unwrap();
+        std::os::unix::fs::symlink("/etc", data.root().join("images")).unwrap();
+        assert!(data.resolve("images/passwd").is_err());
+        assert!(data.resolve("images/new.png").is_err());
+        std::fs::remove_dir_all(data.root()).unwrap();
+    }
+
+    #[test]
+    fn test_temp_is_removed() {
+        let data = DataDir::temp();
+        data.create().unwrap();
+        let root = data.root().to_path_buf();
+        let clone = data.clone();
+        drop(data);
+        assert!(root.exists());
+        drop(clone);
+        assert!(!root.exists());
+    }
+}
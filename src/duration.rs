@@ -1,60 +1,194 @@
 use serde::{Deserialize, Serialize};
-use std::ops;
+use std::{fmt::Display, ops, str::FromStr};
 
-#[derive(Debug, Serialize, Deserialize, Default, Clone, Copy, PartialEq, PartialOrd)]
+/// A length of time with second precision. Always normalized, it is stored
+/// (and serialized) as a plain number of seconds.
+#[derive(Debug, Serialize, Deserialize, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(transparent)]
 pub struct Duration {
-    pub seconds: u64,
-    pub minutes: u64,
-    pub hours: u64,
+    seconds: u64,
 }
 
+const MINUTE: u64 = 60;
+const HOUR: u64 = 60 * MINUTE;
+const DAY: u64 = 24 * HOUR;
+
 impl Duration {
-    pub fn to_seconds(&self) -> u64 {
-        self.seconds + self.minutes * 60 + self.hours * 3600
+    pub fn to_seconds(self) -> u64 {
+        self.seconds
     }
 
     pub fn from_seconds(seconds: u64) -> Self {
-        let hours = seconds / 3600;
-        let minutes = (seconds - hours * 3600) / 60;
-        let seconds = seconds - hours * 3600 - minutes * 60;
-        Self {
-            seconds,
-            minutes,
-            hours,
-        }
+        Self { seconds }
+    }
+
+    /// Whole days.
+    pub fn days(self) -> u64 {
+        self.seconds / DAY
+    }
+
+    /// Hours past the whole days, 0 to 23.
+    pub fn hours(self) -> u64 {
+        self.seconds % DAY / HOUR
+    }
+
+    /// Minutes past the whole hours, 0 to 59.
+    pub fn minutes(self) -> u64 {
+        self.seconds % HOUR / MINUTE
+    }
+
+    /// Seconds past the whole minutes, 0 to 59.
+    pub fn seconds(self) -> u64 {
+        self.seconds % MINUTE
+    }
+
+    /// The longest duration if the sum does not fit.
+    pub fn saturating_add(self, other: Self) -> Self {
+        Self::from_seconds(self.seconds.saturating_add(other.seconds))
+    }
+
+    /// Zero if `other` is longer than `self`.
+    pub fn saturating_sub(self, other: Self) -> Self {
+        Self::from_seconds(self.seconds.saturating_sub(other.seconds))
     }
 }
 
+/// Saturates at the longest duration.
 impl ops::Add for Duration {
     type Output = Self;
 
     fn add(self, other: Self) -> Self {
-        Self {
-            seconds: self.seconds + other.seconds,
-            minutes: self.minutes + other.minutes,
-            hours: self.hours + other.hours,
-        }
+        self.saturating_add(other)
     }
 }
 
+/// Saturates at zero.
 impl ops::Sub for Duration {
     type Output = Self;
 
     fn sub(self, other: Self) -> Self {
-        Self {
-            seconds: self.seconds - other.seconds,
-            minutes: self.minutes - other.minutes,
-            hours: self.hours - other.hours,
-        }
+        self.saturating_sub(other)
     }
 }
 
 impl ops::AddAssign for Duration {
     fn add_assign(&mut self, other: Self) {
-        *self = Self {
-            seconds: self.seconds + other.seconds,
-            minutes: self.minutes + other.minutes,
-            hours: self.hours + other.hours,
+        *self = *self + other;
+    }
+}
+
+impl ops::SubAssign for Duration {
+    fn sub_assign(&mut self, other: Self) {
+        *self = *self - other;
+    }
+}
+
+impl std::iter::Sum for Duration {
+    fn sum<I: Iterator<Item = Self>>(iter: I) -> Self {
+        iter.fold(Duration::default(), |total, d| total + d)
+    }
+}
+
+/// Shows the two largest units, like "1d 02h", "2h 05m", "5m 03s" or "42s".
+impl Display for Duration {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.days() > 0 {
+            write!(f, "{}d {:02}h", self.days(), self.hours())
+        } else if self.hours() > 0 {
+            write!(f, "{}h {:02}m", self.hours(), self.minutes())
+        } else if self.minutes() > 0 {
+            write!(f, "{}m {:02}s", self.minutes(), self.seconds())
+        } else {
+            write!(f, "{}s", self.seconds())
         }
     }
 }
+
+/// Parses a sequence of numbers with a `d`, `h`, `m` or `s` unit, like
+/// "1h30m", "90m", "1.5h" or "2h 05m".
+impl FromStr for Duration {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("Invalid duration {:?}, expected something like 1h30m", s);
+        let mut total = 0.0;
+        let mut rest = s.trim();
+        if rest.is_empty() {
+            return Err(invalid());
+        }
+        while !rest.is_empty() {
+            let number_len = rest
+                .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+                .ok_or_else(invalid)?;
+            let number: f64 = rest[..number_len].parse().map_err(|_| invalid())?;
+            rest = &rest[number_len..];
+            let unit = match rest.chars().next() {
+                Some('d') => DAY,
+                Some('h') => HOUR,
+                Some('m') => MINUTE,
+                Some('s') => 1,
+                _ => return Err(invalid()),
+            };
+            total += number * unit as f64;
+            rest = rest[1..].trim_start();
+        }
+        Ok(Self::from_seconds(total.round() as u64))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_normalized() {
+        let d = Duration::from_seconds(40) + Duration::from_seconds(40);
+        assert_eq!((d.minutes(), d.seconds()), (1, 20));
+        let d = Duration::from_seconds(DAY + 2 * HOUR + 5 * MINUTE + 3);
+        assert_eq!(
+            (d.days(), d.hours(), d.minutes(), d.seconds()),
+            (1, 2, 5, 3)
+        );
+    }
+
+    #[test]
+    fn test_sub() {
+        let short = Duration::from_seconds(5 * MINUTE);
+        let long = Duration::from_seconds(HOUR);
+        assert_eq!(short - long, Duration::default());
+        assert_eq!(long - short, Duration::from_seconds(55 * MINUTE));
+    }
+
+    #[test]
+    fn test_add_overflow() {
+        let max = Duration::from_seconds(u64::MAX);
+        let one = Duration::from_seconds(1);
+        assert_eq!(max + one, max);
+        assert_eq!([max, one, one].into_iter().sum::<Duration>(), max);
+    }
+
+    #[test]
+    fn test_display() {
+        let show = |s| Duration::from_seconds(s).to_string();
+        assert_eq!(show(2 * HOUR + 5 * MINUTE), "2h 05m");
+        assert_eq!(show(DAY + 2 * HOUR + 59 * MINUTE), "1d 02h");
+        assert_eq!(show(5 * MINUTE + 3), "5m 03s");
+        assert_eq!(show(42), "42s");
+    }
+
+    #[test]
+    fn test_parse() {
+        let parse = |s: &str| s.parse::<Duration>().map(|d| d.to_seconds());
+        assert_eq!(parse("1h30m"), Ok(90 * MINUTE));
+        assert_eq!(parse("90m"), Ok(90 * MINUTE));
+        assert_eq!(parse("1.5h"), Ok(90 * MINUTE));
+        assert_eq!(parse("2h 05m"), Ok(2 * HOUR + 5 * MINUTE));
+        assert_eq!(parse(" 1d 1s "), Ok(DAY + 1));
+        assert!(parse("").is_err());
+        assert!(parse("90").is_err());
+        assert!(parse("1x").is_err());
+        assert!(parse("h").is_err());
+        let d = Duration::from_seconds(3 * HOUR + 7 * MINUTE);
+        assert_eq!(d.to_string().parse::<Duration>(), Ok(d));
+    }
+}
@@ -0,0 +1,185 @@
+use std::fmt::Display;
+
+use axum::{
+    extract::rejection::{FormRejection, JsonRejection, PathRejection, QueryRejection},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+};
+use serde::Serialize;
+use utoipa::ToSchema;
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Debug)]
+pub enum Error {
+    /// No task with this id
+    TaskNotFound(u64),
+    /// A summary, image or other resource does not exist
+    NotFound(String),
+    /// The request is malformed
+    BadRequest(String),
+    /// No valid login session or API token
+    Unauthorized(String),
+    /// The role of the user does not allow this
+    Forbidden(String),
+    /// The task is not in a state that allows the action
+    InvalidState(String),
+    /// The task changed since the revision the client based its edit on
+    PreconditionFailed(String),
+    /// The request body is over the configured limit
+    TooLarge(String),
+    /// Invalid configuration
+    Config(String),
+    /// Stored data could not be read or written
+    Storage(String),
+    Io(std::io::Error),
+    Json(serde_json::Error),
+    Sqlite(rusqlite::Error),
+    Image(image::ImageError),
+    /// Talking to a remote server failed
+    Http(hyper::Error),
+}
+
+/// The body of every error response.
+#[derive(Serialize, ToSchema)]
+pub struct ErrorBody {
+    /// Stable machine readable name of the error, like `task_not_found`
+    error: &'static str,
+    message: String,
+}
+
+impl Error {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            Error::TaskNotFound(_) | Error::NotFound(_) => StatusCode::NOT_FOUND,
+            Error::BadRequest(_) => StatusCode::BAD_REQUEST,
+            Error::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+            Error::Forbidden(_) => StatusCode::FORBIDDEN,
+            Error::InvalidState(_) => StatusCode::CONFLICT,
+            Error::PreconditionFailed(_) => StatusCode::PRECONDITION_FAILED,
+            Error::TooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+            Error::Config(_)
+            | Error::Storage(_)
+            | Error::Io(_)
+            | Error::Json(_)
+            | Error::Sqlite(_)
+            | Error::Image(_)
+            | Error::Http(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    /// Stable machine readable name of the error.
+    pub fn code(&self) -> &'static str {
+        match self {
+            Error::TaskNotFound(_) => "task_not_found",
+            Error::NotFound(_) => "not_found",
+            Error::BadRequest(_) => "bad_request",
+            Error::Unauthorized(_) => "unauthorized",
+            Error::Forbidden(_) => "forbidden",
+            Error::InvalidState(_) => "invalid_state",
+            Error::PreconditionFailed(_) => "precondition_failed",
+            Error::TooLarge(_) => "too_large",
+            _ => "internal",
+        }
+    }
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::TaskNotFound(id) => write!(f, "Task {} not found", id),
+            Error::NotFound(message)
+            | Error::BadRequest(message)
+            | Error::Unauthorized(message)
+            | Error::Forbidden(message)
+            | Error::InvalidState(message)
+            | Error::PreconditionFailed(message)
+            | Error::TooLarge(message)
+            | Error::Config(message)
+            | Error::Storage(message) => write!(f, "{}", message),
+            Error::Io(e) => write!(f, "{}", e),
+            Error::Json(e) => write!(f, "{}", e),
+            Error::Sqlite(e) => write!(f, "{}", e),
+            Error::Image(e) => write!(f, "{}", e),
+            Error::Http(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl IntoResponse for Error {
+    fn into_response(self) -> Response {
+        if self.status().is_server_error() {
+            tracing::error!("Error: {}", self);
+        }
+        let body = ErrorBody {
+            error: self.code(),
+            message: self.to_string(),
+        };
+        (self.status(), axum::Json(body)).into_response()
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(e: serde_json::Error) -> Self {
+        Error::Json(e)
+    }
+}
+
+impl From<rusqlite::Error> for Error {
+    fn from(e: rusqlite::Error) -> Self {
+        Error::Sqlite(e)
+    }
+}
+
+impl From<image::ImageError> for Error {
+    fn from(e: image::ImageError) -> Self {
+        Error::Image(e)
+    }
+}
+
+impl From<hyper::Error> for Error {
+    fn from(e: hyper::Error) -> Self {
+        Error::Http(e)
+    }
+}
+
+impl From<tokio::task::JoinError> for Error {
+    fn from(e: tokio::task::JoinError) -> Self {
+        Error::Storage(e.to_string())
+    }
+}
+
+impl From<JsonRejection> for Error {
+    fn from(e: JsonRejection) -> Self {
+        if e.status() == StatusCode::PAYLOAD_TOO_LARGE {
+            return Error::TooLarge(e.body_text());
+        }
+        Error::BadRequest(e.body_text())
+    }
+}
+
+impl From<FormRejection> for Error {
+    fn from(e: FormRejection) -> Self {
+        Error::BadRequest(e.body_text())
+    }
+}
+
+impl From<QueryRejection> for Error {
+    fn from(e: QueryRejection) -> Self {
+        Error::BadRequest(e.body_text())
+    }
+}
+
+impl From<PathRejection> for Error {
+    fn from(e: PathRejection) -> Self {
+        Error::BadRequest(e.body_text())
+    }
+}
@@ -0,0 +1,44 @@
+use serde::Serialize;
+use utoipa::ToSchema;
+
+use crate::task::Task;
+
+/// A change to the tasks, pushed to every client watching
+/// `/api/v1/events`. Events carry the whole task after the change so clients
+/// can replace their copy.
+#[derive(Serialize, ToSchema, Clone)]
+#[serde(tag = "type")]
+pub enum Event {
+    #[serde(rename = "task_created")]
+    Created { task: Task },
+    /// Archived, reopened, got an image or anything without its own event
+    #[serde(rename = "task_updated")]
+    Updated { task: Task },
+    /// Started or resumed
+    #[serde(rename = "task_started")]
+    Started { task: Task },
+    #[serde(rename = "task_paused")]
+    Paused { task: Task },
+    /// Completed
+    #[serde(rename = "task_stopped")]
+    Stopped { task: Task },
+    #[serde(rename = "task_renamed")]
+    Renamed { task: Task },
+    #[serde(rename = "task_deleted")]
+    Deleted { id: u64 },
+}
+
+impl Event {
+    /// Name of the event in the stream, same as the `type` field.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Event::Created { .. } => "task_created",
+            Event::Updated { .. } => "task_updated",
+            Event::Started { .. } => "task_started",
+            Event::Paused { .. } => "task_paused",
+            Event::Stopped { .. } => "task_stopped",
+            Event::Renamed { .. } => "task_renamed",
+            Event::Deleted { .. } => "task_deleted",
+        }
+    }
+}
@@ -0,0 +1,54 @@
+//! Wrappers around the axum extractors that reject malformed requests with
+//! the same json error body as every other error.
+
+use axum::{
+    async_trait,
+    extract::FromRequestParts,
+    headers::{self, Header},
+    http::{header, request::Parts},
+    response::{IntoResponse, Response},
+};
+use axum_macros::FromRequest;
+use serde::Serialize;
+
+use crate::error::Error;
+
+#[derive(FromRequest)]
+#[from_request(via(axum::Json), rejection(Error))]
+pub struct Json<T>(pub T);
+
+impl<T: Serialize> IntoResponse for Json<T> {
+    fn into_response(self) -> Response {
+        axum::Json(self.0).into_response()
+    }
+}
+
+#[derive(FromRequest)]
+#[from_request(via(axum::Form), rejection(Error))]
+pub struct Form<T>(pub T);
+
+#[derive(axum_macros::FromRequestParts)]
+#[from_request(via(axum::extract::Query), rejection(Error))]
+pub struct Query<T>(pub T);
+
+#[derive(axum_macros::FromRequestParts)]
+#[from_request(via(axum::extract::Path), rejection(Error))]
+pub struct Path<T>(pub T);
+
+/// The `If-Match` header, `None` when the request has none.
+pub struct IfMatch(pub Option<headers::IfMatch>);
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for IfMatch {
+    type Rejection = Error;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Error> {
+        if !parts.headers.contains_key(header::IF_MATCH) {
+            return Ok(IfMatch(None));
+        }
+        let if_match =
+            headers::IfMatch::decode(&mut parts.headers.get_all(header::IF_MATCH).iter())
+                .map_err(|_| Error::BadRequest("Malformed If-Match header".to_string()))?;
+        Ok(IfMatch(Some(if_match)))
+    }
+}
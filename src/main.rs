@@ -1,91 +1,234 @@
-use app::App;
+use auth::CurrentUser;
 use axum::{
     body::{self, Body, Full},
-    extract::DefaultBodyLimit,
-    http::{header, Response, StatusCode},
+    extract::{DefaultBodyLimit, State},
+    headers::ETag,
+    http::{header, Method, Response, StatusCode},
+    middleware,
     response::{Html, IntoResponse},
-    routing::{get, post},
-    Json, Router,
+    routing::get,
+    Router, TypedHeader,
 };
+use clap::Parser;
+use config::Config;
+use error::{Error, Result};
+use extract::{IfMatch, Json, Path, Query};
 use serde::{Deserialize, Serialize};
-use std::{env, path::Path};
-use tower_http::cors::CorsLayer;
+use state::AppState;
+use task::Task;
+use tracing_subscriber::{
+    filter::{LevelFilter, Targets},
+    fmt,
+    prelude::*,
+};
+use user::Role;
+use utoipa::{IntoParams, ToSchema};
 
+mod api;
 mod app;
+mod auth;
+mod cli;
+mod config;
+mod data_dir;
 mod duration;
+mod error;
+mod event;
+mod extract;
+mod share;
+mod state;
+mod storage;
 mod task;
 mod time;
-
-type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+mod tui;
+mod user;
+mod zone;
 
 #[tokio::main]
-async fn main() -> Result<()> {
-    let args = env::args().collect::<Vec<String>>();
-    let ip = if args.len() == 2 {
-        args[1].parse::<String>()?
-    } else {
-        println!("Usage: {} <ip:port>", args[0]);
-        return Ok(());
+async fn main() {
+    let args = config::Args::parse();
+    let config = match Config::load(&args).and_then(|config| {
+        config.data.create()?;
+        Ok(config)
+    }) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            std::process::exit(2);
+        }
     };
+    // The libraries only get to say something when it is important
+    let filter = Targets::new()
+        .with_default(config.log_level.min(LevelFilter::WARN))
+        .with_target(env!("CARGO_CRATE_NAME"), config.log_level);
+    tracing_subscriber::registry()
+        .with(fmt::layer().with_writer(std::io::stderr))
+        .with(filter)
+        .init();
 
-    let state;
-    if !Path::new("data.json").exists() {
-        state = app::App::new();
-        state.save().await?;
-    } else {
-        state = app::App::load().await?;
-        state.save().await?;
+    // Older versions kept everything in the working directory
+    let cwd = std::env::current_dir().unwrap_or_default();
+    if cwd != config.data.root()
+        && [storage::JSON_FILE, storage::SQLITE_FILE]
+            .iter()
+            .any(|file| cwd.join(file).exists())
+    {
+        tracing::warn!(
+            "Found tasks in the working directory, but the data directory is {}. Pass --data-dir . to keep using them",
+            config.data.root().display()
+        );
+    }
+
+    let result = match args.command {
+        Some(command) => cli::run(command, args.server, args.token, &config).await,
+        None => serve(config).await,
+    };
+    if let Err(e) = result {
+        eprintln!("Error: {}", e);
+        std::process::exit(1);
+    }
+}
+
+async fn serve(config: Config) -> Result<()> {
+    let storage = storage::open(config.storage, &config.data, config.backups)?;
+    let app = app::App::load(storage.as_ref())?;
+    app.save(storage.as_ref())?;
+    if app.get_users().is_empty() {
+        tracing::warn!(
+            "There are no accounts yet, anyone who can reach {} may change the tasks. Add one with tasks-todo user add <name>",
+            config.bind
+        );
     }
-    let routes = Router::new()
-        .route("/", get(index))
+    let state = AppState::new(app, storage, config.data.clone(), config.zone);
+    state.spawn_persister();
+    tracing::info!("Keeping data in {}", config.data.root().display());
+    let router_service = router(state.clone(), &config).into_make_service();
+    let server = axum::Server::try_bind(&config.bind)?.serve(router_service);
+    tracing::info!("Listening on http://{}", server.local_addr());
+    server
+        .with_graceful_shutdown(async {
+            tokio::signal::ctrl_c().await.ok();
+            state.close();
+        })
+        .await?;
+    // Make sure the last mutations hit the disk before exiting
+    state.flush().await
+}
+
+fn router(state: AppState, config: &Config) -> Router {
+    let public = Router::new()
         .route("/index.js", get(get_js))
         .route("/index.css", get(get_css))
         .route("/favicon.png", get(get_favicon))
-        .route("/tasks", get(get_tasks))
-        .route("/modifytask", post(modify_task))
-        .route("/addtask", post(add_task))
-        .route("/renametask", post(rename_task))
-        .route("/summaries/:key", get(get_summaries))
-        .route("/images/:key", get(get_images))
-        .route("/uploadimages", post(upload_images))
-        .layer(CorsLayer::permissive())
-        .layer(DefaultBodyLimit::max(1024 * 1024 * 1024));
-    let router_service = routes.into_make_service();
-    axum::Server::bind(&ip.parse()?)
-        .serve(router_service)
-        .await?;
-    Ok(())
+        .route("/login", get(auth::login_page).post(auth::login))
+        .route("/share/:secret", get(share::redirect))
+        .route("/share/:secret/", get(share::page))
+        .route("/share/:secret/images/:key", get(share::image));
+    api::router(routes())
+        .nest("/api/v1", api::router(api::routes()))
+        .route_layer(middleware::from_fn_with_state(
+            state.clone(),
+            auth::require_user,
+        ))
+        .merge(public)
+        .layer(config.cors())
+        .layer(DefaultBodyLimit::max(config.body_limit))
+        .with_state(state)
 }
 
-async fn get_tasks() -> Json<Vec<task::Task>> {
-    let state = App::load().await.unwrap();
-    Json(state.get_tasks().to_vec())
+/// Every route of the web UI that needs a login, next to `/api/v1`.
+fn routes() -> Vec<api::Route> {
+    vec![
+        api::route("/", Method::GET, index),
+        api::route("/logout", Method::POST, auth::logout),
+        api::route("/tasks", Method::GET, get_tasks),
+        api::route("/modifytask", Method::POST, modify_task),
+        api::route("/addtask", Method::POST, add_task),
+        api::route("/renametask", Method::POST, rename_task),
+        api::route("/deletetask", Method::POST, delete_task),
+        api::route("/archivetask", Method::POST, archive_task),
+        api::route("/summaries/:key", Method::GET, get_summaries),
+        api::route("/images/:key", Method::GET, get_images),
+        api::route("/uploadimages", Method::POST, upload_images),
+    ]
+}
+
+#[derive(Deserialize, Serialize, Debug, IntoParams)]
+struct TasksQuery {
+    /// Include archived tasks
+    #[serde(default)]
+    archived: bool,
+}
+
+#[utoipa::path(
+    get,
+    path = "/tasks",
+    tag = "legacy",
+    params(TasksQuery),
+    responses((status = 200, description = "All tasks", body = [Task], headers(("ETag" = String, description = "Changes whenever one of the tasks does"))))
+)]
+async fn get_tasks(
+    State(state): State<AppState>,
+    Query(query): Query<TasksQuery>,
+) -> (TypedHeader<ETag>, Json<Vec<Task>>) {
+    let state = state.read().await;
+    let tasks: Vec<Task> = state
+        .get_tasks()
+        .iter()
+        .filter(|t| query.archived || !t.is_archived())
+        .cloned()
+        .collect();
+    (api::list_etag(tasks.iter()), Json(tasks))
 }
 
-#[derive(Deserialize, Serialize)]
+#[derive(Deserialize, Serialize, ToSchema)]
 struct PostTask {
     id: u64,
     action: String,
     summary: Option<String>,
+    /// Why a complete task is reopened
+    reason: Option<String>,
+    /// Start a new session right after reopening
+    #[serde(default)]
+    start: bool,
 }
 
-async fn modify_task(body: Json<PostTask>) -> impl IntoResponse {
-    let mut state = App::load().await.unwrap();
-    let tasks = state.get_tasks();
-    let task = tasks
-        .iter()
-        .find(|t| t.get_id() == body.id)
-        .unwrap()
-        .get_id();
+#[utoipa::path(
+    post,
+    path = "/modifytask",
+    tag = "legacy",
+    params(("If-Match" = Option<String>, Header, description = "Only change the task if it is still at this revision")),
+    request_body = PostTask,
+    responses(
+        (status = 200, description = "Done"),
+        (status = 418, description = "Stopping needs the newline separated images uploaded first", body = String, content_type = "text/plain"),
+        (status = 400, description = "Malformed request", body = ErrorBody),
+        (status = 403, description = "Viewers can not change tasks", body = ErrorBody),
+        (status = 404, description = "No such task", body = ErrorBody),
+        (status = 409, description = "The task is not in a state that allows the action", body = ErrorBody),
+        (status = 412, description = "The task changed since the If-Match revision", body = ErrorBody)
+    )
+)]
+async fn modify_task(
+    State(state): State<AppState>,
+    user: Option<CurrentUser>,
+    precondition: IfMatch,
+    Json(body): Json<PostTask>,
+) -> Result<Response<String>> {
+    let by = user.as_ref().map(|CurrentUser(user)| user.as_str());
+    let data = state.data().clone();
+    let mut state = state.write().await;
+    state.check_role(by, Role::Member)?;
+    let task = body.id;
+    api::check_revision(state.get_task(task)?, &precondition)?;
     match body.action.as_str() {
-        "start" => {
-            state.start_task(task).unwrap();
-        }
+        "start" => state.start_task(task, by)?,
+        "pause" => state.pause_task(task, by)?,
+        "resume" => state.resume_task(task, by)?,
+        "reopen" => state.reopen_task(task, body.reason, body.start, by)?,
         "stop" => {
-            let images = state.stop_task(task, body.summary.clone()).await.unwrap();
-            if images.is_some() {
-                let images = images.unwrap();
-                return Response::builder()
+            let images = state.stop_task(task, body.summary, by, &data).await?;
+            if let Some(images) = images {
+                return Ok(Response::builder()
                     .status(StatusCode::IM_A_TEAPOT)
                     .body(
                         images
@@ -94,109 +237,230 @@ async fn modify_task(body: Json<PostTask>) -> impl IntoResponse {
                             .collect::<Vec<String>>()
                             .join("\n"),
                     )
-                    .unwrap();
+                    .unwrap());
             }
         }
-        _ => {}
+        action => return Err(Error::BadRequest(format!("Unknown action {}", action))),
     }
-    state.save().await.unwrap();
-    println!("{} {}ed", body.id, body.action);
-    Response::builder()
+    tracing::info!("{} {}ed", body.id, body.action);
+    Ok(Response::builder()
         .status(StatusCode::OK)
         .body("".to_string())
-        .unwrap()
+        .unwrap())
 }
 
-#[derive(Deserialize, Serialize, Debug)]
+#[derive(Deserialize, Serialize, Debug, ToSchema)]
 struct AddTask {
     name: String,
     parent: Option<u64>,
 }
 
-async fn add_task(body: Json<AddTask>) -> impl IntoResponse {
-    let mut state = App::load().await.unwrap();
-    let parent = body.parent;
+#[utoipa::path(
+    post,
+    path = "/addtask",
+    tag = "legacy",
+    request_body = AddTask,
+    responses((status = 200, description = "Added"), (status = 403, description = "Viewers can not add tasks", body = ErrorBody), (status = 404, description = "No such task", body = ErrorBody))
+)]
+async fn add_task(
+    State(state): State<AppState>,
+    user: Option<CurrentUser>,
+    Json(body): Json<AddTask>,
+) -> Result<Response<String>> {
+    let by = user.as_ref().map(|CurrentUser(user)| user.as_str());
+    let mut state = state.write().await;
+    state.check_role(by, Role::Member)?;
     let name = &body.name;
-    if parent.is_some() {
-        let parent = parent.unwrap();
-        state.add_subtask(parent, name.to_string()).unwrap();
+    if let Some(parent) = body.parent {
+        state.add_subtask(parent, name.to_string(), by)?;
     } else {
-        state.add_task(name.to_string()).unwrap();
+        state.add_task(name.to_string(), by)?;
     }
-    state.save().await.unwrap();
-    println!("Added task {}", name);
-    Response::builder()
+    tracing::info!("Added task {}", name);
+    Ok(Response::builder()
         .status(StatusCode::OK)
         .body("".to_string())
-        .unwrap()
+        .unwrap())
 }
 
-#[derive(Deserialize, Serialize, Debug)]
+#[derive(Deserialize, Serialize, Debug, ToSchema)]
 struct RenameTask {
     id: u64,
     name: String,
 }
 
-async fn rename_task(body: Json<RenameTask>) -> impl IntoResponse {
-    let mut state = App::load().await.unwrap();
+#[utoipa::path(
+    post,
+    path = "/renametask",
+    tag = "legacy",
+    params(("If-Match" = Option<String>, Header, description = "Only change the task if it is still at this revision")),
+    request_body = RenameTask,
+    responses((status = 200, description = "Renamed"), (status = 403, description = "Viewers can not rename tasks", body = ErrorBody), (status = 404, description = "No such task", body = ErrorBody), (status = 412, description = "The task changed since the If-Match revision", body = ErrorBody))
+)]
+async fn rename_task(
+    State(state): State<AppState>,
+    user: Option<CurrentUser>,
+    precondition: IfMatch,
+    Json(body): Json<RenameTask>,
+) -> Result<Response<String>> {
+    let mut state = state.write().await;
+    state.check_role(
+        user.as_ref().map(|CurrentUser(user)| user.as_str()),
+        Role::Member,
+    )?;
     let id = body.id;
     let name = &body.name;
-    state.rename_task(id, name.to_string()).unwrap();
-    state.save().await.unwrap();
-    println!("Renamed task {} to {}", id, name);
-    Response::builder()
+    api::check_revision(state.get_task(id)?, &precondition)?;
+    state.rename_task(id, name.to_string())?;
+    tracing::info!("Renamed task {} to {}", id, name);
+    Ok(Response::builder()
         .status(StatusCode::OK)
         .body("".to_string())
-        .unwrap()
+        .unwrap())
 }
 
-async fn get_summaries(axum::extract::Path(key): axum::extract::Path<String>) -> impl IntoResponse {
-    let file = async_fs::read_to_string(format!("summaries/{}", key)).await;
-    match file {
-        Ok(file) => {
-            let m = "text/html";
-            Response::builder()
-                .status(StatusCode::OK)
-                .header(
-                    header::CONTENT_TYPE,
-                    header::HeaderValue::from_str(m).unwrap(),
-                )
-                .body(file)
-                .unwrap()
-        }
-        Err(_) => {
-            println!("{}", key);
-            Response::builder()
-                .status(StatusCode::NOT_FOUND)
-                .body(format!("<h1>Error : 404,</h1><p>{} not fount</p>", key))
-                .unwrap()
-        }
+#[derive(Deserialize, Serialize, Debug, ToSchema)]
+struct DeleteTask {
+    id: u64,
+    children: app::DeleteMode,
+}
+
+#[utoipa::path(
+    post,
+    path = "/deletetask",
+    tag = "legacy",
+    params(("If-Match" = Option<String>, Header, description = "Only change the task if it is still at this revision")),
+    request_body = DeleteTask,
+    responses((status = 200, description = "Deleted"), (status = 403, description = "Only managers delete tasks", body = ErrorBody), (status = 404, description = "No such task", body = ErrorBody), (status = 412, description = "The task changed since the If-Match revision", body = ErrorBody))
+)]
+async fn delete_task(
+    State(state): State<AppState>,
+    user: Option<CurrentUser>,
+    precondition: IfMatch,
+    Json(body): Json<DeleteTask>,
+) -> Result<Response<String>> {
+    let removed = {
+        let mut state = state.write().await;
+        state.check_role(
+            user.as_ref().map(|CurrentUser(user)| user.as_str()),
+            Role::Manager,
+        )?;
+        api::check_revision(state.get_task(body.id)?, &precondition)?;
+        state.delete_task(body.id, body.children)?
+    };
+    for task in removed.iter() {
+        task.remove_files(state.data()).await;
+    }
+    tracing::info!("Deleted {} task(s) starting at {}", removed.len(), body.id);
+    Ok(Response::builder()
+        .status(StatusCode::OK)
+        .body("".to_string())
+        .unwrap())
+}
+
+#[derive(Deserialize, Serialize, Debug, ToSchema)]
+struct ArchiveTask {
+    id: u64,
+    archived: bool,
+}
+
+#[utoipa::path(
+    post,
+    path = "/archivetask",
+    tag = "legacy",
+    params(("If-Match" = Option<String>, Header, description = "Only change the task if it is still at this revision")),
+    request_body = ArchiveTask,
+    responses((status = 200, description = "Archived or restored"), (status = 403, description = "Viewers can not archive tasks", body = ErrorBody), (status = 404, description = "No such task", body = ErrorBody), (status = 412, description = "The task changed since the If-Match revision", body = ErrorBody))
+)]
+async fn archive_task(
+    State(state): State<AppState>,
+    user: Option<CurrentUser>,
+    precondition: IfMatch,
+    Json(body): Json<ArchiveTask>,
+) -> Result<Response<String>> {
+    let mut state = state.write().await;
+    state.check_role(
+        user.as_ref().map(|CurrentUser(user)| user.as_str()),
+        Role::Member,
+    )?;
+    api::check_revision(state.get_task(body.id)?, &precondition)?;
+    state.archive_task(body.id, body.archived)?;
+    if body.archived {
+        tracing::info!("Archived task {}", body.id);
+    } else {
+        tracing::info!("Unarchived task {}", body.id);
     }
+    Ok(Response::builder()
+        .status(StatusCode::OK)
+        .body("".to_string())
+        .unwrap())
+}
+
+#[utoipa::path(
+    get,
+    path = "/summaries/{key}",
+    tag = "legacy",
+    params(("key" = String, Path, description = "File name of the summary")),
+    responses(
+        (status = 200, description = "The summary rendered to html", body = String, content_type = "text/html"),
+        (status = 400, description = "The key is not a file name", body = ErrorBody),
+        (status = 404, description = "No such summary", body = ErrorBody)
+    )
+)]
+async fn get_summaries(
+    State(state): State<AppState>,
+    Path(key): Path<String>,
+) -> Result<Response<String>> {
+    let path = state.data().file("summaries", &key)?;
+    let file = async_fs::read_to_string(path)
+        .await
+        .map_err(|_| Error::NotFound(format!("Summary {} not found", key)))?;
+    let m = "text/html";
+    Ok(Response::builder()
+        .status(StatusCode::OK)
+        .header(
+            header::CONTENT_TYPE,
+            header::HeaderValue::from_str(m).unwrap(),
+        )
+        .body(file)
+        .unwrap())
 }
 
-async fn get_images(axum::extract::Path(key): axum::extract::Path<String>) -> impl IntoResponse {
+#[utoipa::path(
+    get,
+    path = "/images/{key}",
+    tag = "legacy",
+    params(("key" = String, Path, description = "File name of the image")),
+    responses(
+        (status = 200, description = "The image converted to png", content_type = "image/png"),
+        (status = 400, description = "The key is not a file name", body = ErrorBody),
+        (status = 404, description = "No such image", body = ErrorBody)
+    )
+)]
+async fn get_images(
+    State(state): State<AppState>,
+    Path(key): Path<String>,
+) -> Result<Response<Body>> {
     use image::io::Reader as ImageReader;
     use std::io::Cursor;
-    let img = ImageReader::open(format!("images/{}", key))
-        .unwrap()
-        .decode()
-        .unwrap();
+    let img = ImageReader::open(state.data().file("images", &key)?)
+        .map_err(|_| Error::NotFound(format!("Image {} not found", key)))?
+        .decode()?;
     let mut bytes = Vec::new();
-    img.write_to(&mut Cursor::new(&mut bytes), image::ImageOutputFormat::Png)
-        .unwrap();
+    img.write_to(&mut Cursor::new(&mut bytes), image::ImageOutputFormat::Png)?;
     let bytes = Body::from(bytes);
     let m = "image/png";
-    Response::builder()
+    Ok(Response::builder()
         .status(StatusCode::OK)
         .header(
             header::CONTENT_TYPE,
             header::HeaderValue::from_str(m).unwrap(),
         )
         .body(bytes)
-        .unwrap()
+        .unwrap())
 }
 
-#[derive(Deserialize, Serialize, Debug)]
+#[derive(Deserialize, Serialize, Debug, ToSchema)]
 struct UploadImages {
     id: u64,
     name: String,
@@ -204,85 +468,55 @@ struct UploadImages {
     extension: String,
 }
 
-async fn upload_images(body: Json<Vec<UploadImages>>) -> impl IntoResponse {
-    if !Path::new("images").exists() {
-        async_fs::create_dir("images").await.unwrap();
-    }
-    for image in body.iter() {
-        let data = &image.data;
-        //let data = base64::decode(data).unwrap();
-        let data = data.split(',').nth(1).unwrap();
-        // Decode Base64 data
-        use base64::{engine::general_purpose, Engine as _};
-
-        let bytes = general_purpose::STANDARD.decode(data).unwrap();
-        let name = format!("images/{}_{}.{}", image.id, image.name, image.extension);
-
-        async_fs::write(name, bytes).await.unwrap();
-    }
-    // Open the temp summary and replace the links
-    let contents = async_fs::read_to_string(format!("temp/{}.md", body[0].id))
-        .await
-        .unwrap();
-
-    // Find ![name](link) and replace link
-    let mut new_contents = String::new();
-
-    for line in contents.lines() {
-        if line.starts_with("![") {
-            // Match the name
-            let start = line.find('[').unwrap() + 1;
-            let end = line.find(']').unwrap();
-            let name = &line[start..end];
-            // Get the image
-            for image in body.iter() {
-                if image.name == name {
-                    // Replace the link with the new one
-                    let mut line = line.to_string();
-                    let start = line.find('(').unwrap() + 1;
-                    let end = line.find(')').unwrap();
-                    let new_link =
-                        format!("images/{}_{}.{}", image.id, image.name, image.extension);
-                    line.replace_range(start..end, &new_link);
-                    new_contents.push_str(&line);
-                    new_contents.push('\n');
-                    break;
-                }
-            }
-        } else {
-            new_contents.push_str(line);
-            new_contents.push('\n');
-        }
-    }
-
-    let mut state = App::load().await.unwrap();
-    state
-        .stop_task(body[0].id, Some(new_contents))
-        .await
-        .unwrap();
-    state.save().await.unwrap();
-    // Delete the temp summary
-    std::mem::drop(contents);
-    async_fs::remove_file(format!("temp/{}.md", body[0].id))
-        .await
-        .unwrap();
-    Response::builder()
+#[utoipa::path(
+    post,
+    path = "/uploadimages",
+    tag = "legacy",
+    params(("If-Match" = Option<String>, Header, description = "Only change the task if it is still at this revision")),
+    request_body = [UploadImages],
+    responses((status = 200, description = "Uploaded"), (status = 400, description = "Malformed request", body = ErrorBody), (status = 403, description = "Viewers can not upload", body = ErrorBody), (status = 404, description = "No such task", body = ErrorBody), (status = 412, description = "The task changed since the If-Match revision", body = ErrorBody))
+)]
+async fn upload_images(
+    State(state): State<AppState>,
+    user: Option<CurrentUser>,
+    precondition: IfMatch,
+    Json(body): Json<Vec<UploadImages>>,
+) -> Result<Response<String>> {
+    let by = user.as_ref().map(|CurrentUser(user)| user.as_str());
+    state.read().await.check_role(by, Role::Member)?;
+    let id = body
+        .first()
+        .ok_or_else(|| Error::BadRequest("No images uploaded".to_string()))?
+        .id;
+    let uploads = body
+        .into_iter()
+        .map(|image| api::Upload {
+            name: image.name,
+            data: image.data,
+            extension: image.extension,
+        })
+        .collect::<Vec<_>>();
+    api::attach_uploads(&state, id, by, &precondition, &uploads).await?;
+    Ok(Response::builder()
         .status(StatusCode::OK)
         .body("".to_string())
-        .unwrap()
+        .unwrap())
 }
 
-async fn index() -> Html<String> {
+/// The page, telling the script who is logged in.
+async fn index(user: Option<CurrentUser>) -> Html<String> {
     let file = include_str!("../static/index.html");
-    Html(file.to_string())
+    let user = serde_json::to_string(&user.map(|CurrentUser(user)| user)).unwrap();
+    let script = format!("<head>\n\t<script>let global_user = {};</script>", user);
+    Html(file.replacen("<head>", &script, 1))
 }
 
-async fn get_js() -> impl IntoResponse {
+async fn get_js(State(state): State<AppState>) -> impl IntoResponse {
     let m = "text/javascript";
     let content = include_str!("../static/index.js");
     let mut result = String::new();
-    result.push_str("let global_ip = \"");
-    result.push_str(&env::args().collect::<Vec<String>>()[1]);
+    result.push_str("let global_tz = \"");
+    result.push_str(state.zone().name());
     result.push_str("\";");
     result.push_str(content);
     Response::builder()
@@ -320,3 +554,582 @@ async fn get_favicon() -> impl IntoResponse {
         .body(body::boxed(Full::from(body)))
         .unwrap()
 }
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use axum::http::Request;
+    use data_dir::DataDir;
+    use std::sync::Arc;
+    use tower::ServiceExt;
+
+    /// A router over an in-memory store holding a single task with id 0.
+    pub(crate) fn test_router() -> Router {
+        let storage = Arc::new(storage::SqliteStorage::open(":memory:").unwrap());
+        let mut app = app::App::new();
+        app.add_task("Write report".to_string(), None).unwrap();
+        router(
+            AppState::new(app, storage, DataDir::temp(), zone::Zone::utc()),
+            &Config::default(),
+        )
+    }
+
+    pub(crate) async fn request(
+        router: &Router,
+        method: &str,
+        uri: &str,
+        body: serde_json::Value,
+    ) -> (StatusCode, serde_json::Value) {
+        let (status, _, body) = request_with_headers(router, method, uri, &[], body).await;
+        (status, body)
+    }
+
+    pub(crate) async fn request_with_headers(
+        router: &Router,
+        method: &str,
+        uri: &str,
+        headers: &[(&str, &str)],
+        body: serde_json::Value,
+    ) -> (StatusCode, axum::http::HeaderMap, serde_json::Value) {
+        let mut request = Request::builder()
+            .method(method)
+            .uri(uri)
+            .header(header::CONTENT_TYPE, "application/json");
+        for (name, value) in headers {
+            request = request.header(*name, *value);
+        }
+        let request = request.body(Body::from(body.to_string())).unwrap();
+        let response = router.clone().oneshot(request).await.unwrap();
+        let status = response.status();
+        let headers = response.headers().clone();
+        let bytes = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let body = serde_json::from_slice(&bytes).unwrap_or(serde_json::Value::Null);
+        (status, headers, body)
+    }
+
+    /// The status and body of a response that is not json.
+    pub(crate) async fn request_text(router: &Router, uri: &str) -> (StatusCode, String) {
+        let request = Request::builder().uri(uri).body(Body::empty()).unwrap();
+        let response = router.clone().oneshot(request).await.unwrap();
+        let status = response.status();
+        let bytes = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        (status, String::from_utf8(bytes.to_vec()).unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_legacy_list_etag() {
+        let router = test_router();
+        let null = serde_json::Value::Null;
+        let (_, legacy, _) =
+            request_with_headers(&router, "GET", "/tasks", &[], null.clone()).await;
+        let (_, api, _) =
+            request_with_headers(&router, "GET", "/api/v1/tasks", &[], null.clone()).await;
+        assert_eq!(legacy["etag"], api["etag"]);
+        request(
+            &router,
+            "POST",
+            "/renametask",
+            serde_json::json!({ "id": 0, "name": "x" }),
+        )
+        .await;
+        let (_, renamed, _) = request_with_headers(&router, "GET", "/tasks", &[], null).await;
+        assert_ne!(renamed["etag"], legacy["etag"]);
+    }
+
+    #[tokio::test]
+    async fn test_missing_task() {
+        use serde_json::json;
+        let requests = [
+            ("/modifytask", json!({ "id": 7, "action": "start" })),
+            ("/renametask", json!({ "id": 7, "name": "Renamed" })),
+            ("/deletetask", json!({ "id": 7, "children": "cascade" })),
+            ("/archivetask", json!({ "id": 7, "archived": true })),
+            ("/addtask", json!({ "name": "Orphan", "parent": 7 })),
+        ];
+        for (uri, body) in requests {
+            let (status, body) = request(&test_router(), "POST", uri, body).await;
+            assert_eq!(status, StatusCode::NOT_FOUND, "{}", uri);
+            assert_eq!(body["error"], "task_not_found", "{}", uri);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_bad_requests() {
+        use serde_json::json;
+        let (status, body) = request(
+            &test_router(),
+            "POST",
+            "/modifytask",
+            json!({ "id": 0, "action": "finish" }),
+        )
+        .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["error"], "bad_request");
+
+        // Malformed bodies get the same json error as everything else
+        let (status, body) = request(
+            &test_router(),
+            "POST",
+            "/renametask",
+            json!({ "id": "zero" }),
+        )
+        .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["error"], "bad_request");
+
+        let (status, _) = request(&test_router(), "POST", "/uploadimages", json!([])).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        let image = json!({ "id": 0, "name": "plot", "data": "data:image/png;base64,%%%", "extension": "png" });
+        let (status, body) = request(&test_router(), "POST", "/uploadimages", json!([image])).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["error"], "bad_request");
+    }
+
+    #[tokio::test]
+    async fn test_invalid_state() {
+        let (status, body) = request(
+            &test_router(),
+            "POST",
+            "/modifytask",
+            serde_json::json!({ "id": 0, "action": "pause" }),
+        )
+        .await;
+        assert_eq!(status, StatusCode::CONFLICT);
+        assert_eq!(body["error"], "invalid_state");
+    }
+
+    #[tokio::test]
+    async fn test_missing_files() {
+        let (status, body) = request(
+            &test_router(),
+            "GET",
+            "/summaries/missing.html",
+            serde_json::Value::Null,
+        )
+        .await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(body["error"], "not_found");
+        let (status, _) = request(
+            &test_router(),
+            "GET",
+            "/images/missing.png",
+            serde_json::Value::Null,
+        )
+        .await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_path_traversal() {
+        let router = test_router();
+        for uri in [
+            "/summaries/..%2Fdata.json",
+            "/summaries/..",
+            "/images/%2Fetc%2Fpasswd",
+            "/images/..%2F..%2Fetc%2Fpasswd",
+        ] {
+            let (status, body) = request(&router, "GET", uri, serde_json::Value::Null).await;
+            assert_eq!(status, StatusCode::BAD_REQUEST, "{}", uri);
+            assert_eq!(body["error"], "bad_request");
+        }
+
+        for extension in ["png/../../../evil", "/etc/passwd", "sh"] {
+            let image = serde_json::json!({ "id": 0, "name": "plot", "data": "data:image/png;base64,aGk=", "extension": extension });
+            let (status, _) =
+                request(&router, "POST", "/uploadimages", serde_json::json!([image])).await;
+            assert_eq!(status, StatusCode::BAD_REQUEST, "{}", extension);
+        }
+
+        // Names only identify the image, they never end up in a path
+        for name in ["../../evil", "/etc/passwd"] {
+            let image = serde_json::json!({ "name": name, "data": "data:image/png;base64,aGk=", "extension": "PNG" });
+            let (status, body) = request(
+                &router,
+                "POST",
+                "/api/v1/tasks/0/attachments",
+                serde_json::json!([image]),
+            )
+            .await;
+            assert_eq!(status, StatusCode::CREATED);
+            let path = body["images"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .find(|image| image["name"] == name)
+                .unwrap()["path"]
+                .as_str()
+                .unwrap()
+                .to_string();
+            assert!(
+                path.starts_with("images/0_") && path.ends_with(".png"),
+                "{}",
+                path
+            );
+            assert_eq!(path.matches('/').count(), 1, "{}", path);
+            assert!(!path.contains(".."), "{}", path);
+        }
+    }
+
+    /// Logs in through the form, returning the response headers.
+    async fn login(router: &Router, form: &str) -> axum::http::HeaderMap {
+        let request = Request::builder()
+            .method("POST")
+            .uri("/login")
+            .header(header::CONTENT_TYPE, "application/x-www-form-urlencoded")
+            .body(Body::from(form.to_string()))
+            .unwrap();
+        let response = router.clone().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::SEE_OTHER);
+        response.headers().clone()
+    }
+
+    #[tokio::test]
+    async fn test_auth() {
+        use serde_json::{json, Value};
+        let storage = Arc::new(storage::SqliteStorage::open(":memory:").unwrap());
+        let mut app = app::App::new();
+        app.add_task("Write report".to_string(), None).unwrap();
+        app.add_user(user::User::new("ada".to_string(), "secret", Role::Member).unwrap())
+            .unwrap();
+        let router = router(
+            AppState::new(app, storage, DataDir::temp(), zone::Zone::utc()),
+            &Config::default(),
+        );
+
+        for uri in ["/api/v1/tasks", "/tasks", "/summaries/0.html"] {
+            let (status, body) = request(&router, "GET", uri, Value::Null).await;
+            assert_eq!(status, StatusCode::UNAUTHORIZED, "{}", uri);
+            assert_eq!(body["error"], "unauthorized");
+        }
+        let (status, _) = request(&router, "POST", "/addtask", json!({ "name": "x" })).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        let (status, headers, _) =
+            request_with_headers(&router, "GET", "/", &[("accept", "text/html")], Value::Null)
+                .await;
+        assert_eq!(status, StatusCode::SEE_OTHER);
+        assert_eq!(headers[header::LOCATION], "/login");
+        for uri in ["/login", "/index.css", "/index.js", "/favicon.png"] {
+            let (status, _) = request(&router, "GET", uri, Value::Null).await;
+            assert_eq!(status, StatusCode::OK, "{}", uri);
+        }
+
+        let headers = login(&router, "name=ada&password=wrong").await;
+        assert_eq!(headers[header::LOCATION], "/login?failed");
+        assert!(!headers.contains_key(header::SET_COOKIE));
+        let headers = login(&router, "name=bob&password=secret").await;
+        assert_eq!(headers[header::LOCATION], "/login?failed");
+
+        let headers = login(&router, "name=ada&password=secret").await;
+        assert_eq!(headers[header::LOCATION], "/");
+        let set_cookie = headers[header::SET_COOKIE].to_str().unwrap();
+        assert!(set_cookie.contains("HttpOnly"));
+        let cookie = set_cookie.split(';').next().unwrap().to_string();
+        let session = [("cookie", cookie.as_str())];
+        let (status, _, body) =
+            request_with_headers(&router, "GET", "/api/v1/tasks", &session, Value::Null).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body[0]["name"], "Write report");
+
+        // Tokens for scripts
+        let (status, _, body) = request_with_headers(
+            &router,
+            "POST",
+            "/api/v1/tokens",
+            &session,
+            json!({ "name": "ci" }),
+        )
+        .await;
+        assert_eq!(status, StatusCode::CREATED);
+        let bearer = format!("Bearer {}", body["token"].as_str().unwrap());
+        let token = [("authorization", bearer.as_str())];
+        let (status, _, body) =
+            request_with_headers(&router, "GET", "/api/v1/tokens", &token, Value::Null).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body[0]["name"], "ci");
+        assert!(body[0].get("hash").is_none());
+        let (status, _, _) =
+            request_with_headers(&router, "DELETE", "/api/v1/tokens/ci", &token, Value::Null).await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        let (status, _, _) =
+            request_with_headers(&router, "GET", "/api/v1/tasks", &token, Value::Null).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+        let (status, _, _) =
+            request_with_headers(&router, "POST", "/logout", &session, Value::Null).await;
+        assert_eq!(status, StatusCode::SEE_OTHER);
+        let (status, _, _) =
+            request_with_headers(&router, "GET", "/api/v1/tasks", &session, Value::Null).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn test_my_tasks() {
+        use serde_json::{json, Value};
+        let storage = Arc::new(storage::SqliteStorage::open(":memory:").unwrap());
+        let mut app = app::App::new();
+        for name in ["ada", "bob"] {
+            app.add_user(user::User::new(name.to_string(), "secret", Role::Member).unwrap())
+                .unwrap();
+            app.add_token(name, "ci".to_string(), user::hash_secret(name))
+                .unwrap();
+        }
+        let router = router(
+            AppState::new(app, storage, DataDir::temp(), zone::Zone::utc()),
+            &Config::default(),
+        );
+        let ada = [("authorization", "Bearer ada")];
+        let bob = [("authorization", "Bearer bob")];
+        let post =
+            |as_user, body| request_with_headers(&router, "POST", "/api/v1/tasks", as_user, body);
+
+        let (_, _, body) = post(&ada, json!({ "name": "Own" })).await;
+        assert_eq!(body["creator"], "ada");
+        assert_eq!(body["assignee"], Value::Null);
+        let (_, _, body) = post(&ada, json!({ "name": "For bob", "assignee": "bob" })).await;
+        assert_eq!(body["assignee"], "bob");
+        let (status, _, _) = post(&ada, json!({ "name": "x", "assignee": "eve" })).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        post(&bob, json!({ "name": "Bob's" })).await;
+
+        let names = |body: Value| -> Vec<String> {
+            body.as_array()
+                .unwrap()
+                .iter()
+                .map(|t| t["name"].as_str().unwrap().to_string())
+                .collect()
+        };
+        let get = |as_user, uri| request_with_headers(&router, "GET", uri, as_user, Value::Null);
+        let (_, _, body) = get(&ada, "/api/v1/tasks?mine=true").await;
+        assert_eq!(names(body), ["Own"]);
+        let (_, _, body) = get(&bob, "/api/v1/tasks?mine=true").await;
+        assert_eq!(names(body), ["For bob", "Bob's"]);
+        let (_, _, body) = get(&bob, "/api/v1/tasks?creator=ada").await;
+        assert_eq!(names(body), ["Own", "For bob"]);
+        let (_, _, body) = get(&bob, "/api/v1/tasks?assignee=bob").await;
+        assert_eq!(names(body), ["For bob"]);
+
+        // Both work on the same task, each in their own session
+        let (status, _, _) = request_with_headers(
+            &router,
+            "POST",
+            "/api/v1/tasks/1/sessions",
+            &ada,
+            Value::Null,
+        )
+        .await;
+        assert_eq!(status, StatusCode::CREATED);
+        let (status, _, body) = request_with_headers(
+            &router,
+            "POST",
+            "/api/v1/tasks/1/sessions",
+            &bob,
+            Value::Null,
+        )
+        .await;
+        assert_eq!(status, StatusCode::CREATED);
+        assert_eq!(body["user"], "bob");
+        let (status, _, _) = request_with_headers(
+            &router,
+            "POST",
+            "/api/v1/tasks/1/sessions",
+            &bob,
+            Value::Null,
+        )
+        .await;
+        assert_eq!(status, StatusCode::CONFLICT);
+        let (status, _, _) = request_with_headers(
+            &router,
+            "DELETE",
+            "/api/v1/tasks/1/sessions/current",
+            &ada,
+            Value::Null,
+        )
+        .await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        let (_, _, body) = get(&ada, "/api/v1/tasks/1").await;
+        assert_eq!(body["status"], "InProgress");
+
+        let (status, _, body) = request_with_headers(
+            &router,
+            "PATCH",
+            "/api/v1/tasks/1",
+            &bob,
+            json!({ "assignee": null }),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["assignee"], Value::Null);
+    }
+
+    const VIC: [(&str, &str); 1] = [("authorization", "Bearer vic")];
+    const MEG: [(&str, &str); 1] = [("authorization", "Bearer meg")];
+    const MAX: [(&str, &str); 1] = [("authorization", "Bearer max")];
+
+    /// A release with its changelog and an unrelated task, worked on by a
+    /// viewer, a member and a manager who log in with `VIC`, `MEG` and `MAX`.
+    fn team_router() -> Router {
+        let storage = Arc::new(storage::SqliteStorage::open(":memory:").unwrap());
+        let mut app = app::App::new();
+        app.add_task("Release".to_string(), None).unwrap();
+        app.add_subtask(0, "Changelog <draft>".to_string(), None)
+            .unwrap();
+        app.add_task("Secret plans".to_string(), None).unwrap();
+        for (name, role) in [
+            ("vic", Role::Viewer),
+            ("meg", Role::Member),
+            ("max", Role::Manager),
+        ] {
+            app.add_user(user::User::new(name.to_string(), "secret", role).unwrap())
+                .unwrap();
+            app.add_token(name, "ci".to_string(), user::hash_secret(name))
+                .unwrap();
+        }
+        router(
+            AppState::new(app, storage, DataDir::temp(), zone::Zone::utc()),
+            &Config::default(),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_viewers_only_read() {
+        use serde_json::{json, Value};
+        let router = team_router();
+        let (status, _, _) =
+            request_with_headers(&router, "GET", "/api/v1/tasks", &VIC, Value::Null).await;
+        assert_eq!(status, StatusCode::OK);
+        let new_task = json!({ "name": "x" });
+        let (status, _, body) =
+            request_with_headers(&router, "POST", "/api/v1/tasks", &VIC, new_task.clone()).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        assert_eq!(body["error"], "forbidden");
+        let (status, _, _) =
+            request_with_headers(&router, "POST", "/addtask", &VIC, new_task.clone()).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        let (status, _, _) =
+            request_with_headers(&router, "POST", "/api/v1/tasks", &MEG, new_task).await;
+        assert_eq!(status, StatusCode::CREATED);
+    }
+
+    #[tokio::test]
+    async fn test_managers_reassign_and_delete() {
+        use serde_json::{json, Value};
+        let router = team_router();
+        // Members take tasks, managers reassign them
+        let assign = |to: &str| json!({ "assignee": to });
+        let patch = "/api/v1/tasks/2";
+        let (status, _, _) =
+            request_with_headers(&router, "PATCH", patch, &MEG, assign("meg")).await;
+        assert_eq!(status, StatusCode::OK);
+        let (status, _, _) =
+            request_with_headers(&router, "PATCH", patch, &MEG, assign("max")).await;
+        assert_eq!(status, StatusCode::OK);
+        let (status, _, _) =
+            request_with_headers(&router, "PATCH", patch, &MEG, assign("meg")).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        let (status, _, _) =
+            request_with_headers(&router, "PATCH", patch, &MAX, assign("meg")).await;
+        assert_eq!(status, StatusCode::OK);
+
+        let (status, _, _) =
+            request_with_headers(&router, "DELETE", patch, &MEG, Value::Null).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        let (status, _, _) =
+            request_with_headers(&router, "DELETE", patch, &MAX, Value::Null).await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+    }
+
+    #[tokio::test]
+    async fn test_share_links() {
+        use serde_json::{json, Value};
+        let router = team_router();
+        // A read-only link to the release and its changelog
+        let summary = json!({ "status": "Complete", "summary": "# Shipped it" });
+        request_with_headers(&router, "PATCH", "/api/v1/tasks/1", &MEG, summary).await;
+        let share = json!({ "task_id": 0 });
+        let (status, _, _) =
+            request_with_headers(&router, "POST", "/api/v1/shares", &VIC, share.clone()).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        let (status, _, body) =
+            request_with_headers(&router, "POST", "/api/v1/shares", &MEG, share).await;
+        assert_eq!(status, StatusCode::CREATED);
+        let url = body["url"].as_str().unwrap();
+        let id = body["id"].as_str().unwrap();
+        let (status, html) = request_text(&router, url).await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(html.contains("Release"));
+        assert!(html.contains("Changelog &lt;draft&gt;"));
+        assert!(html.contains("<h1>Shipped it</h1>"));
+        assert!(!html.contains("Secret plans"));
+        let (status, _) = request_text(&router, url.trim_end_matches('/')).await;
+        assert_eq!(status, StatusCode::PERMANENT_REDIRECT);
+        let (status, _) = request_text(&router, "/share/guessed/").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        let revoke = format!("/api/v1/shares/{}", id);
+        let (status, _, _) =
+            request_with_headers(&router, "DELETE", &revoke, &VIC, Value::Null).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        let (status, _, _) =
+            request_with_headers(&router, "DELETE", &revoke, &MEG, Value::Null).await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        let (status, _) = request_text(&router, url).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_summary_revision_authors() {
+        use serde_json::{json, Value};
+        let router = team_router();
+        // Every edit of the changelog is kept with its author
+        let summary = json!({ "status": "Complete", "summary": "# Shipped it" });
+        request_with_headers(&router, "PATCH", "/api/v1/tasks/1", &MEG, summary).await;
+        let edit = json!({ "markdown": "# Shipped it\nOn time" });
+        let summary = "/api/v1/tasks/1/summary";
+        request_with_headers(&router, "PUT", summary, &MAX, edit).await;
+        let restore = "/api/v1/tasks/1/summary/revisions/1/restore";
+        let (status, _, _) =
+            request_with_headers(&router, "POST", restore, &VIC, Value::Null).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        let (status, _, task) =
+            request_with_headers(&router, "POST", restore, &MAX, Value::Null).await;
+        assert_eq!(status, StatusCode::OK);
+        let revisions = task["summary_revisions"].as_array().unwrap();
+        let authors: Vec<&str> = revisions
+            .iter()
+            .map(|revision| revision["author"].as_str().unwrap())
+            .collect();
+        assert_eq!(authors, ["meg", "max", "max"]);
+        assert_eq!(revisions[2]["restored"], 1);
+    }
+
+    #[tokio::test]
+    async fn test_body_limit() {
+        let storage = Arc::new(storage::SqliteStorage::open(":memory:").unwrap());
+        let config = Config {
+            body_limit: 64,
+            ..Config::default()
+        };
+        let router = router(
+            AppState::new(app::App::new(), storage, DataDir::temp(), zone::Zone::utc()),
+            &config,
+        );
+        let name = "x".repeat(100);
+        let (status, body) = request(
+            &router,
+            "POST",
+            "/api/v1/tasks",
+            serde_json::json!({ "name": name }),
+        )
+        .await;
+        assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+        assert_eq!(body["error"], "too_large");
+        let (status, _) = request(
+            &router,
+            "POST",
+            "/api/v1/tasks",
+            serde_json::json!({ "name": "x" }),
+        )
+        .await;
+        assert_eq!(status, StatusCode::CREATED);
+    }
+}
@@ -0,0 +1,185 @@
+//! Read-only links to a task and its subtasks for people without an account.
+//!
+//! Whoever has the link sees the tasks and their summaries until the link is
+//! revoked. Like API tokens, only the hash of the secret in the link is
+//! stored.
+
+use std::collections::{HashMap, HashSet};
+
+use axum::{
+    body::Body,
+    extract::State,
+    http::{header, Response, StatusCode},
+    response::{Html, Redirect},
+};
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use crate::{
+    cli,
+    error::{Error, Result},
+    extract::Path,
+    state::AppState,
+    task::{Task, TaskStaus},
+    time::Time,
+    user,
+};
+
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
+pub struct Share {
+    pub(crate) hash: String,
+    /// The root of the shared subtree
+    pub(crate) task_id: u64,
+    pub(crate) created_by: Option<String>,
+    pub(crate) created: Time,
+}
+
+/// What the API tells about a share link.
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct ShareInfo {
+    /// Identifies the link to revoke it, it does not open it
+    pub id: String,
+    pub task_id: u64,
+    pub created_by: Option<String>,
+    pub created: Time,
+}
+
+impl Share {
+    /// The start of the hash, enough to tell links apart.
+    pub fn id(&self) -> &str {
+        &self.hash[..12]
+    }
+
+    pub fn info(&self) -> ShareInfo {
+        ShareInfo {
+            id: self.id().to_string(),
+            task_id: self.task_id,
+            created_by: self.created_by.clone(),
+            created: self.created,
+        }
+    }
+}
+
+/// Where the link with the secret points to.
+pub fn url(secret: &str) -> String {
+    format!("/share/{}/", secret)
+}
+
+/// The shared tasks, starting with the root of the subtree.
+async fn shared_tasks(state: &AppState, secret: &str) -> Result<Vec<Task>> {
+    let app = state.read().await;
+    let share = app
+        .find_share(&user::hash_secret(secret))
+        .ok_or_else(|| Error::NotFound("This link does not exist or was revoked".to_string()))?;
+    let ids: HashSet<u64> = app.subtree_ids(share.task_id).into_iter().collect();
+    Ok(app
+        .get_tasks()
+        .iter()
+        .filter(|task| ids.contains(&task.get_id()))
+        .cloned()
+        .collect())
+}
+
+/// Links are handed out with a trailing slash so relative image links in the
+/// summaries resolve below them, add it when it got lost.
+pub async fn redirect(Path(secret): Path<String>) -> Redirect {
+    Redirect::permanent(&url(&secret))
+}
+
+/// The subtree with the summaries inlined.
+pub async fn page(
+    State(state): State<AppState>,
+    Path(secret): Path<String>,
+) -> Result<Html<String>> {
+    let tasks = shared_tasks(&state, &secret).await?;
+    let mut summaries = HashMap::new();
+    for task in tasks.iter() {
+        let Some(path) = task.get_summary() else {
+            continue;
+        };
+        match async_fs::read_to_string(state.data().resolve(path)?).await {
+            Ok(summary) => {
+                summaries.insert(task.get_id(), summary);
+            }
+            Err(e) => tracing::warn!("Failed to read {}: {}", path, e),
+        }
+    }
+
+    let mut html = String::new();
+    for (depth, task) in cli::walk_tree(&tasks, &HashSet::new()) {
+        html.push_str(&format!(
+            "<li class=\"task\" style=\"margin-left: {}em\">\n",
+            depth * 2
+        ));
+        html.push_str(&format!(
+            "<div class=\"task-title\">{} {}</div>\n",
+            icon(task.get_status()),
+            escape(task.get_name())
+        ));
+        html.push_str(&format!(
+            "<div class=\"tracked-time\">{}, tracked {}</div>\n",
+            task.get_status(),
+            task.tracked_time()
+        ));
+        if let Some(assignee) = task.get_assignee() {
+            html.push_str(&format!(
+                "<div class=\"assignee\">@{}</div>\n",
+                escape(assignee)
+            ));
+        }
+        if let Some(summary) = summaries.get(&task.get_id()) {
+            html.push_str(&format!("<div class=\"summary\">{}</div>\n", summary));
+        }
+        html.push_str("</li>\n");
+    }
+    let title = escape(tasks.first().map(Task::get_name).unwrap_or_default());
+    // Only the template gets filled in, never the tasks
+    let (head, tail) = include_str!("../static/share.html")
+        .split_once("{tasks}")
+        .expect("the share template has a place for the tasks");
+    let head = head
+        .replace("{base}", &url(&secret))
+        .replace("{title}", &title);
+    Ok(Html(head + &html + tail))
+}
+
+/// An image linked from one of the shared summaries.
+pub async fn image(
+    State(state): State<AppState>,
+    Path((secret, key)): Path<(String, String)>,
+) -> Result<Response<Body>> {
+    let tasks = shared_tasks(&state, &secret).await?;
+    let path = format!("images/{}", key);
+    if !tasks
+        .iter()
+        .any(|task| task.get_images().iter().any(|image| image.path == path))
+    {
+        return Err(Error::NotFound(format!("Image {} not found", key)));
+    }
+    let bytes = async_fs::read(state.data().file("images", &key)?)
+        .await
+        .map_err(|_| Error::NotFound(format!("Image {} not found", key)))?;
+    let format = image::guess_format(&bytes)?;
+    Ok(Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, format.to_mime_type())
+        .body(Body::from(bytes))
+        .unwrap())
+}
+
+/// Same icons as the web UI.
+fn icon(status: &TaskStaus) -> &'static str {
+    match status {
+        TaskStaus::Incomplete => "🔴",
+        TaskStaus::InProgress => "🟡",
+        TaskStaus::Paused => "⏸",
+        TaskStaus::Complete => "🟢",
+    }
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
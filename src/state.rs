@@ -0,0 +1,243 @@
+use std::{
+    ops::{Deref, DerefMut},
+    sync::Arc,
+};
+
+use tokio::sync::{broadcast, watch, Mutex, Notify, RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+use crate::{
+    app::App, auth::Sessions, data_dir::DataDir, error::Result, event::Event, storage::Storage,
+    zone::Zone,
+};
+
+/// The single in-memory copy of the task database shared by every handler.
+///
+/// Reads take a shared lock, mutations take the exclusive lock so they are
+/// serialized. Persisting to disk happens behind the scenes: a write guard that
+/// changed the app marks the state dirty when it is dropped and a background
+/// task saves a snapshot of the app.
+///
+/// Dropping a write guard also broadcasts the events recorded by the app to
+/// every subscriber.
+#[derive(Clone)]
+pub struct AppState {
+    inner: Arc<Shared>,
+}
+
+struct Shared {
+    app: RwLock<App>,
+    storage: Arc<dyn Storage>,
+    data: DataDir,
+    zone: Zone,
+    sessions: Sessions,
+    dirty: Notify,
+    saving: Mutex<()>,
+    events: broadcast::Sender<Event>,
+    closing: watch::Sender<bool>,
+}
+
+/// How many events a slow subscriber may fall behind before it misses some.
+const EVENT_BUFFER: usize = 256;
+
+impl AppState {
+    pub fn new(mut app: App, storage: Arc<dyn Storage>, data: DataDir, zone: Zone) -> AppState {
+        // Nobody can be subscribed to changes made before now
+        app.take_changed();
+        app.take_events();
+        AppState {
+            inner: Arc::new(Shared {
+                app: RwLock::new(app),
+                storage,
+                data,
+                zone,
+                sessions: Sessions::default(),
+                dirty: Notify::new(),
+                saving: Mutex::new(()),
+                events: broadcast::channel(EVENT_BUFFER).0,
+                closing: watch::channel(false).0,
+            }),
+        }
+    }
+
+    pub async fn read(&self) -> RwLockReadGuard<'_, App> {
+        self.inner.app.read().await
+    }
+
+    /// Where summaries and images are kept.
+    pub fn data(&self) -> &DataDir {
+        &self.inner.data
+    }
+
+    /// The time zone timestamps are displayed in.
+    pub fn zone(&self) -> Zone {
+        self.inner.zone
+    }
+
+    /// Who is logged in to the web UI.
+    pub fn sessions(&self) -> &Sessions {
+        &self.inner.sessions
+    }
+
+    pub async fn write(&self) -> AppWriteGuard<'_> {
+        AppWriteGuard {
+            guard: self.inner.app.write().await,
+            dirty: &self.inner.dirty,
+            events: &self.inner.events,
+        }
+    }
+
+    /// Receives every event from now on.
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.inner.events.subscribe()
+    }
+
+    /// Tells long running responses like the event stream to finish, the
+    /// server only shuts down once they did.
+    pub fn close(&self) {
+        self.inner.closing.send_replace(true);
+    }
+
+    /// Resolves once `close` was called.
+    pub async fn closed(&self) {
+        let mut closing = self.inner.closing.subscribe();
+        // The sender lives as long as the state, this can not fail
+        let _ = closing.wait_for(|closing| *closing).await;
+    }
+
+    /// Spawns the write-behind task. Several mutations landing while a save is
+    /// in flight are coalesced into a single follow-up save.
+    pub fn spawn_persister(&self) {
+        let state = self.clone();
+        tokio::spawn(async move {
+            loop {
+                state.inner.dirty.notified().await;
+                if let Err(e) = state.flush().await {
+                    tracing::error!("Failed to save tasks: {}", e);
+                }
+            }
+        });
+    }
+
+    /// Saves a snapshot of the current app to disk. Saves are serialized so an
+    /// older snapshot can never overwrite a newer one.
+    pub async fn flush(&self) -> Result<()> {
+        let _saving = self.inner.saving.lock().await;
+        let snapshot = self.read().await.clone();
+        let storage = self.inner.storage.clone();
+        tokio::task::spawn_blocking(move || snapshot.save(storage.as_ref())).await?
+    }
+}
+
+pub struct AppWriteGuard<'a> {
+    guard: RwLockWriteGuard<'a, App>,
+    dirty: &'a Notify,
+    events: &'a broadcast::Sender<Event>,
+}
+
+impl Deref for AppWriteGuard<'_> {
+    type Target = App;
+
+    fn deref(&self) -> &App {
+        &self.guard
+    }
+}
+
+impl DerefMut for AppWriteGuard<'_> {
+    fn deref_mut(&mut self) -> &mut App {
+        &mut self.guard
+    }
+}
+
+impl Drop for AppWriteGuard<'_> {
+    fn drop(&mut self) {
+        // Failed and read-only requests must not cause a save
+        if !self.guard.take_changed() {
+            return;
+        }
+        for event in self.guard.take_events() {
+            // Failing only means nobody is listening
+            let _ = self.events.send(event);
+        }
+        self.dirty.notify_one();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex as StdMutex;
+
+    use futures_util::FutureExt;
+
+    use super::*;
+    use crate::user::{Role, User};
+
+    /// Remembers how many tasks every save had.
+    #[derive(Default)]
+    struct Recorder {
+        saves: StdMutex<Vec<usize>>,
+    }
+
+    impl Storage for Recorder {
+        fn load(&self) -> Result<Option<App>> {
+            Ok(None)
+        }
+
+        fn save(&self, app: &App) -> Result<()> {
+            self.saves.lock().unwrap().push(app.get_tasks().len());
+            Ok(())
+        }
+    }
+
+    fn state() -> (AppState, Arc<Recorder>) {
+        let storage = Arc::new(Recorder::default());
+        let state = AppState::new(App::new(), storage.clone(), DataDir::temp(), Zone::utc());
+        (state, storage)
+    }
+
+    fn is_dirty(state: &AppState) -> bool {
+        state.inner.dirty.notified().now_or_never().is_some()
+    }
+
+    #[tokio::test]
+    async fn test_only_changes_mark_dirty() {
+        let (state, _) = state();
+        drop(state.write().await);
+        assert!(!is_dirty(&state));
+        assert!(state.write().await.start_task(42, None).is_err());
+        assert!(!is_dirty(&state));
+        let mut events = state.subscribe();
+        state.write().await.add_task("a".to_string(), None).unwrap();
+        assert!(is_dirty(&state));
+        assert_eq!(events.recv().await.unwrap().name(), "task_created");
+        let user = User::new("ada".to_string(), "secret", Role::Member).unwrap();
+        state.write().await.add_user(user).unwrap();
+        assert!(is_dirty(&state));
+        assert!(events.try_recv().is_err());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_concurrent_writers() {
+        let (state, storage) = state();
+        state.spawn_persister();
+        let writers: Vec<_> = (0..64)
+            .map(|i| {
+                let state = state.clone();
+                tokio::spawn(async move {
+                    state.write().await.add_task(i.to_string(), None).unwrap();
+                })
+            })
+            .collect();
+        for writer in writers {
+            writer.await.unwrap();
+        }
+        let app = state.read().await;
+        let mut ids: Vec<u64> = app.get_tasks().iter().map(|t| t.get_id()).collect();
+        drop(app);
+        ids.sort();
+        assert_eq!(ids, (0..64).collect::<Vec<u64>>());
+        // What the server does on shutdown, the persister may still be behind
+        state.close();
+        state.flush().await.unwrap();
+        assert_eq!(storage.saves.lock().unwrap().last(), Some(&64));
+    }
+}
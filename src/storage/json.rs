@@ -0,0 +1,272 @@
+use std::{
+    fs::{self, File},
+    io::Write,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+use serde::Serialize;
+
+use super::{
+    migrations::{self, CURRENT_VERSION},
+    Storage, DEFAULT_BACKUPS,
+};
+use crate::{
+    app::App,
+    error::{Error, Result},
+};
+
+/// Keeps the whole app as a single pretty printed json document.
+///
+/// Saves never touch the file in place: the new contents are written to a
+/// temporary file, synced and renamed over the old one. Before overwriting, the
+/// previous file is copied to a timestamped backup at most once per
+/// `BACKUP_INTERVAL`, keeping the newest `backups` copies around.
+pub struct JsonStorage {
+    path: PathBuf,
+    backups: usize,
+}
+
+const BACKUP_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// The app as written to disk, tagged with the schema version.
+#[derive(Serialize)]
+struct Document<'a> {
+    version: u64,
+    #[serde(flatten)]
+    app: &'a App,
+}
+
+impl JsonStorage {
+    pub fn new(path: impl Into<PathBuf>) -> JsonStorage {
+        JsonStorage {
+            path: path.into(),
+            backups: DEFAULT_BACKUPS,
+        }
+    }
+
+    pub fn with_backups(mut self, backups: usize) -> JsonStorage {
+        self.backups = backups;
+        self
+    }
+
+    fn read(path: &Path) -> Result<serde_json::Value> {
+        let serialized = fs::read_to_string(path)?;
+        Ok(serde_json::from_str(&serialized)?)
+    }
+
+    fn parse(path: &Path) -> Result<App> {
+        let mut doc = JsonStorage::read(path)?;
+        migrations::migrate(&mut doc)?;
+        Ok(serde_json::from_value(doc)?)
+    }
+
+    /// Backups are named `<file>.<unix seconds>.bak`, sorted newest first.
+    fn list_backups(&self) -> Result<Vec<(u64, PathBuf)>> {
+        let dir = parent_dir(&self.path);
+        let Some(file_name) = self.path.file_name().and_then(|n| n.to_str()) else {
+            return Ok(Vec::new());
+        };
+        let prefix = format!("{}.", file_name);
+        let mut backups = Vec::new();
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?;
+            let name = entry.file_name();
+            let Some(name) = name.to_str() else {
+                continue;
+            };
+            let stamp = name
+                .strip_prefix(&prefix)
+                .and_then(|rest| rest.strip_suffix(".bak"))
+                .and_then(|stamp| stamp.parse::<u64>().ok());
+            if let Some(stamp) = stamp {
+                backups.push((stamp, entry.path()));
+            }
+        }
+        backups.sort_by_key(|(stamp, _)| std::cmp::Reverse(*stamp));
+        Ok(backups)
+    }
+
+    fn backup(&self) -> Result<()> {
+        if self.backups == 0 || !self.path.exists() {
+            return Ok(());
+        }
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map_err(|e| Error::Storage(e.to_string()))?
+            .as_secs();
+        let backups = self.list_backups()?;
+        if let Some((newest, _)) = backups.first() {
+            if now.saturating_sub(*newest) < BACKUP_INTERVAL.as_secs() {
+                return Ok(());
+            }
+        }
+        let backup = PathBuf::from(format!("{}.{}.bak", self.path.display(), now));
+        fs::copy(&self.path, &backup)?;
+        for (_, old) in backups.iter().skip(self.backups - 1) {
+            fs::remove_file(old)?;
+        }
+        Ok(())
+    }
+
+    /// Falls back to the newest backup that still parses. The broken file is
+    /// kept next to it for inspection.
+    fn recover(&self, error: Error) -> Result<App> {
+        for (_, backup) in self.list_backups()? {
+            if let Ok(app) = JsonStorage::parse(&backup) {
+                let corrupt = format!("{}.corrupt", self.path.display());
+                fs::rename(&self.path, &corrupt)?;
+                tracing::warn!(
+                    "{} could not be read ({}), recovered from {}. The broken file was moved to {}",
+                    self.path.display(),
+                    error,
+                    backup.display(),
+                    corrupt
+                );
+                return Ok(app);
+            }
+        }
+        Err(Error::Storage(format!(
+            "{} could not be read and no valid backup was found: {}",
+            self.path.display(),
+            error
+        )))
+    }
+}
+
+impl Storage for JsonStorage {
+    fn load(&self) -> Result<Option<App>> {
+        if !self.path.exists() {
+            return Ok(None);
+        }
+        let mut doc = match JsonStorage::read(&self.path) {
+            Ok(doc) => doc,
+            Err(e) => return Ok(Some(self.recover(e)?)),
+        };
+        // A document this build can not migrate, like one from a newer
+        // version, is not broken. Recovering would roll it back to a backup.
+        migrations::migrate(&mut doc)?;
+        match serde_json::from_value(doc) {
+            Ok(app) => Ok(Some(app)),
+            Err(e) => Ok(Some(self.recover(e.into())?)),
+        }
+    }
+
+    fn save(&self, app: &App) -> Result<()> {
+        let serialized = serde_json::to_string_pretty(&Document {
+            version: CURRENT_VERSION,
+            app,
+        })?;
+        self.backup()?;
+        write_atomic(&self.path, serialized.as_bytes())
+    }
+}
+
+fn parent_dir(path: &Path) -> &Path {
+    match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    }
+}
+
+/// Writes to a temporary file next to `path` and renames it into place, so a
+/// crash leaves either the old or the new contents but never a truncated file.
+/// Every save gets its own temporary file, two processes saving at once never
+/// write into the same one.
+fn write_atomic(path: &Path, contents: &[u8]) -> Result<()> {
+    let mut temp = tempfile::NamedTempFile::new_in(parent_dir(path))?;
+    temp.write_all(contents)?;
+    temp.as_file().sync_all()?;
+    temp.persist(path).map_err(|e| e.error)?;
+    // Persist the rename itself
+    #[cfg(unix)]
+    File::open(parent_dir(path))?.sync_all()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("tasks-todo-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_backups_rotate() {
+        let dir = temp_dir("rotate");
+        let storage = JsonStorage::new(dir.join("data.json")).with_backups(2);
+        storage.save(&App::new()).unwrap();
+        for stamp in [100, 200, 300] {
+            fs::write(dir.join(format!("data.json.{}.bak", stamp)), "{}").unwrap();
+        }
+        storage.save(&App::new()).unwrap();
+        let backups = storage.list_backups().unwrap();
+        assert_eq!(backups.len(), 2);
+        assert_eq!(backups[1].0, 300);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_concurrent_saves() {
+        let dir = temp_dir("concurrent");
+        let path = dir.join("data.json");
+        let mut app = App::new();
+        app.add_task("saved".to_string(), None).unwrap();
+        std::thread::scope(|scope| {
+            for _ in 0..4 {
+                scope.spawn(|| {
+                    let storage = JsonStorage::new(&path).with_backups(0);
+                    for _ in 0..10 {
+                        storage.save(&app).unwrap();
+                    }
+                });
+            }
+        });
+        let loaded = JsonStorage::new(&path).load().unwrap().unwrap();
+        assert_eq!(loaded.get_tasks()[0].get_name(), "saved");
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_recover_from_backup() {
+        let dir = temp_dir("recover");
+        let path = dir.join("data.json");
+        let storage = JsonStorage::new(&path);
+        let mut app = App::new();
+        app.add_task("kept".to_string(), None).unwrap();
+        storage.save(&app).unwrap();
+        storage.save(&app).unwrap();
+        assert_eq!(storage.list_backups().unwrap().len(), 1);
+
+        fs::write(&path, "{\"tasks\": [").unwrap();
+        let recovered = storage.load().unwrap().unwrap();
+        assert_eq!(recovered.get_tasks()[0].get_name(), "kept");
+        assert!(dir.join("data.json.corrupt").exists());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_refuse_newer_version() {
+        let dir = temp_dir("newer");
+        let path = dir.join("data.json");
+        let storage = JsonStorage::new(&path);
+        storage.save(&App::new()).unwrap();
+        storage.save(&App::new()).unwrap();
+        assert_eq!(storage.list_backups().unwrap().len(), 1);
+
+        let newer = format!(
+            "{{\"version\": {}, \"tasks\": [], \"running_id\": 0}}",
+            CURRENT_VERSION + 1
+        );
+        fs::write(&path, &newer).unwrap();
+        assert!(storage.load().is_err());
+        assert_eq!(fs::read_to_string(&path).unwrap(), newer);
+        assert!(!dir.join("data.json.corrupt").exists());
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,370 @@
+use serde_json::Value;
+
+use crate::{
+    duration::Duration,
+    error::{Error, Result},
+    time::Time,
+};
+
+/// Version of the persisted document written by this build.
+pub const CURRENT_VERSION: u64 = 11;
+
+type Migration = fn(&mut Value) -> Result<()>;
+
+/// `MIGRATIONS[n]` upgrades a version `n` document to version `n + 1`.
+const MIGRATIONS: [Migration; CURRENT_VERSION as usize] = [
+    v0_to_v1, v1_to_v2, v2_to_v3, v3_to_v4, v4_to_v5, v5_to_v6, v6_to_v7, v7_to_v8, v8_to_v9,
+    v9_to_v10, v10_to_v11,
+];
+
+/// Brings a serialized app up to `CURRENT_VERSION`. Documents written before
+/// versioning was introduced have no `version` field and count as version 0.
+pub fn migrate(doc: &mut Value) -> Result<()> {
+    let version = match doc.get("version") {
+        None => 0,
+        Some(version) => version
+            .as_u64()
+            .ok_or_else(|| corrupt("Invalid schema version"))?,
+    };
+    if version > CURRENT_VERSION {
+        return Err(Error::Storage(format!(
+            "Data was written by a newer version (schema {}, this build supports up to {})",
+            version, CURRENT_VERSION
+        )));
+    }
+    for (from, migration) in MIGRATIONS.iter().enumerate().skip(version as usize) {
+        migration(doc)?;
+        object(doc)?.insert("version".to_string(), Value::from(from as u64 + 1));
+    }
+    Ok(())
+}
+
+fn corrupt(message: &str) -> Error {
+    Error::Storage(message.to_string())
+}
+
+fn object(doc: &mut Value) -> Result<&mut serde_json::Map<String, Value>> {
+    doc.as_object_mut()
+        .ok_or_else(|| corrupt("Expected a json object"))
+}
+
+fn tasks(doc: &mut Value) -> Result<&mut Vec<Value>> {
+    object(doc)?
+        .get_mut("tasks")
+        .and_then(Value::as_array_mut)
+        .ok_or_else(|| corrupt("Expected a tasks array"))
+}
+
+/// Version 1 only adds the version field and the list of uploaded images.
+fn v0_to_v1(doc: &mut Value) -> Result<()> {
+    for task in tasks(doc)? {
+        let task = task
+            .as_object_mut()
+            .ok_or_else(|| corrupt("Expected a task object"))?;
+        task.entry("images")
+            .or_insert_with(|| Value::Array(Vec::new()));
+    }
+    Ok(())
+}
+
+/// Version 2 adds archiving.
+fn v1_to_v2(doc: &mut Value) -> Result<()> {
+    for task in tasks(doc)? {
+        let task = task
+            .as_object_mut()
+            .ok_or_else(|| corrupt("Expected a task object"))?;
+        task.entry("archived").or_insert(Value::Bool(false));
+    }
+    Ok(())
+}
+
+/// Version 3 replaces the single start and end time with a list of sessions.
+fn v2_to_v3(doc: &mut Value) -> Result<()> {
+    for task in tasks(doc)? {
+        let task = task
+            .as_object_mut()
+            .ok_or_else(|| corrupt("Expected a task object"))?;
+        let start = task.remove("start_time").unwrap_or(Value::Null);
+        let end = task.remove("end_time").unwrap_or(Value::Null);
+        let sessions = match (start, end) {
+            (Value::Null, Value::Null) => vec![],
+            (Value::Null, end) => vec![serde_json::json!({ "start": end, "end": end })],
+            (start, end) => vec![serde_json::json!({ "start": start, "end": end })],
+        };
+        task.insert("sessions".to_string(), Value::Array(sessions));
+    }
+    Ok(())
+}
+
+/// Version 4 records when complete tasks were reopened.
+fn v3_to_v4(doc: &mut Value) -> Result<()> {
+    for task in tasks(doc)? {
+        let task = task
+            .as_object_mut()
+            .ok_or_else(|| corrupt("Expected a task object"))?;
+        task.entry("reopened")
+            .or_insert_with(|| Value::Array(Vec::new()));
+    }
+    Ok(())
+}
+
+/// Up to version 4 every timestamp was shifted to IST, from version 5 on they
+/// are stored in UTC.
+fn v4_to_v5(doc: &mut Value) -> Result<()> {
+    let ist = Duration::from_seconds(5 * 60 * 60 + 30 * 60);
+    let to_utc = |time: &mut Value| -> Result<()> {
+        if !time.is_null() {
+            let ist_time: Time = serde_json::from_value(time.take())?;
+            *time = serde_json::to_value(ist_time - ist)?;
+        }
+        Ok(())
+    };
+    for task in tasks(doc)? {
+        let task = task
+            .as_object_mut()
+            .ok_or_else(|| corrupt("Expected a task object"))?;
+        for session in task
+            .get_mut("sessions")
+            .and_then(Value::as_array_mut)
+            .ok_or_else(|| corrupt("Expected a sessions array"))?
+        {
+            to_utc(&mut session["start"])?;
+            to_utc(&mut session["end"])?;
+        }
+        for reopening in task
+            .get_mut("reopened")
+            .and_then(Value::as_array_mut)
+            .ok_or_else(|| corrupt("Expected a reopened array"))?
+        {
+            to_utc(&mut reopening["time"])?;
+        }
+    }
+    Ok(())
+}
+
+/// Version 6 counts revisions of every task to detect conflicting edits.
+fn v5_to_v6(doc: &mut Value) -> Result<()> {
+    for task in tasks(doc)? {
+        let task = task
+            .as_object_mut()
+            .ok_or_else(|| corrupt("Expected a task object"))?;
+        task.entry("revision").or_insert(Value::from(0));
+    }
+    Ok(())
+}
+
+/// Version 7 adds user accounts.
+fn v6_to_v7(doc: &mut Value) -> Result<()> {
+    object(doc)?
+        .entry("users")
+        .or_insert_with(|| Value::Array(Vec::new()));
+    Ok(())
+}
+
+/// Version 8 records who added a task, who it is assigned to and whose
+/// session each one is.
+fn v7_to_v8(doc: &mut Value) -> Result<()> {
+    for task in tasks(doc)? {
+        let task = task
+            .as_object_mut()
+            .ok_or_else(|| corrupt("Expected a task object"))?;
+        task.entry("creator").or_insert(Value::Null);
+        task.entry("assignee").or_insert(Value::Null);
+        for session in task
+            .get_mut("sessions")
+            .and_then(Value::as_array_mut)
+            .ok_or_else(|| corrupt("Expected a sessions array"))?
+        {
+            session
+                .as_object_mut()
+                .ok_or_else(|| corrupt("Expected a session object"))?
+                .entry("user")
+                .or_insert(Value::Null);
+        }
+    }
+    Ok(())
+}
+
+/// Version 9 adds roles and share links. Existing accounts become managers,
+/// they could do everything before.
+fn v8_to_v9(doc: &mut Value) -> Result<()> {
+    let doc = object(doc)?;
+    for user in doc
+        .get_mut("users")
+        .and_then(Value::as_array_mut)
+        .ok_or_else(|| corrupt("Expected a users array"))?
+    {
+        user.as_object_mut()
+            .ok_or_else(|| corrupt("Expected a user object"))?
+            .entry("role")
+            .or_insert(Value::from("manager"));
+    }
+    doc.entry("shares")
+        .or_insert_with(|| Value::Array(Vec::new()));
+    Ok(())
+}
+
+/// Version 10 keeps the revisions of the summaries. Summaries written before
+/// have none, their history starts with the next edit.
+fn v9_to_v10(doc: &mut Value) -> Result<()> {
+    for task in tasks(doc)? {
+        task.as_object_mut()
+            .ok_or_else(|| corrupt("Expected a task object"))?
+            .entry("summary_revisions")
+            .or_insert_with(|| Value::Array(Vec::new()));
+    }
+    Ok(())
+}
+
+/// Version 11 repairs the sessions and reopenings written by a
+/// `Time::from_iso` that thought November had 31 days. It wrote December 1st
+/// as November 31st and every other December date one day early. December
+/// 1st itself is ambiguous once normalized and left as is, like in the
+/// database.
+fn v10_to_v11(doc: &mut Value) -> Result<()> {
+    let day = Duration::from_seconds(24 * 60 * 60);
+    let repair = |time: &mut Value| -> Result<()> {
+        if !time.is_null() {
+            let written: Time = serde_json::from_value(time.take())?;
+            let mut repaired = Time::from_iso(written.to_iso());
+            if repaired.month == 12 && repaired.day != 1 {
+                repaired += day;
+            }
+            *time = serde_json::to_value(repaired)?;
+        }
+        Ok(())
+    };
+    for task in tasks(doc)? {
+        let task = task
+            .as_object_mut()
+            .ok_or_else(|| corrupt("Expected a task object"))?;
+        for session in task
+            .get_mut("sessions")
+            .and_then(Value::as_array_mut)
+            .ok_or_else(|| corrupt("Expected a sessions array"))?
+        {
+            repair(&mut session["start"])?;
+            repair(&mut session["end"])?;
+        }
+        for reopening in task
+            .get_mut("reopened")
+            .and_then(Value::as_array_mut)
+            .ok_or_else(|| corrupt("Expected a reopened array"))?
+        {
+            repair(&mut reopening["time"])?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{app::App, user::Role};
+
+    /// One fixture per schema version, every version ever written must be
+    /// listed here.
+    const FIXTURES: [(u64, &str); CURRENT_VERSION as usize + 1] = [
+        (0, include_str!("fixtures/v0.json")),
+        (1, include_str!("fixtures/v1.json")),
+        (2, include_str!("fixtures/v2.json")),
+        (3, include_str!("fixtures/v3.json")),
+        (4, include_str!("fixtures/v4.json")),
+        (5, include_str!("fixtures/v5.json")),
+        (6, include_str!("fixtures/v6.json")),
+        (7, include_str!("fixtures/v7.json")),
+        (8, include_str!("fixtures/v8.json")),
+        (9, include_str!("fixtures/v9.json")),
+        (10, include_str!("fixtures/v10.json")),
+        (11, include_str!("fixtures/v11.json")),
+    ];
+
+    #[test]
+    fn test_fixtures_cover_every_version() {
+        for (i, (version, _)) in FIXTURES.iter().enumerate() {
+            assert_eq!(*version, i as u64);
+        }
+    }
+
+    #[test]
+    fn test_migrate_fixtures() {
+        for (version, fixture) in FIXTURES {
+            let mut doc: Value = serde_json::from_str(fixture).unwrap();
+            migrate(&mut doc).unwrap();
+            assert_eq!(doc["version"], CURRENT_VERSION, "fixture v{}", version);
+            let app: App = serde_json::from_value(doc).unwrap();
+            let tasks = app.get_tasks();
+            assert_eq!(tasks.len(), 3, "fixture v{}", version);
+            assert_eq!(tasks[1].get_parent_id(), Some(0));
+            assert_eq!(tasks[2].get_name(), "Write report");
+            if version < 6 {
+                assert_eq!(tasks[0].get_revision(), 0);
+            }
+            if version < 7 {
+                assert!(app.get_users().is_empty());
+            } else {
+                assert!(app.get_users()[0].check_password("secret"));
+                let role = if version < 9 {
+                    Role::Manager
+                } else {
+                    Role::Member
+                };
+                assert_eq!(app.get_users()[0].get_role(), role);
+            }
+            if version < 8 {
+                assert_eq!(tasks[1].get_assignee(), None);
+            } else {
+                assert_eq!(tasks[1].get_creator(), Some("ada"));
+                assert_eq!(tasks[1].get_assignee(), Some("ada"));
+                assert_eq!(tasks[1].get_sessions()[0].user.as_deref(), Some("ada"));
+            }
+            let revisions = tasks[0].get_summary_revisions();
+            if version < 10 {
+                assert!(revisions.is_empty());
+            } else {
+                assert_eq!(revisions[1].author.as_deref(), Some("ada"));
+                assert_eq!(revisions[1].restored, None);
+            }
+        }
+    }
+
+    #[test]
+    fn test_ist_to_utc() {
+        let mut doc: Value = serde_json::from_str(FIXTURES[4].1).unwrap();
+        migrate(&mut doc).unwrap();
+        let app: App = serde_json::from_value(doc).unwrap();
+        let session = &app.get_tasks()[1].get_sessions()[0];
+        assert_eq!(session.start, Time::new(2023, 11, 28, 4, 30, 0).unwrap());
+    }
+
+    #[test]
+    fn test_repair_december() {
+        let mut doc: Value = serde_json::from_str(FIXTURES[5].1).unwrap();
+        let utc = |month, day| {
+            serde_json::json!({
+                "year": 2023, "month": month, "day": day, "hour": 9, "minute": 0, "second": 0
+            })
+        };
+        let sessions = &mut doc["tasks"][0]["sessions"];
+        sessions[0] = serde_json::json!({ "start": utc(11, 31), "end": utc(12, 30) });
+        sessions[1] = serde_json::json!({ "start": utc(12, 1), "end": utc(11, 30) });
+        migrate(&mut doc).unwrap();
+        let app: App = serde_json::from_value(doc).unwrap();
+        let sessions = app.get_tasks()[0].get_sessions();
+        let time = |month, day| Time::new(2023, month, day, 9, 0, 0).unwrap();
+        assert_eq!(sessions[0].start, time(12, 1));
+        assert_eq!(sessions[0].end, Some(time(12, 31)));
+        assert_eq!(sessions[1].start, time(12, 1));
+        assert_eq!(sessions[1].end, Some(time(11, 30)));
+    }
+
+    #[test]
+    fn test_reject_newer_version() {
+        let mut doc = serde_json::json!({
+            "version": CURRENT_VERSION + 1,
+            "tasks": [],
+            "running_id": 0
+        });
+        assert!(migrate(&mut doc).is_err());
+    }
+}
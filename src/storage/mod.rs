@@ -0,0 +1,86 @@
+use std::{path::Path, str::FromStr, sync::Arc};
+
+use crate::{
+    app::App,
+    data_dir::DataDir,
+    error::{Error, Result},
+};
+
+mod json;
+mod migrations;
+mod sqlite;
+
+pub use json::JsonStorage;
+pub use sqlite::SqliteStorage;
+
+/// Where the app gets persisted to.
+///
+/// Implementations are blocking, callers on the async runtime should go through
+/// `tokio::task::spawn_blocking`.
+pub trait Storage: Send + Sync {
+    /// Returns `None` when nothing has been stored yet.
+    fn load(&self) -> Result<Option<App>>;
+    fn save(&self, app: &App) -> Result<()>;
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Backend {
+    Json,
+    Sqlite,
+}
+
+impl FromStr for Backend {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "json" => Ok(Backend::Json),
+            "sqlite" => Ok(Backend::Sqlite),
+            _ => Err(Error::Config(format!(
+                "Unknown storage backend {}, expected json or sqlite",
+                s
+            ))),
+        }
+    }
+}
+
+pub const JSON_FILE: &str = "data.json";
+pub const SQLITE_FILE: &str = "tasks.db";
+
+/// Number of rolling data.json backups kept unless configured otherwise.
+pub const DEFAULT_BACKUPS: usize = 5;
+
+/// Opens the store of the backend in the data directory, keeping `backups`
+/// rolling copies of data.json.
+pub fn open(backend: Backend, data: &DataDir, backups: usize) -> Result<Arc<dyn Storage>> {
+    let json_file = data.resolve(JSON_FILE)?;
+    match backend {
+        Backend::Json => Ok(Arc::new(JsonStorage::new(json_file).with_backups(backups))),
+        Backend::Sqlite => {
+            let storage = SqliteStorage::open(data.resolve(SQLITE_FILE)?)?;
+            migrate_json(&storage, &json_file)?;
+            Ok(Arc::new(storage))
+        }
+    }
+}
+
+/// One-shot import of an existing data.json into a fresh database. The json
+/// file is renamed afterwards so it is not imported again.
+fn migrate_json(storage: &SqliteStorage, json_file: &Path) -> Result<()> {
+    if !json_file.exists() || storage.load()?.is_some() {
+        return Ok(());
+    }
+    let app = JsonStorage::new(json_file)
+        .load()?
+        .ok_or_else(|| Error::Storage("data.json is empty".to_string()))?;
+    storage.save(&app)?;
+    let migrated = format!("{}.migrated", json_file.display());
+    std::fs::rename(json_file, &migrated)?;
+    tracing::info!(
+        "Imported {} tasks from {}, the old file was moved to {}",
+        app.get_tasks().len(),
+        json_file.display(),
+        migrated
+    );
+    Ok(())
+}
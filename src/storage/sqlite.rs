@@ -0,0 +1,587 @@
+use std::{collections::HashMap, path::Path, sync::Mutex};
+
+use rusqlite::{params, Connection, OptionalExtension, Transaction};
+
+use super::Storage;
+use crate::{
+    app::App,
+    error::{Error, Result},
+    share::Share,
+    task::{Image, Reopening, Session, SummaryRevision, Task, TaskStaus},
+    time::Time,
+    user::{ApiToken, User},
+};
+
+/// `MIGRATIONS[n]` upgrades a database at `PRAGMA user_version` n to n + 1.
+const MIGRATIONS: &[&str] = &[
+    // 1: initial schema
+    "
+        CREATE TABLE IF NOT EXISTS meta (
+            key TEXT PRIMARY KEY,
+            value INTEGER NOT NULL
+        );
+        CREATE TABLE IF NOT EXISTS tasks (
+            id INTEGER PRIMARY KEY,
+            parent_id INTEGER,
+            name TEXT NOT NULL,
+            status TEXT NOT NULL
+        );
+        CREATE TABLE IF NOT EXISTS sessions (
+            task_id INTEGER NOT NULL REFERENCES tasks(id),
+            start INTEGER,
+            end INTEGER
+        );
+        CREATE TABLE IF NOT EXISTS summaries (
+            task_id INTEGER PRIMARY KEY REFERENCES tasks(id),
+            path TEXT NOT NULL
+        );
+        CREATE TABLE IF NOT EXISTS images (
+            task_id INTEGER NOT NULL REFERENCES tasks(id),
+            name TEXT NOT NULL,
+            path TEXT NOT NULL,
+            PRIMARY KEY (task_id, name)
+        );
+    ",
+    // 2: archiving
+    "ALTER TABLE tasks ADD COLUMN archived INTEGER NOT NULL DEFAULT 0;",
+    // 3: a task can have many sessions, each with a start
+    "
+        UPDATE sessions SET start = end WHERE start IS NULL;
+        DELETE FROM sessions WHERE start IS NULL;
+    ",
+    // 4: reopening complete tasks
+    "
+        CREATE TABLE reopenings (
+            task_id INTEGER NOT NULL REFERENCES tasks(id),
+            time INTEGER NOT NULL,
+            reason TEXT
+        );
+    ",
+    // 5: timestamps move from IST to UTC
+    "
+        UPDATE sessions SET start = start - 19800, end = end - 19800;
+        UPDATE reopenings SET time = time - 19800;
+    ",
+    // 6: revisions for optimistic concurrency
+    "ALTER TABLE tasks ADD COLUMN revision INTEGER NOT NULL DEFAULT 0;",
+    // 7: user accounts
+    "
+        CREATE TABLE users (
+            name TEXT PRIMARY KEY,
+            password TEXT NOT NULL
+        );
+        CREATE TABLE tokens (
+            user TEXT NOT NULL REFERENCES users(name),
+            name TEXT NOT NULL,
+            hash TEXT NOT NULL UNIQUE,
+            created INTEGER NOT NULL,
+            PRIMARY KEY (user, name)
+        );
+    ",
+    // 8: who added and works on a task, whose session it is
+    "
+        ALTER TABLE tasks ADD COLUMN creator TEXT;
+        ALTER TABLE tasks ADD COLUMN assignee TEXT;
+        ALTER TABLE sessions ADD COLUMN user TEXT;
+    ",
+    // 9: roles, existing accounts keep doing everything they could, and share
+    // links
+    "
+        ALTER TABLE users ADD COLUMN role TEXT NOT NULL DEFAULT 'manager';
+        CREATE TABLE shares (
+            hash TEXT PRIMARY KEY,
+            task_id INTEGER NOT NULL,
+            created_by TEXT,
+            created INTEGER NOT NULL
+        );
+    ",
+    // 10: every revision of the summaries
+    "
+        CREATE TABLE summary_revisions (
+            task_id INTEGER NOT NULL REFERENCES tasks(id),
+            number INTEGER NOT NULL,
+            author TEXT,
+            time INTEGER NOT NULL,
+            restored INTEGER,
+            PRIMARY KEY (task_id, number)
+        );
+    ",
+    // 11: December dates were written one day early by the old
+    // Time::from_iso, December 1st is ambiguous and left as is
+    "
+        UPDATE sessions SET start = start + 86400
+            WHERE strftime('%m', start, 'unixepoch') = '12'
+            AND strftime('%d', start, 'unixepoch') != '01';
+        UPDATE sessions SET end = end + 86400
+            WHERE strftime('%m', end, 'unixepoch') = '12'
+            AND strftime('%d', end, 'unixepoch') != '01';
+        UPDATE reopenings SET time = time + 86400
+            WHERE strftime('%m', time, 'unixepoch') = '12'
+            AND strftime('%d', time, 'unixepoch') != '01';
+    ",
+];
+
+/// Embedded database storage. Only the tasks that changed since the last save
+/// are written back.
+pub struct SqliteStorage {
+    inner: Mutex<Inner>,
+}
+
+struct Inner {
+    conn: Connection,
+    saved: HashMap<u64, Task>,
+    saved_users: Vec<User>,
+    saved_shares: Vec<Share>,
+}
+
+impl SqliteStorage {
+    pub fn open(path: impl AsRef<Path>) -> Result<SqliteStorage> {
+        let mut conn = Connection::open(path)?;
+        migrate(&mut conn)?;
+        Ok(SqliteStorage {
+            inner: Mutex::new(Inner {
+                conn,
+                saved: HashMap::new(),
+                saved_users: Vec::new(),
+                saved_shares: Vec::new(),
+            }),
+        })
+    }
+}
+
+impl Storage for SqliteStorage {
+    fn load(&self) -> Result<Option<App>> {
+        let mut inner = self
+            .inner
+            .lock()
+            .map_err(|_| Error::Storage("Storage lock poisoned".to_string()))?;
+        let Inner {
+            conn,
+            saved,
+            saved_users,
+            saved_shares,
+        } = &mut *inner;
+        let running_id: Option<i64> = conn
+            .query_row(
+                "SELECT value FROM meta WHERE key = 'running_id'",
+                [],
+                |row| row.get(0),
+            )
+            .optional()?;
+        let Some(running_id) = running_id else {
+            return Ok(None);
+        };
+
+        let mut tasks = Vec::new();
+        let mut stmt = conn.prepare(
+            "SELECT id, parent_id, name, status, archived, revision, creator, assignee
+                FROM tasks ORDER BY id",
+        )?;
+        let mut rows = stmt.query([])?;
+        while let Some(row) = rows.next()? {
+            let id: i64 = row.get(0)?;
+            let parent_id: Option<i64> = row.get(1)?;
+            let status: String = row.get(3)?;
+            let mut task = Task::new(id as u64, parent_id.map(|id| id as u64), row.get(2)?);
+            task.status = status_from_str(&status)?;
+            task.archived = row.get(4)?;
+            task.revision = row.get::<_, i64>(5)? as u64;
+            task.creator = row.get(6)?;
+            task.assignee = row.get(7)?;
+            tasks.push(task);
+        }
+
+        let mut stmt =
+            conn.prepare("SELECT task_id, start, end, user FROM sessions ORDER BY rowid")?;
+        let mut rows = stmt.query([])?;
+        while let Some(row) = rows.next()? {
+            let start: i64 = row.get(1)?;
+            let end: Option<i64> = row.get(2)?;
+            find(&mut tasks, row.get(0)?)?.sessions.push(Session {
+                start: Time::from_iso(start),
+                end: end.map(Time::from_iso),
+                user: row.get(3)?,
+            });
+        }
+
+        let mut stmt = conn.prepare("SELECT task_id, path FROM summaries")?;
+        let mut rows = stmt.query([])?;
+        while let Some(row) = rows.next()? {
+            find(&mut tasks, row.get(0)?)?.summary = Some(row.get(1)?);
+        }
+
+        let mut stmt = conn.prepare(
+            "SELECT task_id, number, author, time, restored FROM summary_revisions
+                ORDER BY task_id, number",
+        )?;
+        let mut rows = stmt.query([])?;
+        while let Some(row) = rows.next()? {
+            let number: i64 = row.get(1)?;
+            let time: i64 = row.get(3)?;
+            let restored: Option<i64> = row.get(4)?;
+            find(&mut tasks, row.get(0)?)?
+                .summary_revisions
+                .push(SummaryRevision {
+                    number: number as u64,
+                    author: row.get(2)?,
+                    time: Time::from_iso(time),
+                    restored: restored.map(|number| number as u64),
+                });
+        }
+
+        let mut stmt = conn.prepare("SELECT task_id, name, path FROM images ORDER BY rowid")?;
+        let mut rows = stmt.query([])?;
+        while let Some(row) = rows.next()? {
+            let image = Image {
+                name: row.get(1)?,
+                path: row.get(2)?,
+            };
+            find(&mut tasks, row.get(0)?)?.images.push(image);
+        }
+
+        let mut stmt =
+            conn.prepare("SELECT task_id, time, reason FROM reopenings ORDER BY rowid")?;
+        let mut rows = stmt.query([])?;
+        while let Some(row) = rows.next()? {
+            let time: i64 = row.get(1)?;
+            find(&mut tasks, row.get(0)?)?.reopened.push(Reopening {
+                time: Time::from_iso(time),
+                reason: row.get(2)?,
+            });
+        }
+
+        let mut users = Vec::new();
+        let mut stmt = conn.prepare("SELECT name, password, role FROM users ORDER BY rowid")?;
+        let mut rows = stmt.query([])?;
+        while let Some(row) = rows.next()? {
+            let role: String = row.get(2)?;
+            users.push(User {
+                name: row.get(0)?,
+                password: row.get(1)?,
+                role: role
+                    .parse()
+                    .map_err(|_| Error::Storage(format!("Unknown role {}", role)))?,
+                tokens: Vec::new(),
+            });
+        }
+
+        let mut stmt =
+            conn.prepare("SELECT user, name, hash, created FROM tokens ORDER BY rowid")?;
+        let mut rows = stmt.query([])?;
+        while let Some(row) = rows.next()? {
+            let user: String = row.get(0)?;
+            let created: i64 = row.get(3)?;
+            users
+                .iter_mut()
+                .find(|u| u.name == user)
+                .ok_or_else(|| Error::Storage("Database references a missing user".to_string()))?
+                .tokens
+                .push(ApiToken {
+                    name: row.get(1)?,
+                    hash: row.get(2)?,
+                    created: Time::from_iso(created),
+                });
+        }
+
+        let mut shares = Vec::new();
+        let mut stmt =
+            conn.prepare("SELECT hash, task_id, created_by, created FROM shares ORDER BY rowid")?;
+        let mut rows = stmt.query([])?;
+        while let Some(row) = rows.next()? {
+            let task_id: i64 = row.get(1)?;
+            let created: i64 = row.get(3)?;
+            shares.push(Share {
+                hash: row.get(0)?,
+                task_id: task_id as u64,
+                created_by: row.get(2)?,
+                created: Time::from_iso(created),
+            });
+        }
+
+        *saved = tasks.iter().map(|t| (t.id, t.clone())).collect();
+        *saved_users = users.clone();
+        *saved_shares = shares.clone();
+        Ok(Some(App::from_parts(
+            tasks,
+            running_id as u64,
+            users,
+            shares,
+        )))
+    }
+
+    fn save(&self, app: &App) -> Result<()> {
+        let mut inner = self
+            .inner
+            .lock()
+            .map_err(|_| Error::Storage("Storage lock poisoned".to_string()))?;
+        let Inner {
+            conn,
+            saved,
+            saved_users,
+            saved_shares,
+        } = &mut *inner;
+        let tx = conn.transaction()?;
+        tx.execute(
+            "INSERT OR REPLACE INTO meta (key, value) VALUES ('running_id', ?1)",
+            params![app.get_running_id() as i64],
+        )?;
+        let mut current = HashMap::new();
+        for task in app.get_tasks() {
+            if saved.get(&task.id) != Some(task) {
+                delete_task(&tx, task.id)?;
+                insert_task(&tx, task)?;
+            }
+            current.insert(task.id, task.clone());
+        }
+        for id in saved.keys() {
+            if !current.contains_key(id) {
+                delete_task(&tx, *id)?;
+            }
+        }
+        // There are only ever a few users, rewrite them all on a change
+        if saved_users.as_slice() != app.get_users() {
+            tx.execute("DELETE FROM tokens", [])?;
+            tx.execute("DELETE FROM users", [])?;
+            for user in app.get_users() {
+                insert_user(&tx, user)?;
+            }
+        }
+        if saved_shares.as_slice() != app.get_shares() {
+            tx.execute("DELETE FROM shares", [])?;
+            for share in app.get_shares() {
+                tx.execute(
+                    "INSERT INTO shares (hash, task_id, created_by, created)
+                        VALUES (?1, ?2, ?3, ?4)",
+                    params![
+                        share.hash,
+                        share.task_id as i64,
+                        share.created_by,
+                        share.created.to_iso()
+                    ],
+                )?;
+            }
+        }
+        tx.commit()?;
+        *saved = current;
+        *saved_users = app.get_users().to_vec();
+        *saved_shares = app.get_shares().to_vec();
+        Ok(())
+    }
+}
+
+fn migrate(conn: &mut Connection) -> Result<()> {
+    let version: i64 = conn.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+    if version as usize > MIGRATIONS.len() {
+        return Err(Error::Storage(format!(
+            "Database was written by a newer version (schema {}, this build supports up to {})",
+            version,
+            MIGRATIONS.len()
+        )));
+    }
+    for (from, migration) in MIGRATIONS.iter().enumerate().skip(version as usize) {
+        let tx = conn.transaction()?;
+        tx.execute_batch(migration)?;
+        tx.pragma_update(None, "user_version", from as i64 + 1)?;
+        tx.commit()?;
+    }
+    Ok(())
+}
+
+/// `tasks` is sorted by id since it is loaded with `ORDER BY id`.
+fn find(tasks: &mut [Task], id: i64) -> Result<&mut Task> {
+    let index = tasks
+        .binary_search_by_key(&(id as u64), |t| t.id)
+        .map_err(|_| Error::Storage("Database references a missing task".to_string()))?;
+    Ok(&mut tasks[index])
+}
+
+fn delete_task(tx: &Transaction, id: u64) -> Result<()> {
+    for table in [
+        "sessions",
+        "summaries",
+        "summary_revisions",
+        "images",
+        "reopenings",
+    ] {
+        tx.execute(
+            &format!("DELETE FROM {} WHERE task_id = ?1", table),
+            params![id as i64],
+        )?;
+    }
+    tx.execute("DELETE FROM tasks WHERE id = ?1", params![id as i64])?;
+    Ok(())
+}
+
+fn insert_task(tx: &Transaction, task: &Task) -> Result<()> {
+    tx.execute(
+        "INSERT INTO tasks (id, parent_id, name, status, archived, revision, creator, assignee)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+        params![
+            task.id as i64,
+            task.parent_id.map(|id| id as i64),
+            task.name,
+            status_to_str(&task.status),
+            task.archived,
+            task.revision as i64,
+            task.creator,
+            task.assignee
+        ],
+    )?;
+    for session in &task.sessions {
+        tx.execute(
+            "INSERT INTO sessions (task_id, start, end, user) VALUES (?1, ?2, ?3, ?4)",
+            params![
+                task.id as i64,
+                session.start.to_iso(),
+                session.end.map(|t| t.to_iso()),
+                session.user
+            ],
+        )?;
+    }
+    if let Some(summary) = &task.summary {
+        tx.execute(
+            "INSERT INTO summaries (task_id, path) VALUES (?1, ?2)",
+            params![task.id as i64, summary],
+        )?;
+    }
+    for revision in &task.summary_revisions {
+        tx.execute(
+            "INSERT INTO summary_revisions (task_id, number, author, time, restored)
+                VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![
+                task.id as i64,
+                revision.number as i64,
+                revision.author,
+                revision.time.to_iso(),
+                revision.restored.map(|number| number as i64)
+            ],
+        )?;
+    }
+    for image in &task.images {
+        tx.execute(
+            "INSERT INTO images (task_id, name, path) VALUES (?1, ?2, ?3)",
+            params![task.id as i64, image.name, image.path],
+        )?;
+    }
+    for reopening in &task.reopened {
+        tx.execute(
+            "INSERT INTO reopenings (task_id, time, reason) VALUES (?1, ?2, ?3)",
+            params![task.id as i64, reopening.time.to_iso(), reopening.reason],
+        )?;
+    }
+    Ok(())
+}
+
+fn insert_user(tx: &Transaction, user: &User) -> Result<()> {
+    tx.execute(
+        "INSERT INTO users (name, password, role) VALUES (?1, ?2, ?3)",
+        params![user.name, user.password, user.role.to_string()],
+    )?;
+    for token in &user.tokens {
+        tx.execute(
+            "INSERT INTO tokens (user, name, hash, created) VALUES (?1, ?2, ?3, ?4)",
+            params![user.name, token.name, token.hash, token.created.to_iso()],
+        )?;
+    }
+    Ok(())
+}
+
+fn status_to_str(status: &TaskStaus) -> &'static str {
+    match status {
+        TaskStaus::Incomplete => "Incomplete",
+        TaskStaus::InProgress => "InProgress",
+        TaskStaus::Paused => "Paused",
+        TaskStaus::Complete => "Complete",
+    }
+}
+
+fn status_from_str(status: &str) -> Result<TaskStaus> {
+    match status {
+        "Incomplete" => Ok(TaskStaus::Incomplete),
+        "InProgress" => Ok(TaskStaus::InProgress),
+        "Paused" => Ok(TaskStaus::Paused),
+        "Complete" => Ok(TaskStaus::Complete),
+        _ => Err(Error::Storage(format!("Unknown task status {}", status))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{data_dir::DataDir, user::Role};
+
+    #[tokio::test]
+    async fn test_roundtrip() {
+        let storage = SqliteStorage::open(":memory:").unwrap();
+        assert!(storage.load().unwrap().is_none());
+
+        let mut app = App::new();
+        let parent = app.add_task("parent".to_string(), None).unwrap();
+        let child = app
+            .add_subtask(parent, "child".to_string(), Some("ada"))
+            .unwrap();
+        app.assign_task(child, Some("ada".to_string())).unwrap();
+        app.start_task(child, Some("ada")).unwrap();
+        app.attach_image(child, "plot".to_string(), "images/1_plot.png".to_string())
+            .unwrap();
+        let data = DataDir::temp();
+        app.set_summary(child, "# Plan".to_string(), Some("ada"), &data)
+            .await
+            .unwrap();
+        storage.save(&app).unwrap();
+
+        app.rename_task(parent, "renamed".to_string()).unwrap();
+        storage.save(&app).unwrap();
+
+        let mut user = User::new("ada".to_string(), "secret", Role::Viewer).unwrap();
+        user.tokens.push(ApiToken {
+            name: "ci".to_string(),
+            hash: "abc".to_string(),
+            created: Time::from_iso(1_700_000_000),
+        });
+        app.add_user(user).unwrap();
+        app.add_share(child, "f".repeat(64), Some("ada")).unwrap();
+        storage.save(&app).unwrap();
+
+        let loaded = storage.load().unwrap().unwrap();
+        assert_eq!(loaded.get_running_id(), app.get_running_id());
+        assert!(loaded.get_users() == app.get_users());
+        assert!(loaded.get_shares() == app.get_shares());
+        // Started by its child, then renamed
+        assert_eq!(loaded.get_tasks()[0].get_revision(), 2);
+        assert!(loaded.get_tasks() == app.get_tasks());
+        assert_eq!(loaded.get_tasks()[1].get_summary_revisions().len(), 1);
+        std::fs::remove_dir_all(data.root()).unwrap();
+    }
+
+    #[test]
+    fn test_repair_december() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        for migration in &MIGRATIONS[..10] {
+            conn.execute_batch(migration).unwrap();
+        }
+        conn.pragma_update(None, "user_version", 10).unwrap();
+        let time = |month, day| Time::new(2023, month, day, 9, 0, 0).unwrap().to_iso();
+        conn.execute_batch(&format!(
+            "INSERT INTO tasks (id, name, status) VALUES (0, 'December', 'Complete');
+            INSERT INTO sessions (task_id, start, end) VALUES (0, {}, {}), (0, {}, {});",
+            time(12, 1),
+            time(12, 30),
+            time(11, 30),
+            time(12, 2)
+        ))
+        .unwrap();
+        migrate(&mut conn).unwrap();
+        let sessions: Vec<(i64, i64)> = conn
+            .prepare("SELECT start, end FROM sessions ORDER BY start")
+            .unwrap()
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+            .unwrap()
+            .collect::<rusqlite::Result<_>>()
+            .unwrap();
+        assert_eq!(
+            sessions,
+            [(time(11, 30), time(12, 3)), (time(12, 1), time(12, 31))]
+        );
+    }
+}
@@ -1,12 +1,19 @@
 use serde::{Deserialize, Serialize};
-use std::{collections::HashMap, fmt::Display, path::Path};
+use std::{collections::HashMap, fmt::Display, ops::Range};
+use utoipa::ToSchema;
 
-use crate::time::Time;
+use crate::{
+    data_dir::DataDir,
+    duration::Duration,
+    error::{Error, Result},
+    time::Time,
+};
 
-#[derive(Serialize, Deserialize, Clone, PartialEq)]
+#[derive(Serialize, Deserialize, ToSchema, Clone, PartialEq, Debug)]
 pub enum TaskStaus {
     Incomplete,
     InProgress,
+    Paused,
     Complete,
 }
 
@@ -15,20 +22,117 @@ impl Display for TaskStaus {
         match self {
             TaskStaus::Incomplete => write!(f, "Incomplete"),
             TaskStaus::InProgress => write!(f, "In Progress"),
+            TaskStaus::Paused => write!(f, "Paused"),
             TaskStaus::Complete => write!(f, "Complete"),
         }
     }
 }
 
-#[derive(Serialize, Deserialize, Clone)]
+#[derive(Serialize, Deserialize, ToSchema, Clone, PartialEq)]
+pub struct Image {
+    pub name: String,
+    pub path: String,
+}
+
+/// A stretch of time spent working on a task. `end` is `None` while the
+/// session is still running.
+///
+/// Everyone tracks their own sessions, so several people can work on a task
+/// at the same time. `user` is `None` for sessions started without an
+/// account, those belong to everyone.
+#[derive(Serialize, Deserialize, ToSchema, Clone, PartialEq)]
+pub struct Session {
+    pub start: Time,
+    pub end: Option<Time>,
+    pub user: Option<String>,
+}
+
+impl Session {
+    /// Without a user every session matches, a session without a user only
+    /// belongs to nobody in particular.
+    fn belongs_to(&self, user: Option<&str>) -> bool {
+        user.is_none() || self.user.as_deref() == user
+    }
+}
+
+/// Records a completed task being moved back to work.
+#[derive(Serialize, Deserialize, ToSchema, Clone, PartialEq)]
+pub struct Reopening {
+    pub time: Time,
+    pub reason: Option<String>,
+}
+
+/// One version of the summary, numbered from 1 in the order they were
+/// written. The markdown itself is kept in the data directory.
+#[derive(Serialize, Deserialize, ToSchema, Clone, PartialEq, Debug)]
+pub struct SummaryRevision {
+    pub number: u64,
+    pub author: Option<String>,
+    pub time: Time,
+    /// The older revision this one brought back
+    pub restored: Option<u64>,
+}
+
+/// How a line changed from one revision to the other.
+#[derive(Serialize, Deserialize, ToSchema, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum Change {
+    Same,
+    Added,
+    Removed,
+}
+
+#[derive(Serialize, Deserialize, ToSchema, Clone, PartialEq, Debug)]
+pub struct DiffLine {
+    pub change: Change,
+    pub line: String,
+}
+
+/// Line by line differences from `old` to `new`, unchanged lines included. A
+/// missing newline at the end does not count as a change.
+pub fn diff_lines(old: &str, new: &str) -> Vec<DiffLine> {
+    let old: Vec<&str> = old.lines().collect();
+    let new: Vec<&str> = new.lines().collect();
+    similar::TextDiff::from_slices(&old, &new)
+        .iter_all_changes()
+        .map(|change| DiffLine {
+            change: match change.tag() {
+                similar::ChangeTag::Equal => Change::Same,
+                similar::ChangeTag::Insert => Change::Added,
+                similar::ChangeTag::Delete => Change::Removed,
+            },
+            line: change.value().to_string(),
+        })
+        .collect()
+}
+
+/// Where an image is linked in a summary.
+pub struct ImageLink<'a> {
+    pub name: &'a str,
+    /// The byte range of the link target
+    pub path: Range<usize>,
+}
+
+#[derive(Serialize, Deserialize, ToSchema, Clone, PartialEq)]
 pub struct Task {
-    id: u64,
-    parent_id: Option<u64>,
-    name: String,
-    status: TaskStaus,
-    start_time: Option<Time>,
-    end_time: Option<Time>,
-    summary: Option<String>,
+    pub(crate) id: u64,
+    pub(crate) parent_id: Option<u64>,
+    pub(crate) name: String,
+    pub(crate) status: TaskStaus,
+    pub(crate) sessions: Vec<Session>,
+    pub(crate) summary: Option<String>,
+    /// Every summary written so far, the last one is the current one
+    pub(crate) summary_revisions: Vec<SummaryRevision>,
+    pub(crate) images: Vec<Image>,
+    pub(crate) archived: bool,
+    pub(crate) reopened: Vec<Reopening>,
+    /// Who added the task, `None` without accounts
+    pub(crate) creator: Option<String>,
+    /// Who is supposed to work on it
+    pub(crate) assignee: Option<String>,
+    /// Bumped by every change, clients send it back to detect conflicting
+    /// edits
+    pub(crate) revision: u64,
 }
 
 impl Task {
@@ -38,81 +142,404 @@ impl Task {
             parent_id,
             name,
             status: TaskStaus::Incomplete,
-            start_time: None,
-            end_time: None,
+            sessions: Vec::new(),
             summary: None,
+            summary_revisions: Vec::new(),
+            images: Vec::new(),
+            archived: false,
+            reopened: Vec::new(),
+            creator: None,
+            assignee: None,
+            revision: 0,
         }
     }
 
-    pub fn start(&mut self) {
+    /// Starts a session for the user, or joins the people already working on
+    /// it.
+    /// Returns whether anything changed, starting again while the user's
+    /// session is running is a no-op.
+    pub fn start(&mut self, user: Option<&str>) -> Result<bool> {
+        if self.status == TaskStaus::Complete {
+            return Err(Error::InvalidState("Task is already complete".to_string()));
+        }
+        if !self.open_session(user) && self.status == TaskStaus::InProgress {
+            return Ok(false);
+        }
         self.status = TaskStaus::InProgress;
-        self.start_time = Some(Time::now());
+        self.touch();
+        Ok(true)
     }
 
-    async fn get_images(&self, summary: &str) -> HashMap<String, String> {
-        let mut images = HashMap::new();
-        for line in summary.lines() {
-            if line.contains("![") {
-                let mut split = line.split("](");
-                let image = split.next().unwrap().split('[').nth(1).unwrap();
-                let path = split.next().unwrap().split(')').next().unwrap();
-                if (path.starts_with("http")) || (path.starts_with("images")) {
-                    continue;
-                }
-                images.insert(image.to_string(), path.to_string());
+    /// Ends the session of the user, without a user every running session.
+    /// The task stays in progress while someone else is still working on it.
+    pub fn pause(&mut self, user: Option<&str>) -> Result<()> {
+        if self.status != TaskStaus::InProgress {
+            return Err(Error::InvalidState(
+                "Only tasks in progress can be paused".to_string(),
+            ));
+        }
+        if !self.close_sessions(user) {
+            return Err(Error::InvalidState(format!(
+                "{} has no running session on this task",
+                user.unwrap_or("Nobody")
+            )));
+        }
+        if self.running_sessions().next().is_none() {
+            self.status = TaskStaus::Paused;
+        }
+        self.touch();
+        Ok(())
+    }
+
+    pub fn resume(&mut self, user: Option<&str>) -> Result<()> {
+        if self.status != TaskStaus::Paused {
+            return Err(Error::InvalidState(
+                "Only paused tasks can be resumed".to_string(),
+            ));
+        }
+        self.open_session(user);
+        self.status = TaskStaus::InProgress;
+        self.touch();
+        Ok(())
+    }
+
+    /// Moves a complete task back to incomplete. Sessions and the summary are
+    /// kept.
+    pub fn reopen(&mut self, reason: Option<String>) -> Result<()> {
+        if self.status != TaskStaus::Complete {
+            return Err(Error::InvalidState(
+                "Only complete tasks can be reopened".to_string(),
+            ));
+        }
+        self.status = TaskStaus::Incomplete;
+        self.touch();
+        self.reopened.push(Reopening {
+            time: Time::now(),
+            reason,
+        });
+        Ok(())
+    }
+
+    /// Marks the task as changed.
+    pub fn touch(&mut self) {
+        self.revision += 1;
+    }
+
+    /// Returns whether a session was opened, the user may already have one.
+    fn open_session(&mut self, user: Option<&str>) -> bool {
+        if self.running_session(user).is_some() {
+            return false;
+        }
+        self.sessions.push(Session {
+            start: Time::now(),
+            end: None,
+            user: user.map(str::to_string),
+        });
+        true
+    }
+
+    /// Returns whether there was a session to close.
+    fn close_sessions(&mut self, user: Option<&str>) -> bool {
+        let now = Time::now();
+        let mut closed = false;
+        for session in self.sessions.iter_mut() {
+            if session.end.is_none() && session.belongs_to(user) {
+                session.end = Some(now);
+                closed = true;
             }
         }
-        images
+        closed
     }
 
-    pub async fn stop(&mut self, summary: Option<String>) -> Option<HashMap<String, String>> {
-        self.status = TaskStaus::Complete;
-        self.end_time = Some(Time::now());
-        if let Some(summary) = summary {
-            if !Path::exists(Path::new("summaries")) {
-                async_fs::create_dir("summaries").await.unwrap();
+    fn running_sessions(&self) -> impl Iterator<Item = &Session> {
+        self.sessions.iter().filter(|s| s.end.is_none())
+    }
+
+    /// The session of the user that is still running.
+    pub fn running_session(&self, user: Option<&str>) -> Option<&Session> {
+        self.running_sessions().find(|s| s.user.as_deref() == user)
+    }
+
+    /// Total time spent on the task over all sessions, counting a running
+    /// session up to now.
+    pub fn tracked_time(&self) -> Duration {
+        let now = Time::now();
+        self.sessions
+            .iter()
+            .map(|s| s.end.unwrap_or(now) - s.start)
+            .sum()
+    }
+
+    /// Time the user spent on the task.
+    pub fn tracked_time_of(&self, user: &str) -> Duration {
+        let now = Time::now();
+        self.sessions
+            .iter()
+            .filter(|s| s.user.as_deref() == Some(user))
+            .map(|s| s.end.unwrap_or(now) - s.start)
+            .sum()
+    }
+
+    /// Every `![name](path)` in the summary, in order. Links do not span
+    /// lines.
+    pub fn image_links(summary: &str) -> Vec<ImageLink<'_>> {
+        let mut links = Vec::new();
+        let mut offset = 0;
+        while let Some(found) = summary[offset..].find("![") {
+            let name_start = offset + found + 2;
+            let line_end = summary[name_start..]
+                .find('\n')
+                .map_or(summary.len(), |end| name_start + end);
+            let line = &summary[name_start..line_end];
+            let Some(name_end) = line.find("](") else {
+                offset = name_start;
+                continue;
+            };
+            let path_start = name_start + name_end + 2;
+            let Some(path_end) = summary[path_start..line_end].find(')') else {
+                offset = name_start;
+                continue;
+            };
+            let path = path_start..path_start + path_end;
+            links.push(ImageLink {
+                name: &summary[name_start..name_start + name_end],
+                path: path.clone(),
+            });
+            offset = path.end + 1;
+        }
+        links
+    }
+
+    /// Local images referenced by the summary, by name. Links that are
+    /// already uploaded or point to the web are left alone.
+    pub fn local_images(summary: &str) -> HashMap<String, String> {
+        Task::image_links(summary)
+            .into_iter()
+            .map(|link| (link.name, &summary[link.path]))
+            .filter(|(_, path)| !path.starts_with("http") && !path.starts_with("images"))
+            .map(|(name, path)| (name.to_string(), path.to_string()))
+            .collect()
+    }
+
+    /// Points the links to the images named in `paths` at their new paths.
+    pub fn relink_images(summary: &str, paths: &HashMap<&str, &str>) -> String {
+        let mut relinked = String::new();
+        let mut copied = 0;
+        for link in Task::image_links(summary) {
+            if let Some(path) = paths.get(link.name) {
+                relinked.push_str(&summary[copied..link.path.start]);
+                relinked.push_str(path);
+                copied = link.path.end;
             }
+        }
+        relinked.push_str(&summary[copied..]);
+        relinked
+    }
 
-            let summary_images = self.get_images(&summary).await;
+    /// Completes the task. A summary linking local images leaves the task
+    /// untouched and returns them, it completes once they are uploaded.
+    pub async fn stop(
+        &mut self,
+        summary: Option<String>,
+        by: Option<&str>,
+        data: &DataDir,
+    ) -> Result<Option<HashMap<String, String>>> {
+        if let Some(summary) = summary {
+            let summary_images = Task::local_images(&summary);
             if !summary_images.is_empty() {
-                if !Path::exists(Path::new("temp")) {
-                    async_fs::create_dir("temp").await.unwrap();
-                }
-                let temp_md = format!("temp/{}.md", self.id);
-                async_fs::write(&temp_md, summary).await.unwrap();
-                return Some(summary_images);
+                data.write(&DataDir::pending_summary(self.id), summary)
+                    .await?;
+                // The task only completes once the images have been uploaded
+                return Ok(Some(summary_images));
             }
 
-            let summary_text = markdown::to_html(&summary);
-            async_fs::write(format!("summaries/{}.html", self.id), summary_text)
-                .await
-                .unwrap();
-            self.summary = Some(format!("summaries/{}.html", self.id));
-            return None;
+            self.write_summary(summary, by, None, data).await?;
         }
-        None
+        self.status = TaskStaus::Complete;
+        self.touch();
+        self.close_sessions(None);
+        Ok(None)
+    }
+
+    /// Replaces the summary, in any status. Local images can not be waited
+    /// for here, they have to be uploaded as attachments and linked first.
+    pub async fn set_summary(
+        &mut self,
+        summary: String,
+        by: Option<&str>,
+        data: &DataDir,
+    ) -> Result<()> {
+        let mut images: Vec<String> = Task::local_images(&summary).into_keys().collect();
+        if !images.is_empty() {
+            images.sort();
+            return Err(Error::BadRequest(format!(
+                "The summary links local images {}, upload them as attachments and link those",
+                images.join(", ")
+            )));
+        }
+        self.write_summary(summary, by, None, data).await?;
+        self.touch();
+        Ok(())
+    }
+
+    /// Makes an older revision the current summary again. This adds a
+    /// revision, the ones after it are kept.
+    pub async fn restore_summary(
+        &mut self,
+        number: u64,
+        by: Option<&str>,
+        data: &DataDir,
+    ) -> Result<()> {
+        let summary = self.read_summary_revision(number, data).await?;
+        self.write_summary(summary, by, Some(number), data).await?;
+        self.touch();
+        Ok(())
+    }
+
+    /// The markdown of one of the revisions.
+    pub async fn read_summary_revision(&self, number: u64, data: &DataDir) -> Result<String> {
+        let not_found = || {
+            Error::NotFound(format!(
+                "Task {} has no summary revision {}",
+                self.id, number
+            ))
+        };
+        if !self.summary_revisions.iter().any(|r| r.number == number) {
+            return Err(not_found());
+        }
+        async_fs::read_to_string(data.resolve(&DataDir::summary_revision(self.id, number))?)
+            .await
+            .map_err(|_| not_found())
+    }
+
+    /// Keeps the markdown next to its rendering so it can be edited later,
+    /// and a copy of it as a new revision.
+    async fn write_summary(
+        &mut self,
+        summary: String,
+        by: Option<&str>,
+        restored: Option<u64>,
+        data: &DataDir,
+    ) -> Result<()> {
+        let number = self.summary_revisions.last().map_or(1, |r| r.number + 1);
+        data.write(&DataDir::summary_revision(self.id, number), &summary)
+            .await?;
+        let path = DataDir::summary(self.id);
+        data.write(&path, markdown::to_html(&summary)).await?;
+        data.write(&DataDir::summary_source(self.id), summary)
+            .await?;
+        self.summary = Some(path);
+        self.summary_revisions.push(SummaryRevision {
+            number,
+            author: by.map(str::to_string),
+            time: Time::now(),
+            restored,
+        });
+        Ok(())
     }
 
     pub fn rename(&mut self, name: String) {
         self.name = name;
+        self.touch();
+    }
+
+    /// Attaches the image, returning the path of an image with the same name
+    /// it replaces.
+    pub fn attach_image(&mut self, name: String, path: String) -> Option<String> {
+        let replaced = self
+            .images
+            .iter()
+            .position(|image| image.name == name)
+            .map(|index| self.images.remove(index).path);
+        self.images.push(Image { name, path });
+        self.touch();
+        replaced
+    }
+
+    /// Deletes the summary with its markdown and revisions, uploaded images
+    /// and any pending temp summary belonging to this task.
+    pub async fn remove_files(&self, data: &DataDir) {
+        let mut files = vec![
+            DataDir::pending_summary(self.id),
+            DataDir::summary_source(self.id),
+        ];
+        files.extend(self.summary.clone());
+        files.extend(
+            self.summary_revisions
+                .iter()
+                .map(|r| DataDir::summary_revision(self.id, r.number)),
+        );
+        files.extend(self.images.iter().map(|image| image.path.clone()));
+        for file in files {
+            let file = match data.resolve(&file) {
+                Ok(file) => file,
+                Err(e) => {
+                    tracing::warn!("Not removing {}: {}", file, e);
+                    continue;
+                }
+            };
+            if file.exists() {
+                if let Err(e) = async_fs::remove_file(&file).await {
+                    tracing::warn!("Failed to remove {}: {}", file.display(), e);
+                }
+            }
+        }
     }
 
     pub fn get_id(&self) -> u64 {
         self.id
     }
 
-    #[allow(dead_code)]
     pub fn get_parent_id(&self) -> Option<u64> {
         self.parent_id
     }
 
-    #[allow(dead_code)]
     pub fn get_name(&self) -> &str {
         &self.name
     }
 
-    #[allow(dead_code)]
     pub fn get_status(&self) -> &TaskStaus {
         &self.status
     }
+
+    pub fn get_sessions(&self) -> &[Session] {
+        &self.sessions
+    }
+
+    pub fn get_summary(&self) -> Option<&str> {
+        self.summary.as_deref()
+    }
+
+    pub fn get_summary_revisions(&self) -> &[SummaryRevision] {
+        &self.summary_revisions
+    }
+
+    pub fn get_images(&self) -> &[Image] {
+        &self.images
+    }
+
+    pub fn get_revision(&self) -> u64 {
+        self.revision
+    }
+
+    pub fn is_archived(&self) -> bool {
+        self.archived
+    }
+
+    pub fn get_creator(&self) -> Option<&str> {
+        self.creator.as_deref()
+    }
+
+    pub fn get_assignee(&self) -> Option<&str> {
+        self.assignee.as_deref()
+    }
+
+    /// Assigned to the user, or added by them and not assigned to anyone
+    /// else.
+    pub fn is_for(&self, user: &str) -> bool {
+        match self.get_assignee() {
+            Some(assignee) => assignee == user,
+            None => self.get_creator() == Some(user),
+        }
+    }
 }
@@ -1,13 +1,18 @@
-use crate::{duration::Duration, Result};
+use crate::duration::Duration;
+#[cfg(test)]
+use crate::error::{Error, Result};
 use serde::{Deserialize, Serialize};
 use std::{
     fmt::Display,
     ops::{Add, AddAssign, Sub, SubAssign},
 };
+use utoipa::ToSchema;
 
-#[derive(Debug, Serialize, Deserialize, Default, Clone, Copy, PartialEq, PartialOrd)]
+/// A calendar date and time of day in the proleptic Gregorian calendar.
+/// Stored times are always in UTC, use `Zone::to_local` to display them.
+#[derive(Debug, Serialize, Deserialize, ToSchema, Default, Clone, Copy, PartialEq, PartialOrd)]
 pub struct Time {
-    pub year: u32,
+    pub year: i32,
     pub month: u8,
     pub day: u8,
     pub hour: u8,
@@ -15,27 +20,112 @@ pub struct Time {
     pub second: u8,
 }
 
+const SECONDS_PER_DAY: i64 = 24 * 60 * 60;
+
 impl Time {
-    pub fn new(year: u32, month: u8, day: u8, hour: u8, minute: u8, second: u8) -> Result<Self> {
-        if !matches!(month, 1..=12) {
-            return Err("Invalid month".into());
-        }
-        let days_month = if Time::is_leap_year(year) {
-            [31, 29, 31, 30, 31, 30, 31, 31, 30, 31, 31, 31]
+    /// The earliest and latest times whose year fits, arithmetic saturates at
+    /// them.
+    const MIN: Time = Time {
+        year: i32::MIN,
+        month: 1,
+        day: 1,
+        hour: 0,
+        minute: 0,
+        second: 0,
+    };
+    const MAX: Time = Time {
+        year: i32::MAX,
+        month: 12,
+        day: 31,
+        hour: 23,
+        minute: 59,
+        second: 59,
+    };
+
+    /// Days since 1970-01-01, negative before it.
+    ///
+    /// Counts in 400 year eras starting on March 1st so the leap day is the
+    /// last day of the year, see <http://howardhinnant.github.io/date_algorithms.html>.
+    fn days_from_civil(year: i32, month: u8, day: u8) -> i64 {
+        let year = year as i64 - if month <= 2 { 1 } else { 0 };
+        let era = year.div_euclid(400);
+        let year_of_era = year.rem_euclid(400);
+        let month = month as i64;
+        let day_of_year = (153 * (month + if month > 2 { -3 } else { 9 }) + 2) / 5 + day as i64 - 1;
+        let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+        era * 146097 + day_of_era - 719468
+    }
+
+    /// Inverse of `days_from_civil`.
+    fn civil_from_days(days: i64) -> (i32, u8, u8) {
+        let days = days + 719468;
+        let era = days.div_euclid(146097);
+        let day_of_era = days.rem_euclid(146097);
+        let year_of_era =
+            (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+        let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+        let shifted_month = (5 * day_of_year + 2) / 153;
+        let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+        let month = if shifted_month < 10 {
+            shifted_month + 3
         } else {
-            [31, 28, 31, 30, 31, 30, 31, 31, 30, 31, 31, 31]
+            shifted_month - 9
         };
-        if day < 1 || day > days_month[month as usize - 1] {
-            return Err("Invalid day".into());
+        let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+        (year as i32, month as u8, day as u8)
+    }
+
+    /// Seconds since the unix epoch, negative before 1970.
+    pub fn to_iso(self) -> i64 {
+        let days = Time::days_from_civil(self.year, self.month, self.day);
+        days * SECONDS_PER_DAY
+            + self.hour as i64 * 60 * 60
+            + self.minute as i64 * 60
+            + self.second as i64
+    }
+
+    pub fn from_iso(seconds: i64) -> Self {
+        let days = seconds.div_euclid(SECONDS_PER_DAY);
+        let seconds = seconds.rem_euclid(SECONDS_PER_DAY);
+        let (year, month, day) = Time::civil_from_days(days);
+        Self {
+            year,
+            month,
+            day,
+            hour: (seconds / (60 * 60)) as u8,
+            minute: (seconds / 60 % 60) as u8,
+            second: (seconds % 60) as u8,
+        }
+    }
+
+    /// The current time in UTC.
+    pub fn now() -> Self {
+        use std::time::SystemTime;
+        let now = SystemTime::now();
+        let unix_time = now.duration_since(SystemTime::UNIX_EPOCH).unwrap();
+        Time::from_iso(unix_time.as_secs() as i64)
+    }
+}
+
+/// Validated construction from the fields, the app itself only gets times
+/// from timestamps.
+#[cfg(test)]
+impl Time {
+    pub fn new(year: i32, month: u8, day: u8, hour: u8, minute: u8, second: u8) -> Result<Self> {
+        if !matches!(month, 1..=12) {
+            return Err(Error::BadRequest("Invalid month".to_string()));
+        }
+        if day < 1 || day > Time::days_in_month(year, month) {
+            return Err(Error::BadRequest("Invalid day".to_string()));
         }
         if hour > 23 {
-            return Err("Invalid hour".into());
+            return Err(Error::BadRequest("Invalid hour".to_string()));
         }
         if minute > 59 {
-            return Err("Invalid minute".into());
+            return Err(Error::BadRequest("Invalid minute".to_string()));
         }
         if second > 59 {
-            return Err("Invalid second".into());
+            return Err(Error::BadRequest("Invalid second".to_string()));
         }
         Ok(Self {
             year,
@@ -47,114 +137,27 @@ impl Time {
         })
     }
 
-    fn is_leap_year(year: u32) -> bool {
+    fn is_leap_year(year: i32) -> bool {
         (year % 4 == 0 && year % 100 != 0) || year % 400 == 0
     }
 
-    pub fn to_iso(&self) -> u64 {
-        let unix_start = Time::new(1970, 1, 1, 0, 0, 0).unwrap();
-        let days_month = [31, 28, 31, 30, 31, 30, 31, 31, 30, 31, 30, 31];
-        const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
-        let mut days = 0;
-        for year in unix_start.year..self.year {
-            days += if Time::is_leap_year(year) { 366 } else { 365 };
-        }
-        for month in 1..self.month {
-            days += days_month[month as usize - 1] as u64;
-        }
-        if self.month > 2 && Time::is_leap_year(self.year) {
-            days += 1;
-        }
-        days += self.day as u64 - 1;
-        let mut seconds = days * SECONDS_PER_DAY;
-        seconds += self.hour as u64 * 60 * 60;
-        seconds += self.minute as u64 * 60;
-        seconds += self.second as u64;
-        seconds
-    }
-
-    pub fn from_iso(seconds: u64) -> Self {
-        let unix_start = Time::new(1970, 1, 1, 0, 0, 0).unwrap();
-        let mut seconds = seconds;
-        let mut year = unix_start.year;
-        let mut month = 1;
-        let mut day = 1;
-        let mut hour = 0;
-        let mut minute = 0;
-        const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
-        while seconds >= SECONDS_PER_DAY {
-            let days = if Time::is_leap_year(year) { 366 } else { 365 };
-            if seconds >= days * SECONDS_PER_DAY {
-                seconds -= days * SECONDS_PER_DAY;
-                year += 1;
-            } else {
-                break;
-            }
-        }
-        let days_month = if Time::is_leap_year(year) {
-            [31, 29, 31, 30, 31, 30, 31, 31, 30, 31, 31, 31]
-        } else {
-            [31, 28, 31, 30, 31, 30, 31, 31, 30, 31, 31, 31]
-        };
-        while seconds >= SECONDS_PER_DAY {
-            if seconds >= days_month[month as usize - 1] as u64 * SECONDS_PER_DAY {
-                seconds -= days_month[month as usize - 1] as u64 * SECONDS_PER_DAY;
-                month += 1;
-            } else {
-                break;
-            }
-        }
-        while seconds >= SECONDS_PER_DAY {
-            if seconds >= SECONDS_PER_DAY {
-                seconds -= SECONDS_PER_DAY;
-                day += 1;
-            } else {
-                break;
-            }
-        }
-        while seconds >= 60 * 60 {
-            if seconds >= 60 * 60 {
-                seconds -= 60 * 60;
-                hour += 1;
-            } else {
-                break;
-            }
-        }
-        while seconds >= 60 {
-            if seconds >= 60 {
-                seconds -= 60;
-                minute += 1;
-            } else {
-                break;
-            }
-        }
-        let second = seconds as u8;
-        Self {
-            year,
-            month: month as u8,
-            day: day as u8,
-            hour: hour as u8,
-            minute: minute as u8,
-            second,
+    fn days_in_month(year: i32, month: u8) -> u8 {
+        match month {
+            2 if Time::is_leap_year(year) => 29,
+            2 => 28,
+            4 | 6 | 9 | 11 => 30,
+            _ => 31,
         }
     }
-
-    pub fn now() -> Self {
-        use std::time::SystemTime;
-        let gmt_offset = Duration::from_seconds(5 * 60 * 60 + 30 * 60);
-        let now = SystemTime::now();
-        let unix_time = now.duration_since(SystemTime::UNIX_EPOCH).unwrap();
-        let seconds = unix_time.as_secs() + gmt_offset.to_seconds();
-        Time::from_iso(seconds)
-    }
 }
 
 impl Add<Duration> for Time {
     type Output = Self;
 
     fn add(self, rhs: Duration) -> Self::Output {
-        let seconds = self.to_iso() + rhs.to_seconds();
-        Time::from_iso(seconds)
+        let rhs = i64::try_from(rhs.to_seconds()).unwrap_or(i64::MAX);
+        let seconds = self.to_iso().saturating_add(rhs);
+        Time::from_iso(seconds.min(Time::MAX.to_iso()))
     }
 }
 
@@ -168,8 +171,18 @@ impl Sub<Duration> for Time {
     type Output = Self;
 
     fn sub(self, rhs: Duration) -> Self::Output {
-        let seconds = self.to_iso() - rhs.to_seconds();
-        Time::from_iso(seconds)
+        let rhs = i64::try_from(rhs.to_seconds()).unwrap_or(i64::MAX);
+        let seconds = self.to_iso().saturating_sub(rhs);
+        Time::from_iso(seconds.max(Time::MIN.to_iso()))
+    }
+}
+
+/// The time elapsed between two instants, zero if `rhs` is later.
+impl Sub for Time {
+    type Output = Duration;
+
+    fn sub(self, rhs: Self) -> Self::Output {
+        Duration::from_seconds(self.to_iso().saturating_sub(rhs.to_iso()).max(0) as u64)
     }
 }
 
@@ -210,22 +223,121 @@ mod tests {
 
     #[test]
     fn test_leaps() {
-        assert_eq!(Time::is_leap_year(2020), true);
-        assert_eq!(Time::is_leap_year(2021), false);
-        assert_eq!(Time::is_leap_year(2022), false);
-        assert_eq!(Time::is_leap_year(2023), false);
-        assert_eq!(Time::is_leap_year(2024), true);
-        assert_eq!(Time::is_leap_year(1804), true);
-        assert_eq!(Time::is_leap_year(1800), false);
-        assert_eq!(Time::is_leap_year(1932), true);
+        assert!(Time::is_leap_year(2020));
+        assert!(!Time::is_leap_year(2021));
+        assert!(!Time::is_leap_year(2022));
+        assert!(!Time::is_leap_year(2023));
+        assert!(Time::is_leap_year(2024));
+        assert!(Time::is_leap_year(1804));
+        assert!(!Time::is_leap_year(1800));
+        assert!(Time::is_leap_year(1932));
     }
 
     #[test]
     fn test_iso() {
-        let time = Time::new(2023, 11, 27, 3, 18, 52).to_iso();
+        let time = Time::new(2023, 11, 27, 3, 18, 52).unwrap().to_iso();
         let iso = 1701055132;
         assert_eq!(iso, time);
         let time = Time::from_iso(iso);
-        assert_eq!(time, Time::new(2023, 11, 27, 3, 18, 52));
+        assert_eq!(time, Time::new(2023, 11, 27, 3, 18, 52).unwrap());
+    }
+
+    #[test]
+    fn test_month_lengths() {
+        assert!(Time::new(2023, 11, 31, 0, 0, 0).is_err());
+        assert!(Time::new(2023, 12, 31, 0, 0, 0).is_ok());
+        assert!(Time::new(2023, 2, 29, 0, 0, 0).is_err());
+        assert!(Time::new(2024, 2, 29, 0, 0, 0).is_ok());
+        assert_eq!(
+            Time::from_iso(1701388800),
+            Time::new(2023, 12, 1, 0, 0, 0).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_before_epoch() {
+        assert_eq!(Time::new(1969, 12, 31, 23, 59, 59).unwrap().to_iso(), -1);
+        assert_eq!(
+            Time::from_iso(-14182940),
+            Time::new(1969, 7, 20, 20, 17, 40).unwrap()
+        );
+        let epoch = Time::new(1970, 1, 1, 0, 0, 0).unwrap();
+        assert_eq!(
+            epoch - Duration::from_seconds(60),
+            Time::new(1969, 12, 31, 23, 59, 0).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_saturation() {
+        let now = Time::new(2023, 11, 27, 3, 18, 52).unwrap();
+        let forever = Duration::from_seconds(u64::MAX);
+        assert_eq!(now + forever, Time::MAX);
+        assert_eq!(now - forever, Time::MIN);
+        assert_eq!(Time::MAX + Duration::from_seconds(1), Time::MAX);
+        assert_eq!(Time::from_iso(Time::MIN.to_iso()), Time::MIN);
+        let almost = Duration::from_seconds(i64::MAX as u64 + 1);
+        assert!(now + almost > now);
+        assert!(now - almost < now);
+    }
+
+    /// Checks every day from 1600 to 2400 against chrono.
+    #[test]
+    fn test_every_day_against_chrono() {
+        use chrono::{Datelike, NaiveDate};
+        let first = Time::days_from_civil(1600, 1, 1);
+        let last = Time::days_from_civil(2400, 12, 31);
+        for days in first..=last {
+            let time = Time::from_iso(days * SECONDS_PER_DAY);
+            let date = NaiveDate::from_num_days_from_ce_opt((days + 719163) as i32).unwrap();
+            assert_eq!(
+                (time.year, time.month as u32, time.day as u32),
+                (date.year(), date.month(), date.day())
+            );
+            assert_eq!(Time::days_from_civil(time.year, time.month, time.day), days);
+        }
+    }
+
+    mod properties {
+        use super::*;
+        use proptest::prelude::*;
+
+        /// Seconds covering the years 1000 to 3000.
+        const RANGE: std::ops::Range<i64> = -30_610_224_000..32_535_216_000;
+
+        proptest! {
+            #[test]
+            fn seconds_roundtrip(seconds in RANGE) {
+                let time = Time::from_iso(seconds);
+                prop_assert_eq!(time.to_iso(), seconds);
+                let valid = Time::new(
+                    time.year, time.month, time.day, time.hour, time.minute, time.second,
+                );
+                prop_assert_eq!(valid.unwrap(), time);
+            }
+
+            #[test]
+            fn fields_roundtrip(
+                year in 1000i32..3000,
+                month in 1u8..=12,
+                day in 1u8..=31,
+                hour in 0u8..24,
+                minute in 0u8..60,
+                second in 0u8..60,
+            ) {
+                prop_assume!(day <= Time::days_in_month(year, month));
+                let time = Time::new(year, month, day, hour, minute, second).unwrap();
+                prop_assert_eq!(Time::from_iso(time.to_iso()), time);
+            }
+
+            #[test]
+            fn duration_arithmetic(seconds in RANGE, offset in 0u64..10_000_000_000) {
+                let time = Time::from_iso(seconds);
+                let duration = Duration::from_seconds(offset);
+                prop_assert_eq!(time + duration - duration, time);
+                prop_assert_eq!((time + duration) - time, duration);
+                prop_assert!(time + duration >= time);
+            }
+        }
     }
 }
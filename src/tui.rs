@@ -0,0 +1,575 @@
+//! `tasks-todo tui`: the task tree in the terminal, for when there is no
+//! browser around. It drives the same [`Client`] as the other commands, so it
+//! works on the local store or against a server.
+
+use std::collections::HashSet;
+
+use ratatui::{
+    crossterm::event::{self, Event, KeyCode, KeyEvent, KeyEventKind},
+    layout::{Constraint, Layout},
+    style::{Color, Modifier, Style},
+    text::{Line, Span},
+    widgets::{Block, Borders, List, ListItem, ListState, Paragraph, Wrap},
+    DefaultTerminal, Frame,
+};
+
+use crate::{
+    cli::{self, Client},
+    error::Result,
+    task::{Task, TaskStaus},
+    zone::Zone,
+};
+
+/// How often the tasks are reloaded to pick up changes made elsewhere.
+const REFRESH: std::time::Duration = std::time::Duration::from_secs(2);
+
+const HELP: &str = "↑↓ move  ←→ fold  s start  p pause  c complete  r rename  a add child  A add  v summary  . archived  q quit";
+
+pub async fn run(client: &Client, zone: Zone) -> Result<()> {
+    let mut terminal = ratatui::try_init()?;
+    let mut tui = Tui::new();
+    tui.refresh(client).await;
+    let result = tui.run(client, zone, &mut terminal).await;
+    ratatui::try_restore()?;
+    result
+}
+
+struct Tui {
+    tasks: Vec<Task>,
+    collapsed: HashSet<u64>,
+    /// Index into `rows`
+    selected: usize,
+    archived: bool,
+    mode: Mode,
+    /// Outcome of the last action
+    message: String,
+}
+
+enum Mode {
+    Browse,
+    Input {
+        prompt: Prompt,
+        text: String,
+    },
+    Summary {
+        title: String,
+        text: String,
+        scroll: u16,
+    },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Prompt {
+    Rename(u64),
+    /// Add a task under the parent
+    Add(Option<u64>),
+}
+
+#[derive(Debug, PartialEq)]
+enum Action {
+    Quit,
+    Refresh,
+    Start(u64),
+    Pause(u64),
+    Complete(u64),
+    Rename(u64, String),
+    Add(Option<u64>, String),
+    ViewSummary(u64),
+}
+
+impl Tui {
+    fn new() -> Tui {
+        Tui {
+            tasks: Vec::new(),
+            collapsed: HashSet::new(),
+            selected: 0,
+            archived: false,
+            mode: Mode::Browse,
+            message: String::new(),
+        }
+    }
+
+    async fn run(
+        &mut self,
+        client: &Client,
+        zone: Zone,
+        terminal: &mut DefaultTerminal,
+    ) -> Result<()> {
+        loop {
+            terminal.draw(|frame| self.draw(frame, zone))?;
+            if !event::poll(REFRESH)? {
+                self.refresh(client).await;
+                continue;
+            }
+            let Event::Key(key) = event::read()? else {
+                continue;
+            };
+            if key.kind != KeyEventKind::Press {
+                continue;
+            }
+            match self.key(key) {
+                None => {}
+                Some(Action::Quit) => return Ok(()),
+                Some(Action::Complete(id)) => {
+                    // The editor needs the terminal to itself
+                    ratatui::try_restore()?;
+                    let summary = cli::edit_summary(id, "");
+                    *terminal = ratatui::try_init()?;
+                    let result = match summary {
+                        Ok(summary) => cli::complete(client, id, summary, ".".as_ref()).await,
+                        Err(e) => Err(e),
+                    };
+                    let result = match result {
+                        Ok(_) => client.save().await,
+                        Err(e) => Err(e),
+                    };
+                    self.report(result.map(|_| format!("Completed task {}", id)));
+                    self.refresh(client).await;
+                }
+                Some(action) => self.perform(client, action).await,
+            }
+        }
+    }
+
+    /// The visible rows of the tree.
+    fn rows(&self) -> Vec<(usize, &Task)> {
+        cli::walk_tree(&self.tasks, &self.collapsed)
+    }
+
+    fn selected_task(&self) -> Option<&Task> {
+        self.rows().get(self.selected).map(|(_, task)| *task)
+    }
+
+    fn has_children(&self, id: u64) -> bool {
+        self.tasks
+            .iter()
+            .any(|task| task.get_parent_id() == Some(id))
+    }
+
+    /// Updates the view for the key, returning what has to be done with the
+    /// tasks.
+    fn key(&mut self, key: KeyEvent) -> Option<Action> {
+        match &mut self.mode {
+            Mode::Browse => self.browse_key(key),
+            Mode::Input { prompt, text } => match key.code {
+                KeyCode::Esc => {
+                    self.mode = Mode::Browse;
+                    None
+                }
+                KeyCode::Enter => {
+                    let prompt = *prompt;
+                    let text = text.trim().to_string();
+                    self.mode = Mode::Browse;
+                    if text.is_empty() {
+                        return None;
+                    }
+                    Some(match prompt {
+                        Prompt::Rename(id) => Action::Rename(id, text),
+                        Prompt::Add(parent) => Action::Add(parent, text),
+                    })
+                }
+                KeyCode::Backspace => {
+                    text.pop();
+                    None
+                }
+                KeyCode::Char(c) => {
+                    text.push(c);
+                    None
+                }
+                _ => None,
+            },
+            Mode::Summary { scroll, .. } => {
+                match key.code {
+                    KeyCode::Esc | KeyCode::Char('q') | KeyCode::Char('v') => {
+                        self.mode = Mode::Browse
+                    }
+                    KeyCode::Down | KeyCode::Char('j') => *scroll = scroll.saturating_add(1),
+                    KeyCode::Up | KeyCode::Char('k') => *scroll = scroll.saturating_sub(1),
+                    KeyCode::PageDown | KeyCode::Char(' ') => *scroll = scroll.saturating_add(10),
+                    KeyCode::PageUp => *scroll = scroll.saturating_sub(10),
+                    _ => {}
+                }
+                None
+            }
+        }
+    }
+
+    fn browse_key(&mut self, key: KeyEvent) -> Option<Action> {
+        let rows = self.rows().len();
+        let selected = self.selected_task().map(|task| {
+            (
+                task.get_id(),
+                task.get_parent_id(),
+                task.get_name().to_string(),
+            )
+        });
+        match (key.code, selected) {
+            (KeyCode::Char('q') | KeyCode::Esc, _) => return Some(Action::Quit),
+            (KeyCode::Char('g'), _) => return Some(Action::Refresh),
+            (KeyCode::Char('.'), _) => {
+                self.archived = !self.archived;
+                return Some(Action::Refresh);
+            }
+            (KeyCode::Down | KeyCode::Char('j'), _) => {
+                self.selected = (self.selected + 1).min(rows.saturating_sub(1))
+            }
+            (KeyCode::Up | KeyCode::Char('k'), _) => {
+                self.selected = self.selected.saturating_sub(1)
+            }
+            (KeyCode::Char('A'), _) => {
+                self.mode = Mode::Input {
+                    prompt: Prompt::Add(None),
+                    text: String::new(),
+                }
+            }
+            (_, None) => {}
+            (KeyCode::Left | KeyCode::Char('h'), Some((id, parent, _))) => {
+                if self.has_children(id) && !self.collapsed.contains(&id) {
+                    self.collapsed.insert(id);
+                } else if let Some(parent) = parent {
+                    self.select(parent);
+                }
+            }
+            (KeyCode::Right | KeyCode::Char('l'), Some((id, _, _))) => {
+                self.collapsed.remove(&id);
+            }
+            (KeyCode::Enter, Some((id, _, _))) => self.toggle(id),
+            (KeyCode::Char('s'), Some((id, _, _))) => return Some(Action::Start(id)),
+            (KeyCode::Char('p'), Some((id, _, _))) => return Some(Action::Pause(id)),
+            (KeyCode::Char('c'), Some((id, _, _))) => return Some(Action::Complete(id)),
+            (KeyCode::Char('v'), Some((id, _, _))) => return Some(Action::ViewSummary(id)),
+            (KeyCode::Char('r'), Some((id, _, name))) => {
+                self.mode = Mode::Input {
+                    prompt: Prompt::Rename(id),
+                    text: name,
+                }
+            }
+            (KeyCode::Char('a'), Some((id, _, _))) => {
+                self.mode = Mode::Input {
+                    prompt: Prompt::Add(Some(id)),
+                    text: String::new(),
+                }
+            }
+            _ => {}
+        }
+        None
+    }
+
+    fn toggle(&mut self, id: u64) {
+        if !self.collapsed.remove(&id) && self.has_children(id) {
+            self.collapsed.insert(id);
+        }
+    }
+
+    /// Moves the selection to the task if it is visible.
+    fn select(&mut self, id: u64) {
+        if let Some(row) = self.rows().iter().position(|(_, task)| task.get_id() == id) {
+            self.selected = row;
+        }
+    }
+
+    async fn perform(&mut self, client: &Client, action: Action) {
+        let mutates = matches!(
+            action,
+            Action::Start(_) | Action::Pause(_) | Action::Rename(..) | Action::Add(..)
+        );
+        let result = match action {
+            Action::Quit | Action::Complete(_) => return,
+            Action::Refresh => Ok(String::new()),
+            Action::Start(id) => client
+                .start(id)
+                .await
+                .map(|_| format!("Started task {}", id)),
+            Action::Pause(id) => client
+                .pause(id)
+                .await
+                .map(|_| format!("Paused task {}", id)),
+            Action::Rename(id, name) => client
+                .rename(id, name)
+                .await
+                .map(|_| format!("Renamed task {}", id)),
+            Action::Add(parent, name) => match client.add(name, parent).await {
+                Ok(task) => {
+                    if let Some(parent) = parent {
+                        self.collapsed.remove(&parent);
+                    }
+                    self.refresh(client).await;
+                    self.select(task.get_id());
+                    Ok(format!("Added task {}", task.get_id()))
+                }
+                Err(e) => Err(e),
+            },
+            Action::ViewSummary(id) => match client.summary(id).await {
+                Ok(html) => {
+                    let title = self
+                        .tasks
+                        .iter()
+                        .find(|task| task.get_id() == id)
+                        .map_or_else(String::new, |task| task.get_name().to_string());
+                    self.mode = Mode::Summary {
+                        title,
+                        text: html_to_text(&html),
+                        scroll: 0,
+                    };
+                    Ok(String::new())
+                }
+                Err(e) => Err(e),
+            },
+        };
+        // Only mutations need to hit the disk, not every reload
+        let result = match result {
+            Ok(message) if mutates => client.save().await.map(|_| message),
+            result => result,
+        };
+        self.report(result);
+        self.refresh(client).await;
+    }
+
+    fn report(&mut self, result: Result<String>) {
+        self.message = match result {
+            Ok(message) => message,
+            Err(e) => format!("Error: {}", e),
+        };
+    }
+
+    /// Reloads the tasks, keeping the selected task selected.
+    async fn refresh(&mut self, client: &Client) {
+        let selected = self.selected_task().map(Task::get_id);
+        match client.tasks(self.archived).await {
+            Ok(tasks) => self.tasks = tasks,
+            Err(e) => self.message = format!("Error: {}", e),
+        }
+        let rows = self.rows().len();
+        self.selected = self.selected.min(rows.saturating_sub(1));
+        if let Some(id) = selected {
+            self.select(id);
+        }
+    }
+
+    fn draw(&self, frame: &mut Frame, zone: Zone) {
+        let [tree, status, help] = Layout::vertical([
+            Constraint::Min(1),
+            Constraint::Length(1),
+            Constraint::Length(1),
+        ])
+        .areas(frame.area());
+
+        if let Mode::Summary {
+            title,
+            text,
+            scroll,
+        } = &self.mode
+        {
+            let summary = Paragraph::new(text.as_str())
+                .block(Block::default().borders(Borders::ALL).title(title.as_str()))
+                .wrap(Wrap { trim: false })
+                .scroll((*scroll, 0));
+            frame.render_widget(summary, tree);
+        } else {
+            let items: Vec<ListItem> = self
+                .rows()
+                .into_iter()
+                .map(|(depth, task)| self.item(depth, task))
+                .collect();
+            let title = if self.archived {
+                "Tasks (with archived)"
+            } else {
+                "Tasks"
+            };
+            let list = List::new(items)
+                .block(Block::default().borders(Borders::ALL).title(title))
+                .highlight_style(Style::default().add_modifier(Modifier::REVERSED));
+            let mut state = ListState::default().with_selected(Some(self.selected));
+            frame.render_stateful_widget(list, tree, &mut state);
+        }
+
+        let status_line = match &self.mode {
+            Mode::Input { prompt, text } => {
+                let prompt = match prompt {
+                    Prompt::Rename(id) => format!("Rename task {}: ", id),
+                    Prompt::Add(Some(parent)) => format!("Add under task {}: ", parent),
+                    Prompt::Add(None) => "Add task: ".to_string(),
+                };
+                format!("{}{}_", prompt, text)
+            }
+            _ => match self.selected_task() {
+                Some(task) if self.message.is_empty() => task
+                    .get_sessions()
+                    .last()
+                    .map_or_else(String::new, |session| {
+                        format!("Last session {}", zone.to_local(session.start))
+                    }),
+                _ => self.message.clone(),
+            },
+        };
+        frame.render_widget(Paragraph::new(status_line), status);
+        frame.render_widget(
+            Paragraph::new(HELP).style(Style::default().fg(Color::DarkGray)),
+            help,
+        );
+    }
+
+    fn item(&self, depth: usize, task: &Task) -> ListItem<'static> {
+        let fold = if !self.has_children(task.get_id()) {
+            " "
+        } else if self.collapsed.contains(&task.get_id()) {
+            "▸"
+        } else {
+            "▾"
+        };
+        // Same symbols as the web UI
+        let (symbol, color) = match task.get_status() {
+            TaskStaus::Incomplete => ("●", Color::Red),
+            TaskStaus::InProgress => ("●", Color::Yellow),
+            TaskStaus::Paused => ("⏸", Color::Yellow),
+            TaskStaus::Complete => ("●", Color::Green),
+        };
+        let mut name = Style::default();
+        if task.is_archived() {
+            name = name.fg(Color::DarkGray);
+        }
+        ListItem::new(Line::from(vec![
+            Span::raw(format!("{}{} ", "  ".repeat(depth), fold)),
+            Span::styled(symbol, Style::default().fg(color)),
+            Span::styled(format!(" {}", task.get_name()), name),
+            Span::styled(
+                format!("  {}", task.tracked_time()),
+                Style::default().fg(Color::DarkGray),
+            ),
+        ]))
+    }
+}
+
+/// Turns a rendered summary back into readable text: headings and list items
+/// keep their markdown markers, other tags are dropped.
+fn html_to_text(html: &str) -> String {
+    let mut text = String::new();
+    let mut rest = html;
+    while let Some(start) = rest.find('<') {
+        text.push_str(&rest[..start]);
+        let Some(end) = rest[start..].find('>') else {
+            rest = &rest[start..];
+            break;
+        };
+        let tag = &rest[start + 1..start + end];
+        let name = tag
+            .split_whitespace()
+            .next()
+            .unwrap_or_default()
+            .to_lowercase();
+        match name.as_str() {
+            "h1" | "h2" | "h3" | "h4" | "h5" | "h6" => {
+                text.push_str(&"#".repeat(name[1..].parse().unwrap_or(1)));
+                text.push(' ');
+            }
+            "li" => text.push_str("- "),
+            "img" => {
+                let alt = tag
+                    .split_once("alt=\"")
+                    .and_then(|(_, alt)| alt.split_once('"'))
+                    .map_or("", |(alt, _)| alt);
+                text.push_str(&format!("[image {}]", alt));
+            }
+            // The blocks already end in a newline
+            "br" | "br/" => text.push('\n'),
+            _ => {}
+        }
+        rest = &rest[start + end + 1..];
+    }
+    text.push_str(rest);
+    let text = text
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&amp;", "&");
+    // Block ends right after each other only need one blank line
+    let mut lines: Vec<&str> = Vec::new();
+    for line in text.lines() {
+        if line.trim().is_empty() && lines.last().is_some_and(|last| last.trim().is_empty()) {
+            continue;
+        }
+        lines.push(line);
+    }
+    lines.join("\n").trim().to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app::App;
+    use ratatui::crossterm::event::KeyModifiers;
+
+    fn press(tui: &mut Tui, code: KeyCode) -> Option<Action> {
+        tui.key(KeyEvent::new(code, KeyModifiers::NONE))
+    }
+
+    fn tui() -> Tui {
+        let mut app = App::new();
+        let root = app.add_task("root".to_string(), None).unwrap();
+        let child = app.add_subtask(root, "child".to_string(), None).unwrap();
+        app.add_subtask(child, "grandchild".to_string(), None)
+            .unwrap();
+        app.add_task("other".to_string(), None).unwrap();
+        let mut tui = Tui::new();
+        tui.tasks = app.get_tasks().clone();
+        tui
+    }
+
+    #[test]
+    fn test_navigation() {
+        let mut tui = tui();
+        press(&mut tui, KeyCode::Down);
+        assert_eq!(tui.selected_task().unwrap().get_name(), "child");
+        // Folding hides the grandchild, folding again goes to the parent
+        press(&mut tui, KeyCode::Left);
+        assert_eq!(tui.rows().len(), 3);
+        press(&mut tui, KeyCode::Down);
+        assert_eq!(tui.selected_task().unwrap().get_name(), "other");
+        press(&mut tui, KeyCode::Up);
+        press(&mut tui, KeyCode::Left);
+        assert_eq!(tui.selected_task().unwrap().get_name(), "root");
+        press(&mut tui, KeyCode::Enter);
+        assert_eq!(tui.rows().len(), 2);
+        press(&mut tui, KeyCode::Right);
+        assert_eq!(tui.rows().len(), 3);
+        assert_eq!(press(&mut tui, KeyCode::Char('s')), Some(Action::Start(0)));
+        assert_eq!(press(&mut tui, KeyCode::Char('q')), Some(Action::Quit));
+    }
+
+    #[test]
+    fn test_prompts() {
+        let mut tui = tui();
+        press(&mut tui, KeyCode::Char('r'));
+        press(&mut tui, KeyCode::Backspace);
+        for c in "ter".chars() {
+            press(&mut tui, KeyCode::Char(c));
+        }
+        assert_eq!(
+            press(&mut tui, KeyCode::Enter),
+            Some(Action::Rename(0, "rooter".to_string()))
+        );
+        // Keys go to the prompt, not the tree
+        press(&mut tui, KeyCode::Char('a'));
+        assert_eq!(press(&mut tui, KeyCode::Char('q')), None);
+        assert_eq!(
+            press(&mut tui, KeyCode::Enter),
+            Some(Action::Add(Some(0), "q".to_string()))
+        );
+        press(&mut tui, KeyCode::Char('A'));
+        press(&mut tui, KeyCode::Char(' '));
+        assert_eq!(press(&mut tui, KeyCode::Enter), None);
+        assert!(matches!(tui.mode, Mode::Browse));
+    }
+
+    #[test]
+    fn test_html_to_text() {
+        let html = markdown::to_html(
+            "# Done\n\nFixed the *parser* &amp; tests:\n\n- one\n- two\n\n![shot](images/0_shot.png)",
+        );
+        assert_eq!(
+            html_to_text(&html),
+            "# Done\nFixed the parser & tests:\n\n- one\n- two\n\n[image shot]"
+        );
+    }
+}
@@ -0,0 +1,220 @@
+//! Accounts allowed to use the server, their roles, passwords and API tokens.
+
+use std::{fmt::Display, str::FromStr};
+
+use argon2::{
+    password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Argon2,
+};
+use base64::{engine::general_purpose, Engine as _};
+use rand_core::{OsRng, RngCore};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use utoipa::ToSchema;
+
+use crate::{
+    error::{Error, Result},
+    time::Time,
+};
+
+/// Someone allowed to use the server. The web UI logs in with the password,
+/// scripts send one of the tokens.
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
+pub struct User {
+    pub(crate) name: String,
+    /// Argon2 hash in the PHC string format
+    pub(crate) password: String,
+    pub(crate) role: Role,
+    pub(crate) tokens: Vec<ApiToken>,
+}
+
+/// What a user may do, each role can do everything the ones before it can.
+#[derive(Serialize, Deserialize, ToSchema, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    /// Browses tasks and summaries
+    Viewer,
+    /// Adds, works on and completes tasks
+    Member,
+    /// Deletes tasks and reassigns them
+    Manager,
+}
+
+impl Display for Role {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Role::Viewer => write!(f, "viewer"),
+            Role::Member => write!(f, "member"),
+            Role::Manager => write!(f, "manager"),
+        }
+    }
+}
+
+impl FromStr for Role {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "viewer" => Ok(Role::Viewer),
+            "member" => Ok(Role::Member),
+            "manager" => Ok(Role::Manager),
+            _ => Err(Error::BadRequest(format!(
+                "Unknown role {}, expected viewer, member or manager",
+                s
+            ))),
+        }
+    }
+}
+
+/// A bearer token for the API. Only its SHA-256 hash is stored, the token
+/// itself is shown once when it is created.
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
+pub struct ApiToken {
+    pub(crate) name: String,
+    pub(crate) hash: String,
+    pub(crate) created: Time,
+}
+
+/// What the API tells about a token.
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct TokenInfo {
+    pub name: String,
+    pub created: Time,
+}
+
+impl User {
+    pub fn new(name: String, password: &str, role: Role) -> Result<User> {
+        check_name(&name)?;
+        Ok(User {
+            name,
+            password: hash_password(password)?,
+            role,
+            tokens: Vec::new(),
+        })
+    }
+
+    pub fn get_name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn get_role(&self) -> Role {
+        self.role
+    }
+
+    pub fn get_tokens(&self) -> &[ApiToken] {
+        &self.tokens
+    }
+
+    /// Slow on purpose, run it off the async runtime.
+    pub fn check_password(&self, password: &str) -> bool {
+        verify_password(&self.password, password)
+    }
+}
+
+/// A hash with the default parameters for a password nobody uses
+const DUMMY_PASSWORD: &str =
+    "$argon2id$v=19$m=19456,t=2,p=1$TpfYQLbF9XIBRoei7oTJmg$GHbQVsnex5859R0aD/LheFUVPbs2wseKhXzIKY6ztPs";
+
+/// Takes as long as `User::check_password` but always fails, so a login as
+/// an unknown user can not be told apart by its response time.
+pub fn check_unknown_password(password: &str) -> bool {
+    verify_password(DUMMY_PASSWORD, password);
+    false
+}
+
+fn verify_password(hash: &str, password: &str) -> bool {
+    PasswordHash::new(hash).is_ok_and(|hash| {
+        Argon2::default()
+            .verify_password(password.as_bytes(), &hash)
+            .is_ok()
+    })
+}
+
+impl ApiToken {
+    pub fn info(&self) -> TokenInfo {
+        TokenInfo {
+            name: self.name.clone(),
+            created: self.created,
+        }
+    }
+}
+
+pub fn hash_password(password: &str) -> Result<String> {
+    if password.is_empty() {
+        return Err(Error::BadRequest("The password is empty".to_string()));
+    }
+    let salt = SaltString::generate(&mut OsRng);
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map(|hash| hash.to_string())
+        .map_err(|e| Error::Storage(format!("Failed to hash the password: {}", e)))
+}
+
+/// A new random secret for a login session or API token.
+pub fn new_secret() -> String {
+    let mut bytes = [0u8; 32];
+    OsRng.fill_bytes(&mut bytes);
+    general_purpose::URL_SAFE_NO_PAD.encode(bytes)
+}
+
+/// Secrets are stored hashed. They are random and long, a fast hash is
+/// enough.
+pub fn hash_secret(secret: &str) -> String {
+    Sha256::digest(secret.as_bytes())
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// User and token names end up in headers and command lines, keep them
+/// simple.
+pub fn check_name(name: &str) -> Result<()> {
+    let valid = !name.is_empty()
+        && name.len() <= 64
+        && name
+            .chars()
+            .all(|c| c.is_alphanumeric() || matches!(c, '-' | '_' | '.' | '@'));
+    if valid {
+        Ok(())
+    } else {
+        Err(Error::BadRequest(format!(
+            "Invalid name {}, use letters, digits, - _ . and @",
+            name
+        )))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_passwords() {
+        let user = User::new("ada".to_string(), "correct horse", Role::Member).unwrap();
+        assert!(user.password.starts_with("$argon2"));
+        assert!(user.check_password("correct horse"));
+        assert!(!user.check_password("battery staple"));
+        assert!(PasswordHash::new(DUMMY_PASSWORD).is_ok());
+        assert!(!check_unknown_password("tasks-todo dummy"));
+        assert!(User::new("ada".to_string(), "", Role::Member).is_err());
+        assert!(User::new("ada lovelace".to_string(), "x", Role::Member).is_err());
+    }
+
+    #[test]
+    fn test_secrets() {
+        let secret = new_secret();
+        assert_eq!(secret.len(), 43);
+        assert_ne!(secret, new_secret());
+        assert_eq!(hash_secret(&secret), hash_secret(&secret));
+        assert_eq!(hash_secret(&secret).len(), 64);
+    }
+
+    #[test]
+    fn test_roles() {
+        assert!(Role::Viewer < Role::Member && Role::Member < Role::Manager);
+        for role in [Role::Viewer, Role::Member, Role::Manager] {
+            assert_eq!(role.to_string().parse::<Role>().unwrap(), role);
+        }
+        assert!("admin".parse::<Role>().is_err());
+    }
+}
@@ -0,0 +1,78 @@
+use std::str::FromStr;
+
+use chrono::{Offset, TimeZone};
+use chrono_tz::Tz;
+
+use crate::{
+    error::{Error, Result},
+    time::Time,
+};
+
+/// The time zone timestamps are displayed in. Everything is stored in UTC,
+/// zones come from the IANA database bundled with `chrono-tz` so daylight
+/// saving is taken into account.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Zone(Tz);
+
+impl Zone {
+    pub fn utc() -> Zone {
+        Zone(Tz::UTC)
+    }
+
+    /// Reads `TZ`, falling back to UTC when it is not set or not a zone name.
+    pub fn from_tz_env() -> Zone {
+        std::env::var("TZ")
+            .ok()
+            .and_then(|zone| zone.parse().ok())
+            .unwrap_or_else(Zone::utc)
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.0.name()
+    }
+
+    /// Offset from UTC in seconds in effect at the given UTC time.
+    pub fn offset_at(&self, utc: Time) -> i64 {
+        let offset = self.0.offset_from_utc_datetime(
+            &chrono::DateTime::from_timestamp(utc.to_iso(), 0)
+                .unwrap_or_default()
+                .naive_utc(),
+        );
+        offset.fix().local_minus_utc() as i64
+    }
+
+    /// Converts a UTC time to the wall clock time of this zone.
+    pub fn to_local(self, utc: Time) -> Time {
+        Time::from_iso(utc.to_iso() + self.offset_at(utc))
+    }
+}
+
+impl FromStr for Zone {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let tz = s
+            .parse::<Tz>()
+            .map_err(|_| Error::Config(format!("Unknown time zone {}", s)))?;
+        Ok(Zone(tz))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dst() {
+        let zone: Zone = "Europe/Berlin".parse().unwrap();
+        let winter = Time::new(2023, 1, 15, 12, 0, 0).unwrap();
+        let summer = Time::new(2023, 7, 15, 12, 0, 0).unwrap();
+        assert_eq!(zone.offset_at(winter), 3600);
+        assert_eq!(zone.offset_at(summer), 7200);
+        assert_eq!(
+            zone.to_local(summer),
+            Time::new(2023, 7, 15, 14, 0, 0).unwrap()
+        );
+        assert!("Mars/Olympus_Mons".parse::<Zone>().is_err());
+    }
+}
@@ -15,27 +15,86 @@ class Time {
         this.second = second;
     }
 
+    // Times come from the server in UTC, show them in the configured zone
     text() {
-        return this.year + '-' + this.month + '-' + this.day + ' ' + this.hour + ':' + this.minute + ':' + this.second;
+        let format = new Intl.DateTimeFormat('en-GB', {
+            timeZone: global_tz,
+            year: 'numeric',
+            month: '2-digit',
+            day: '2-digit',
+            hour: '2-digit',
+            minute: '2-digit',
+            second: '2-digit',
+            hourCycle: 'h23'
+        });
+        let parts = {};
+        for (const part of format.formatToParts(new Date(this.seconds() * 1000))) {
+            parts[part.type] = part.value;
+        }
+        return parts.year + '-' + parts.month + '-' + parts.day + ' ' + parts.hour + ':' + parts.minute + ':' + parts.second;
+    }
+
+    static from(data) {
+        return new Time(data.year, data.month, data.day, data.hour, data.minute, data.second);
+    }
+
+    seconds() {
+        return Date.UTC(this.year, this.month - 1, this.day, this.hour, this.minute, this.second) / 1000;
     }
 }
 
+function escape_attribute(text) {
+    return text.replace(/&/g, '&amp;').replace(/"/g, '&quot;').replace(/</g, '&lt;');
+}
+
+// Formats a number of seconds like "2h 05m"
+function format_duration(seconds) {
+    let hours = Math.floor(seconds / 3600);
+    let minutes = Math.floor((seconds % 3600) / 60);
+    if (hours === 0) {
+        return minutes + 'm';
+    }
+    return hours + 'h ' + String(minutes).padStart(2, '0') + 'm';
+}
+
 class Task {
-    constructor(id, name, status, parent_id, start_time, end_time, summary) {
+    constructor(id, name, status, parent_id, sessions, summary, archived, reopened, creator, assignee) {
         this.id = id;
         this.name = name;
         this.status = status;
         this.parent_id = parent_id;
-        this.start_time = start_time;
-        this.end_time = end_time;
+        this.sessions = sessions;
+        this.start_time = sessions.length !== 0 ? sessions[0].start : null;
+        this.end_time = status === 'Complete' && sessions.length !== 0 ? sessions[sessions.length - 1].end : null;
         this.children = [];
         this.summary = summary;
+        this.archived = archived;
+        this.reopened = reopened;
+        this.creator = creator;
+        this.assignee = assignee;
+    }
+
+    // Whether the logged in user, or anyone without accounts, is working on it
+    working() {
+        return this.sessions.some(s => s.end === null && (global_user === null || s.user === global_user));
     }
 
     add_child(child) {
         this.children.push(child);
     }
 
+    // Seconds spent over all sessions, a running session counts up to now
+    tracked_seconds() {
+        let now = Date.now() / 1000;
+        let total = 0;
+        for (let i = 0; i < this.sessions.length; i++) {
+            let session = this.sessions[i];
+            let end = session.end !== null ? session.end.seconds() : now;
+            total += Math.max(0, end - session.start.seconds());
+        }
+        return total;
+    }
+
     icon() {
         if (this.status === 'Incomplete') {
             return '🔴';
@@ -43,11 +102,13 @@ class Task {
             return '🟢';
         } else if (this.status === 'InProgress') {
             return '🟡';
+        } else if (this.status === 'Paused') {
+            return '⏸';
         }
     }
 
     html() {
-        let html = '<div class="task">';
+        let html = this.archived ? '<div class="task archived">' : '<div class="task">';
         html += '<li>';
         html += '<div class="task-name">'
         html += '<div class="task-title">';
@@ -71,23 +132,55 @@ class Task {
             html += this.start_time.text();
             html += '</div>';
         }
+        if (this.reopened.length !== 0) {
+            let reasons = this.reopened.map(r => r.time.text() + ': ' + (r.reason !== null ? r.reason : 'no reason given'));
+            html += '<div class="reopened" title="' + escape_attribute(reasons.join('\n')) + '">';
+            html += '↺ ' + this.reopened.length;
+            html += '</div>';
+        }
+        if (this.assignee !== null) {
+            html += '<div class="assignee" title="' + escape_attribute('Added by ' + (this.creator !== null ? this.creator : 'nobody')) + '">';
+            html += '@' + this.assignee;
+            html += '</div>';
+        }
+        if (this.sessions.length !== 0) {
+            html += '<div class="tracked-time">';
+            html += 'Tracked: ' + format_duration(this.tracked_seconds());
+            html += '</div>';
+        }
         html += '</div>';
 
         html += '<div class="side-buttons">'
         html += '<button onclick=rename_task(' + this.id + ')>✎</button>';
-        if (this.status === 'InProgress') {
+        html += '<button onclick=assign_task(' + this.id + ')>@</button>';
+        if (this.status === 'InProgress' && !this.working()) {
+            // Someone else is on it, join them
+            html += '<button onclick=start_task(' + this.id + ')>⇥</button>';
+            html += '<button onclick=complete_task(' + this.id + ')>⇉</button>';
+        } else if (this.status === 'InProgress') {
+            html += '<button onclick=pause_task(' + this.id + ')>⏸</button>';
+            html += '<button onclick=complete_task(' + this.id + ')>⇉</button>';
+        } else if (this.status === 'Paused') {
+            html += '<button onclick=resume_task(' + this.id + ')>▶</button>';
             html += '<button onclick=complete_task(' + this.id + ')>⇉</button>';
         } else if (this.status === 'Complete') {
-            html += ' '
+            html += '<button onclick=reopen_task(' + this.id + ')>↺</button>';
         } else if (this.status === 'Incomplete') {
             html += '<button onclick=start_task(' + this.id + ')>⇥</button>';
         }
 
         if (this.summary !== null) {
-            html += '<button onclick=show_summary("' + this.summary + '")>📄</a>';
+            html += '<button onclick=show_summary(' + this.id + ')>📄</a>';
         }
 
         html += '<button onclick=add_child_task(' + this.id + ')>+</button>';
+        html += '<button onclick=share_task(' + this.id + ')>🔗</button>';
+        if (this.archived) {
+            html += '<button onclick=archive_task(' + this.id + ',false)>📤</button>';
+        } else {
+            html += '<button onclick=archive_task(' + this.id + ',true)>📦</button>';
+        }
+        html += '<button onclick=delete_task(' + this.id + ')>🗑</button>';
         html += '</div>';
         html += '</div>';
         if (this.children.length !== 0) {
@@ -106,6 +199,10 @@ class Task {
 
 let global_tasks = [];
 let global_task_array = [];
+// The tasks as the server sent them, kept up to date by the event stream
+let global_task_data = [];
+let global_show_archived = false;
+let global_show_mine = false;
 
 // Parse task tree
 function parse_task_tree(task_datas) {
@@ -117,15 +214,17 @@ function parse_task_tree(task_datas) {
     let tasks = [];
     for (let i = 0; i < task_datas.length; i++) {
         let task_data = task_datas[i];
-        let start_time = null;
-        let end_time = null;
-        if (task_data.start_time !== null) {
-            start_time = new Time(task_data.start_time.year, task_data.start_time.month, task_data.start_time.day, task_data.start_time.hour, task_data.start_time.minute, task_data.start_time.second);
-        }
-        if (task_data.end_time !== null) {
-            end_time = new Time(task_data.end_time.year, task_data.end_time.month, task_data.end_time.day, task_data.end_time.hour, task_data.end_time.minute, task_data.end_time.second);
-        }
-        let task = new Task(task_data.id, task_data.name, task_data.status, task_data.parent_id, start_time, end_time, task_data.summary);
+        let sessions = task_data.sessions.map(session => ({
+            start: Time.from(session.start),
+            end: session.end !== null ? Time.from(session.end) : null,
+            user: session.user
+        }));
+        let reopened = task_data.reopened.map(reopening => ({
+            time: Time.from(reopening.time),
+            reason: reopening.reason
+        }));
+        let task = new Task(task_data.id, task_data.name, task_data.status, task_data.parent_id, sessions, task_data.summary, task_data.archived, reopened, task_data.creator, task_data.assignee);
+        task.revision = task_data.revision;
         task_map[task_data.id] = task;
         tasks.push(task);
     }
@@ -133,7 +232,7 @@ function parse_task_tree(task_datas) {
     // Create the task tree
     let task_tree = [];
     for (let i = 0; i < tasks.length; i++) {
-        if (tasks[i].parent_id == null) {
+        if (tasks[i].parent_id == null || !(tasks[i].parent_id in task_map)) {
             task_tree.push(tasks[i]);
         } else {
             let parent = task_map[tasks[i].parent_id];
@@ -157,13 +256,13 @@ function add_task() {
             return;
         }
         // post
-        fetch(`http://${global_ip}/addtask`, {
+        fetch(`/api/v1/tasks`, {
             method: 'POST',
             headers: {
                 'Content-Type': 'application/json',
                 'Access-Control-Allow-Origin': '*'
             },
-            body: JSON.stringify({ name: name, parent: null })
+            body: JSON.stringify({ name: name, parent_id: null })
         }).then(async data => {
             console.log(data);
             new_task_dialogue.close();
@@ -190,13 +289,13 @@ function add_child_task(parent_id) {
             return;
         }
         // post
-        fetch(`http://${global_ip}/addtask`, {
+        fetch(`/api/v1/tasks`, {
             method: 'POST',
             headers: {
                 'Content-Type': 'application/json',
                 'Access-Control-Allow-Origin': '*'
             },
-            body: JSON.stringify({ name: name, parent: parent_id })
+            body: JSON.stringify({ name: name, parent_id: parent_id })
         }).then(async data => {
             console.log(data);
             new_task_dialogue.close();
@@ -250,46 +349,184 @@ function save_toggles(tasks) {
 }
 
 async function reload() {
-    let toggles = save_toggles(global_tasks);
     // Wait 100 ms
     await new Promise(r => setTimeout(r, 100));
-    fetch(`http://${global_ip}/tasks`, {
+    fetch(`/api/v1/tasks?archived=${global_show_archived}&mine=${global_show_mine}`, {
         method: 'GET',
         headers: {
             'Content-Type': 'application/json',
             'Access-Control-Allow-Origin': '*'
         },
-    }).then(response => response.json())
-        .then(data => {
-            document.getElementById('task-list').innerHTML = '';
-            let globals = parse_task_tree(data);
-            global_task_array = globals.tasks;
-            global_tasks = globals.task_tree;
-            for (let i = 0; i < global_tasks.length; i++) {
-                let html = global_tasks[i].html();
-                document.getElementById('task-list').innerHTML += html;
-            }
-            enable_toggles();
-            restore_toggles(toggles);
-            toggles = [];
+    }).then(response => {
+        // The session expired or the server restarted
+        if (response.status === 401) {
+            window.location.href = '/login';
+        }
+        return response.json();
+    }).then(data => {
+        global_task_data = data;
+        render();
+    });
+}
+
+function render() {
+    let toggles = save_toggles(global_tasks);
+    document.getElementById('task-list').innerHTML = '';
+    let globals = parse_task_tree(global_task_data);
+    global_task_array = globals.tasks;
+    global_tasks = globals.task_tree;
+    for (let i = 0; i < global_tasks.length; i++) {
+        let html = global_tasks[i].html();
+        document.getElementById('task-list').innerHTML += html;
+    }
+    enable_toggles();
+    restore_toggles(toggles);
+}
+
+// Applies a change pushed by the server without fetching everything again
+function apply_event(event) {
+    if (event.type === 'task_deleted') {
+        global_task_data = global_task_data.filter(t => t.id !== event.id);
+    } else {
+        let task = event.task;
+        let visible = (global_show_archived || !task.archived) && (!global_show_mine || is_mine(task));
+        global_task_data = global_task_data.filter(t => t.id !== task.id);
+        if (visible) {
+            global_task_data.push(task);
+            global_task_data.sort((a, b) => a.id - b.id);
+        }
+    }
+    render();
+}
+
+// Same as the server's mine filter
+function is_mine(task) {
+    return task.assignee !== null ? task.assignee === global_user : task.creator === global_user;
+}
+
+function subscribe() {
+    let events = new EventSource(`/api/v1/events`);
+    let types = ['task_created', 'task_updated', 'task_started', 'task_paused', 'task_stopped', 'task_renamed', 'task_deleted'];
+    for (const type of types) {
+        events.addEventListener(type, function(evt) {
+            apply_event(JSON.parse(evt.data));
         });
+    }
+    // Some events were missed, start over from the full list
+    let missed = false;
+    events.addEventListener('resync', function() {
+        reload();
+    });
+    events.onerror = function() {
+        missed = true;
+    }
+    events.onopen = function() {
+        if (missed) {
+            missed = false;
+            reload();
+        }
+    }
+}
+
+// Edits send the revision they were based on, the server refuses them with
+// 412 if someone else changed the task in the meantime
+function if_match(task_id) {
+    let task = global_task_array.find(t => t.id === task_id);
+    return '"' + task.revision + '"';
+}
+
+// Errors come back as {error, message} json
+async function report_error(response) {
+    if (response.ok) {
+        return;
+    }
+    let message = response.statusText;
+    try {
+        message = (await response.json()).message;
+    } catch (e) {
+        console.log(e);
+    }
+    alert(message);
 }
 
 function start_task(task_id) {
-    fetch(`http://${global_ip}/modifytask`, {
-        method: 'POST',
+    fetch(`/api/v1/tasks/${task_id}`, {
+        method: 'PATCH',
         headers: {
             'Content-Type': 'application/json',
             'Access-Control-Allow-Origin': '*'
         },
-        // body: JSON.stringify({name: name, parent_id: parent_id})
-        body: JSON.stringify({ id: task_id, action: "start" })
-    }).then(data => {
+        body: JSON.stringify({ status: "InProgress" })
+    }).then(async data => {
         console.log(data);
+        await report_error(data);
         reload();
     });
 }
 
+function pause_task(task_id) {
+    fetch(`/api/v1/tasks/${task_id}`, {
+        method: 'PATCH',
+        headers: {
+            'Content-Type': 'application/json',
+            'Access-Control-Allow-Origin': '*'
+        },
+        body: JSON.stringify({ status: "Paused" })
+    }).then(async data => {
+        console.log(data);
+        await report_error(data);
+        reload();
+    });
+}
+
+function resume_task(task_id) {
+    fetch(`/api/v1/tasks/${task_id}`, {
+        method: 'PATCH',
+        headers: {
+            'Content-Type': 'application/json',
+            'Access-Control-Allow-Origin': '*'
+        },
+        body: JSON.stringify({ status: "InProgress" })
+    }).then(async data => {
+        console.log(data);
+        await report_error(data);
+        reload();
+    });
+}
+
+function reopen_task(task_id) {
+    let reopen_dialogue = document.getElementById('reopen-task');
+    let input_text = document.getElementById('reopen-task-reason');
+    let post_reopen = function(start) {
+        let reason = input_text.value !== '' ? input_text.value : null;
+        fetch(`/api/v1/tasks/${task_id}`, {
+            method: 'PATCH',
+            headers: {
+                'Content-Type': 'application/json',
+                'Access-Control-Allow-Origin': '*'
+            },
+            body: JSON.stringify({ status: start ? "InProgress" : "Incomplete", reason: reason })
+        }).then(async data => {
+            console.log(data);
+            await report_error(data);
+            reopen_dialogue.close();
+            input_text.value = '';
+            await reload();
+        });
+    }
+    reopen_dialogue.showModal();
+    document.getElementById('reopen-task-submit').onclick = function() {
+        post_reopen(false);
+    }
+    document.getElementById('reopen-task-start').onclick = function() {
+        post_reopen(true);
+    }
+    document.getElementById('reopen-task-cancel').onclick = function() {
+        input_text.value = '';
+        reopen_dialogue.close();
+    }
+}
+
 function summary_dialogue_setup(summary_dialogue) {
     let nosum_button = document.getElementById('No summary');
     let submit_summary_button = document.getElementById('Submit');
@@ -317,6 +554,7 @@ function summary_dialogue_setup(summary_dialogue) {
 }
 
 function complete_task(task_id) {
+    let revision = if_match(task_id);
     // Dialogue to fetch the summary file. Can be blank
     let summary_dialogue = document.getElementById('summary-dialogue');
     summary_dialogue.showModal();
@@ -326,34 +564,36 @@ function complete_task(task_id) {
         console.log(summary_dialogue.returnValue);
         if (summary_dialogue.returnValue === 'nosum') {
             summary_path = null;
-            fetch(`http://${global_ip}/modifytask`, {
-                method: 'POST',
+            fetch(`/api/v1/tasks/${task_id}`, {
+                method: 'PATCH',
                 headers: {
                     'Content-Type': 'application/json',
+                    'If-Match': revision,
                     'Access-Control-Allow-Origin': '*'
                 },
-                // body: JSON.stringify({name: name, parent_id: parent_id})
-                body: JSON.stringify({ id: task_id, action: "stop", summary: summary_path })
+                body: JSON.stringify({ status: "Complete", summary: summary_path })
             }).then(async data => {
                 console.log(data);
+                await report_error(data);
                 await reload();
             });
         } else if (summary_dialogue.returnValue === 'cancel') {
             // Do nothing
         } else {
             summary_path = summary_dialogue.returnValue;
-            fetch(`http://${global_ip}/modifytask`, {
-                method: 'POST',
+            fetch(`/api/v1/tasks/${task_id}`, {
+                method: 'PATCH',
                 headers: {
                     'Content-Type': 'application/json',
+                    'If-Match': revision,
                     'Access-Control-Allow-Origin': '*'
                 },
-                // body: JSON.stringify({name: name, parent_id: parent_id})
-                body: JSON.stringify({ id: task_id, action: "stop", summary: summary_path })
+                body: JSON.stringify({ status: "Complete", summary: summary_path })
             }).then(async data => {
-                if (data.status === 418) {
-                    let image_names_flat = await data.text();
-                    let image_names = image_names_flat.split('\n');
+                await report_error(data);
+                // Local images have to be uploaded before the task completes
+                if (data.status === 202) {
+                    let image_names = (await data.json()).pending_attachments;
                     let image_dialogue = document.getElementById('images-dialogue');
                     image_dialogue.innerHTML = '';
                     image_dialogue.innerHTML += '<p>Some images are on your local path. Please upload them</p>'
@@ -381,7 +621,6 @@ function complete_task(task_id) {
                                 reader.readAsDataURL(image);
                                 reader.onload = function(evt) {
                                     let image_data = {
-                                        id: task_id,
                                         name: image_names[i],
                                         data: evt.target.result,
                                         extension: image.name.split('.').pop()
@@ -399,7 +638,7 @@ function complete_task(task_id) {
                         }
                         let data = JSON.stringify(image_datas);
                         console.log(data);
-                        fetch(`http://${global_ip}/uploadimages`, {
+                        fetch(`/api/v1/tasks/${task_id}/attachments`, {
                             method: 'POST',
                             headers: {
                                 'Content-Type': 'application/json',
@@ -426,6 +665,7 @@ function rename_task(task_id) {
     let input_text = document.getElementById('rename-task-name');
     let submit_button = document.getElementById('rename-task-submit');
     let cancel_button = document.getElementById('rename-task-cancel');
+    let revision = if_match(task_id);
     // Set input text to previous name
     for (let i = 0; i < global_task_array.length; i++) {
         if (global_task_array[i].id === task_id) {
@@ -440,15 +680,17 @@ function rename_task(task_id) {
             return;
         }
         // post
-        fetch(`http://${global_ip}/renametask`, {
-            method: 'POST',
+        fetch(`/api/v1/tasks/${task_id}`, {
+            method: 'PATCH',
             headers: {
                 'Content-Type': 'application/json',
+                'If-Match': revision,
                 'Access-Control-Allow-Origin': '*'
             },
-            body: JSON.stringify({ id: task_id, name: name })
+            body: JSON.stringify({ name: name })
         }).then(async data => {
             console.log(data);
+            await report_error(data);
             rename_task_dialogue.close();
             await reload();
         });
@@ -458,10 +700,115 @@ function rename_task(task_id) {
     }
 }
 
-async function show_summary(summary) {
+function delete_task(task_id) {
+    let task = global_task_array.find(t => t.id === task_id);
+    let revision = if_match(task_id);
+    let post_delete = function(children) {
+        fetch(`/api/v1/tasks/${task_id}?children=${children}`, {
+            method: 'DELETE',
+            headers: {
+                'If-Match': revision,
+                'Access-Control-Allow-Origin': '*'
+            },
+        }).then(async data => {
+            console.log(data);
+            await report_error(data);
+            await reload();
+        });
+    }
+    if (task.children.length === 0) {
+        if (confirm('Delete "' + task.name + '"?')) {
+            post_delete("cascade");
+        }
+        return;
+    }
+    // Ask what to do with the children
+    let delete_dialogue = document.getElementById('delete-task');
+    document.getElementById('delete-task-name').innerText = task.name;
+    delete_dialogue.showModal();
+    document.getElementById('delete-task-cascade').onclick = function() {
+        delete_dialogue.close();
+        post_delete("cascade");
+    }
+    document.getElementById('delete-task-reparent').onclick = function() {
+        delete_dialogue.close();
+        post_delete("reparent");
+    }
+    document.getElementById('delete-task-cancel').onclick = function() {
+        delete_dialogue.close();
+    }
+}
+
+function archive_task(task_id, archived) {
+    fetch(`/api/v1/tasks/${task_id}`, {
+        method: 'PATCH',
+        headers: {
+            'Content-Type': 'application/json',
+            'Access-Control-Allow-Origin': '*'
+        },
+        body: JSON.stringify({ archived: archived })
+    }).then(async data => {
+        console.log(data);
+        await reload();
+    });
+}
+
+function toggle_archived() {
+    global_show_archived = document.getElementById('show-archived').checked;
+    reload();
+}
+
+// A read-only link to the task and its subtasks, for people without an account
+function share_task(task_id) {
+    fetch(`/api/v1/shares`, {
+        method: 'POST',
+        headers: {
+            'Content-Type': 'application/json',
+            'Access-Control-Allow-Origin': '*'
+        },
+        body: JSON.stringify({ task_id: task_id })
+    }).then(async data => {
+        console.log(data);
+        if (!data.ok) {
+            await report_error(data);
+            return;
+        }
+        let share = await data.json();
+        prompt('Anyone with this link can see the task, its subtasks and summaries', window.location.origin + share.url);
+    });
+}
+
+function toggle_mine() {
+    global_show_mine = document.getElementById('show-mine').checked;
+    reload();
+}
+
+function assign_task(task_id) {
+    let task = global_task_array.find(t => t.id === task_id);
+    let revision = if_match(task_id);
+    let assignee = prompt('Assign "' + task.name + '" to (empty for nobody)', task.assignee !== null ? task.assignee : '');
+    if (assignee === null) {
+        return;
+    }
+    fetch(`/api/v1/tasks/${task_id}`, {
+        method: 'PATCH',
+        headers: {
+            'Content-Type': 'application/json',
+            'If-Match': revision,
+            'Access-Control-Allow-Origin': '*'
+        },
+        body: JSON.stringify({ assignee: assignee !== '' ? assignee : null })
+    }).then(async data => {
+        console.log(data);
+        await report_error(data);
+        await reload();
+    });
+}
+
+async function show_summary(task_id) {
     let summary_dialogue = document.getElementById('summary-view');
     let summary_text = document.getElementById('summary-content');
-    fetch(`http://${global_ip}/${summary}`, {
+    fetch(`/api/v1/tasks/${task_id}/summary`, {
         method: 'GET',
         headers: {
             'Content-Type': 'text/html',
@@ -469,7 +816,24 @@ async function show_summary(summary) {
         },
     }).then(async response => {
         console.log(response);
+        if (!response.ok) {
+            await report_error(response);
+            return;
+        }
         summary_text.innerHTML = await response.text();
+        summary_text.hidden = false;
+        document.getElementById('summary-source').hidden = true;
+        document.getElementById('summary-save').hidden = true;
+        let edit_button = document.getElementById('summary-edit');
+        edit_button.hidden = false;
+        edit_button.onclick = function() {
+            edit_summary(task_id);
+        }
+        let history_button = document.getElementById('summary-history');
+        history_button.hidden = false;
+        history_button.onclick = function() {
+            show_summary_history(task_id);
+        }
         let close_button = document.getElementById('summary-close');
         close_button.onclick = function() {
             summary_dialogue.close();
@@ -479,6 +843,122 @@ async function show_summary(summary) {
     });
 }
 
+// Swaps the rendered summary for its markdown and saves the changes
+async function edit_summary(task_id) {
+    let summary_dialogue = document.getElementById('summary-view');
+    let source = document.getElementById('summary-source');
+    let save_button = document.getElementById('summary-save');
+    let revision = if_match(task_id);
+    let response = await fetch(`/api/v1/tasks/${task_id}/summary/source`);
+    // Summaries written before the markdown was kept start over
+    source.value = response.ok ? await response.text() : '';
+    document.getElementById('summary-content').hidden = true;
+    document.getElementById('summary-edit').hidden = true;
+    document.getElementById('summary-history').hidden = true;
+    source.hidden = false;
+    save_button.hidden = false;
+    save_button.onclick = function() {
+        fetch(`/api/v1/tasks/${task_id}/summary`, {
+            method: 'PUT',
+            headers: {
+                'Content-Type': 'application/json',
+                'If-Match': revision,
+                'Access-Control-Allow-Origin': '*'
+            },
+            body: JSON.stringify({ markdown: source.value })
+        }).then(async data => {
+            if (!data.ok) {
+                await report_error(data);
+                return;
+            }
+            summary_dialogue.close();
+            await reload();
+        });
+    }
+}
+
+// Lists the revisions of the summary, each can be compared with the current
+// one or restored
+async function show_summary_history(task_id) {
+    let response = await fetch(`/api/v1/tasks/${task_id}/summary/revisions`);
+    if (!response.ok) {
+        await report_error(response);
+        return;
+    }
+    let revisions = await response.json();
+    let summary_text = document.getElementById('summary-content');
+    summary_text.replaceChildren();
+    let list = document.createElement('ul');
+    list.id = 'summary-revisions';
+    // Newest first
+    for (const revision of revisions.reverse()) {
+        let item = document.createElement('li');
+        let text = '#' + revision.number + ' ' + Time.from(revision.time).text();
+        if (revision.author !== null) {
+            text += ' by ' + revision.author;
+        }
+        if (revision.restored !== null) {
+            text += ', restores #' + revision.restored;
+        }
+        item.append(text + ' ');
+        let diff = document.createElement('pre');
+        diff.hidden = true;
+        let diff_button = document.createElement('button');
+        diff_button.textContent = '±';
+        diff_button.onclick = async function() {
+            if (!diff.hidden) {
+                diff.hidden = true;
+                return;
+            }
+            let response = await fetch(`/api/v1/tasks/${task_id}/summary/diff?from=${revision.number}`);
+            if (!response.ok) {
+                await report_error(response);
+                return;
+            }
+            diff.replaceChildren();
+            for (const line of await response.json()) {
+                let span = document.createElement('span');
+                span.className = 'diff-' + line.change;
+                let marker = { same: '  ', added: '+ ', removed: '- ' }[line.change];
+                span.textContent = marker + line.line + '\n';
+                diff.append(span);
+            }
+            diff.hidden = false;
+        };
+        let restore_button = document.createElement('button');
+        restore_button.textContent = '↺';
+        restore_button.onclick = function() {
+            restore_summary(task_id, revision.number);
+        };
+        item.append(diff_button, restore_button, diff);
+        list.append(item);
+    }
+    summary_text.append(list);
+}
+
+function restore_summary(task_id, number) {
+    fetch(`/api/v1/tasks/${task_id}/summary/revisions/${number}/restore`, {
+        method: 'POST',
+        headers: {
+            'If-Match': if_match(task_id),
+            'Access-Control-Allow-Origin': '*'
+        },
+    }).then(async data => {
+        if (!data.ok) {
+            await report_error(data);
+            return;
+        }
+        document.getElementById('summary-view').close();
+        await reload();
+        await show_summary(task_id);
+    });
+}
+
 window.onload = async function() {
+    // Without accounts there is nobody to filter by
+    if (global_user === null) {
+        document.getElementById('show-mine-label').hidden = true;
+    }
+    subscribe();
     await reload();
 };